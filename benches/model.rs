@@ -28,7 +28,7 @@ fn bench_model(c: &mut Criterion) {
 
     let agents = std::fs::read_to_string("target/out.json").unwrap();
     let agents: Vec<SimpleAgent> = serde_json::from_str(&agents).expect("Couldn't parse target/out.json");
-    let model = load_model("target/model.onnx").unwrap();
+    let model = load_network("target/model.onnx", ModelBackend::from_env()).unwrap();
     const PLAYER: usize = 8;
     let mut rng = rand::thread_rng();
     let sample_agents = agents.len() / 2;
@@ -47,6 +47,22 @@ fn bench_model(c: &mut Criterion) {
         });
     });
 
+    // Every player of the state at once, as done in the rollouts
+    let history = vec![previous_actions.clone(); players.len()];
+    c.bench_function("run_model_batch", |b| {
+        b.iter(|| {
+            black_box(ModelPolicy::new(&model).predict_all(&players, &history));
+        });
+    });
+
+    if let Ok(native) = load_network("target/model.onnx", ModelBackend::Native) {
+        c.bench_function("run_model_batch_native", |b| {
+            b.iter(|| {
+                black_box(ModelPolicy::new(&native).predict_all(&players, &history));
+            });
+        });
+    }
+
     c.bench_function("generation_0", |b| {
         b.iter(|| {
             let agent = &agents[rng.gen_range(0..sample_agents)];
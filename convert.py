@@ -1,5 +1,7 @@
 import tensorflow as tf
 import tf2onnx
+import onnx
+import json
 
 import train
 
@@ -10,3 +12,35 @@ model_proto, ext = tf2onnx.convert.from_keras(
     # input_signature = (train.INPUT_SIZE,),
     output_path = "target/model.onnx"
 )
+
+# Record the version of the encoding, so that the model is refused if the encoding of the states changes,
+entry = model_proto.metadata_props.add()
+entry.key = "encoder_version"
+entry.value = str(train.ENCODER_VERSION)
+# and the number of players that the model sees, which sets the size of its input and of its output
+entry = model_proto.metadata_props.add()
+entry.key = "max_players"
+entry.value = str(train.MAX_PLAYERS)
+onnx.save(model_proto, "target/model.onnx")
+
+# Weights of the dense layers, for the native backend (see src/mlp.rs); the layers of the value head are named "value..."
+# by train.py, and branch from the last hidden layer of the policy
+activations = {"linear": "Linear", "relu": "Relu", "sigmoid": "Sigmoid", "softmax": "Softmax"}
+
+def export_dense(layer):
+    kernel, bias = layer.get_weights()
+    return {
+        "inputs": int(kernel.shape[0]),
+        "outputs": int(kernel.shape[1]),
+        "weights": kernel.T.flatten().tolist(),
+        "bias": bias.tolist(),
+        "activation": activations[layer.activation.__name__],
+    }
+
+dense = [layer for layer in model.layers if isinstance(layer, tf.keras.layers.Dense)]
+layers = [export_dense(layer) for layer in dense if not layer.name.startswith("value")]
+value_layers = [export_dense(layer) for layer in dense if layer.name.startswith("value")]
+value = {"from": len(layers) - 1, "layers": value_layers} if value_layers else None
+
+with open("target/weights.json", "w") as file:
+    json.dump({"layers": layers, "value": value, "encoder_version": train.ENCODER_VERSION}, file)
@@ -0,0 +1,93 @@
+use obelisk::*;
+use obelisk::coalition::*;
+use obelisk::monte_carlo::*;
+use obelisk::outcome::*;
+use obelisk::model::*;
+use obelisk::input::*;
+use std::time::Instant;
+
+// Usage: coalition <member> <member> [...] [samples] [--horizon <horizon>]
+// Members are given by their name in players.json
+fn main() {
+    let model = load_network("target/model.onnx", ModelBackend::from_env()).unwrap();
+
+    let ai = ModelPolicy::new(&model);
+    let (names, players, previous_actions) = read_players("./players.json");
+    let (constraints, round_constraints) = read_constraints("./constraints.json");
+    let objective = read_objective("./objective.json");
+
+    // The number of rounds that were already played
+    let turn = previous_actions.first().map(|actions| actions.len()).unwrap_or(0);
+
+    let mut members = Vec::new();
+    let mut settings = CoalitionSettings {
+        monte_carlo: MonteCarloSettings {
+            max_rounds: 50usize.saturating_sub(turn).max(1),
+            round_offset: turn,
+            statistics: vec![Statistic::Win, Statistic::Elimination],
+            round_constraints,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--horizon" {
+            let raw = args.next().expect("Expected a horizon after --horizon");
+            settings.monte_carlo.horizon = raw.parse().unwrap_or_else(|err| panic!("{}", err));
+        } else if let Some(index) = names.iter().position(|name| *name == arg) {
+            members.push(index);
+        } else if let Ok(samples) = arg.parse::<usize>() {
+            settings.monte_carlo.samples = samples;
+        } else {
+            panic!("Unknown player: {}", arg);
+        }
+    }
+
+    assert!(members.len() >= 2, "A coalition needs at least two members");
+
+    let start = Instant::now();
+    let plans = coalition_analysis(
+        &players,
+        &members,
+        &previous_actions,
+        &constraints,
+        &settings,
+        Rollout { first_ai: ai, ai, compute_loss: &objective },
+    );
+
+    let format_action = |action| {
+        match action {
+            Action::Attack(n) => format!("Attack({})", names[n]),
+            x => format!("{:?}", x),
+        }
+    };
+
+    println!("=== Coalition analysis ===");
+    println!("Members: {}", members.iter().map(|n| names[*n].as_str()).collect::<Vec<_>>().join(", "));
+    println!("{} samples per plan.", settings.monte_carlo.samples);
+    println!("Objective: {:?}, horizon: {:?}, turn {}", objective, settings.monte_carlo.horizon, turn);
+    println!("Format: 'Member: loss±variance (win probability, elimination probability)', minimize loss.");
+    println!("Time taken: {:.2?}", start.elapsed());
+    println!();
+    println!("== Pareto-optimal plans: ==");
+
+    for plan in plans {
+        println!(
+            "{}",
+            plan.actions.iter().map(|(n, action)| format!("{}: {}", names[*n], format_action(*action))).collect::<Vec<_>>().join(", ")
+        );
+
+        for ((n, _action), outcome) in plan.actions.iter().zip(plan.outcomes.iter()) {
+            println!(
+                "  {}: {:.3}±{:.3} (win {:.1}%, eliminated {:.1}%)",
+                names[*n],
+                outcome.mean(),
+                outcome.confidence(),
+                outcome.win_probability() * 100.0,
+                outcome.elimination_probability() * 100.0
+            );
+        }
+    }
+}
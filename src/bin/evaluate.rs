@@ -1,33 +1,155 @@
 use obelisk::*;
 use obelisk::monte_carlo::*;
-// use obelisk::genetic_basic::*;
+use obelisk::genetic_basic::*;
 use obelisk::model::*;
-#[allow(unused_imports)]
+use obelisk::input::*;
+use obelisk::outcome::*;
+use obelisk::objective::*;
+use obelisk::inference::*;
+use obelisk::uncertain::*;
+use obelisk::plan::*;
+use obelisk::exact::*;
+use obelisk::cache::*;
+use obelisk::session::*;
+use obelisk::endgame::*;
+use obelisk::prediction_cache::PredictionCache;
+use obelisk::ensemble::*;
 use rand::Rng;
 use scoped_threadpool::Pool;
 use std::time::Instant;
-use std::fs::read_to_string;
 
 fn main() -> serde_json::Result<()> {
-    // let agents = std::fs::read_to_string("target/out.json").expect("Couldn't open target/out.json");
-    // let agents: Vec<SimpleAgent> = serde_json::from_str(&agents)?;
+    let mut model_path = String::from("target/model.onnx");
+    let mut samples: usize = 1000;
+    let mut use_genomes = false;
+    let mut infer = false;
+    let mut trajectories = false;
+    let mut exact = false;
+    let mut use_cache = true;
+    let mut use_session = false;
+    let mut use_endgame = true;
+    let mut turn: Option<usize> = None;
+    let mut horizon = Horizon::GameEnd;
+    let mut value_rounds: Option<usize> = None;
+    let mut scenarios: usize = 5;
+    let mut criterion = Criterion::Mean;
+    let mut prediction_cache_size: Option<usize> = None;
+    let mut policies_path: Option<String> = None;
+    let mut ensemble_path: Option<String> = None;
 
-    // const SAMPLE_AGENTS: usize = 25000;
-    // assert!(SAMPLE_AGENTS < agents.len());
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--genomes" => use_genomes = true,
+            "--infer" => infer = true,
+            "--trajectories" => trajectories = true,
+            "--exact" => exact = true,
+            "--no-cache" => use_cache = false,
+            "--session" => use_session = true,
+            "--no-endgame" => use_endgame = false,
+            "--scenarios" => {
+                let raw = args.next().expect("Expected a number after --scenarios");
+                scenarios = raw.parse().expect("Expected a number after --scenarios");
+            }
+            "--turn" => {
+                let raw = args.next().expect("Expected a number after --turn");
+                turn = Some(raw.parse().expect("Expected a number after --turn"));
+            }
+            "--horizon" => {
+                let raw = args.next().expect("Expected a horizon after --horizon");
+                horizon = raw.parse().unwrap_or_else(|err| panic!("{}", err));
+            }
+            "--value" => {
+                let raw = args.next().expect("Expected a number of rounds after --value");
+                value_rounds = Some(raw.parse().expect("Expected a number of rounds after --value"));
+            }
+            "--prediction-cache" => {
+                let raw = args.next().expect("Expected a number of predictions after --prediction-cache");
+                prediction_cache_size = Some(raw.parse().expect("Expected a number of predictions after --prediction-cache"));
+            }
+            "--policies" => {
+                policies_path = Some(args.next().expect("Expected a path after --policies"));
+            }
+            "--ensemble" => {
+                ensemble_path = Some(args.next().expect("Expected a path after --ensemble"));
+            }
+            "--model" => {
+                model_path = args.next().expect("Expected a path after --model");
+            }
+            "--risk" => {
+                let raw = args.next().expect("Expected a criterion after --risk");
+                criterion = raw.parse().unwrap_or_else(|err| panic!("{}", err));
+            }
+            raw if raw.starts_with("--") => panic!("Unknown argument: {}", raw),
+            raw => samples = raw.parse().unwrap_or_else(|_| panic!("Expected a number of samples, got {}", raw)),
+        }
+    }
 
-    let model = load_model("target/model.onnx").unwrap();
+    let model = load_network(&model_path, ModelBackend::from_env()).unwrap();
 
-    let ai = wrap_model(&model);
-    let description = format!("weighted sample from the results of DNN gen 1");
+    // With `--prediction-cache`, the predictions of the model are memoised, since the first simulated rounds go
+    // through the same states over and over
+    let prediction_cache = prediction_cache_size.map(PredictionCache::new);
+    let mut base_ai = ModelPolicy::new(&model);
+    if let Some(prediction_cache) = &prediction_cache {
+        base_ai = base_ai.with_cache(prediction_cache);
+    }
+    // With `--policies`, each player follows the model with its own settings (temperature, top-k, epsilon, argmax)
+    let seats = policies_path.as_deref().map(read_policies).unwrap_or_default();
+    let ai = base_ai.with_seats(&seats);
 
-    // let ai = |p: &[Player], index, round, _previous_actions: &[Action], rng: &mut rand::rngs::ThreadRng| {
-    //     let agent = &agents[rng.gen_range(0..SAMPLE_AGENTS)];
-    //     let action = agent.get_action(p, index, round, rng);
-    //     action
-    // };
-    // let description = format!("pick random action among {}/{} agents.", SAMPLE_AGENTS, agents.len());
+    // With `--ensemble`, the simulated games are played by several models, for instance several generations of the model
+    // (see `EnsembleConfig`); the prediction cache is keyed by the input only, so it isn't shared by the members
+    let ensemble_config = ensemble_path.as_deref().map(EnsembleConfig::load);
+    let ensemble_networks = match &ensemble_config {
+        Some(config) => config.load_networks(ModelBackend::from_env()).unwrap(),
+        None => Vec::new(),
+    };
+    let ensemble_members = ensemble_networks.iter().map(|network| ModelPolicy::new(network).with_seats(&seats)).collect::<Vec<_>>();
+    let ensemble_weights = ensemble_config.as_ref().map(EnsembleConfig::weights).unwrap_or_default();
 
-    let compute_loss = obelisk::genetic_basic::compute_loss;
+    // With `--genomes`, the model is only used for the current turn, and the rest of the game is played by a random
+    // sample of the agents in target/out.json, which is much faster
+    assert!(
+        !(use_genomes && (infer || ensemble_path.is_some())),
+        "--genomes can't be used with --infer or --ensemble, which play the rest of the simulated games with their own policies"
+    );
+    let agents: Option<Vec<SimpleAgent>> = if use_genomes {
+        let agents = std::fs::read_to_string("target/out.json").expect("Couldn't open target/out.json");
+        let agents: Vec<SimpleAgent> = serde_json::from_str(&agents)?;
+        assert!(!agents.is_empty(), "target/out.json contains no agents");
+        Some(agents)
+    } else {
+        None
+    };
+
+    let description = match &agents {
+        Some(agents) => format!(
+            "weighted sample from the results of DNN gen 1 for the current turn, then pick random action among {}/{} agents",
+            agents.len().div_ceil(2),
+            agents.len()
+        ),
+        None => String::from("weighted sample from the results of DNN gen 1"),
+    };
+
+    let objective = read_objective("./objective.json");
+
+    // With `--value <k>`, the simulated games are cut short after k rounds, and the games that didn't end are
+    // measured by the value head of the model instead of the objective. The value head predicts the `Heuristic` loss
+    // that the training data records, so the games that ended must be measured on the same scale.
+    let bootstrapped;
+    let compute_loss: &(dyn Objective + Sync) = match value_rounds {
+        Some(rounds) => {
+            assert!(
+                objective == ObjectiveConfig::Heuristic,
+                "--value can only be used with the Heuristic objective, which the value head of the model predicts",
+            );
+            horizon = Horizon::Rounds(rounds);
+            bootstrapped = Bootstrapped { objective: &objective, value: ModelValue::new(&model) };
+            &bootstrapped
+        }
+        None => &objective,
+    };
 
     // let players = vec![
     //     Player::with_values(2, 1, 4, 2, 0),
@@ -43,62 +165,227 @@ fn main() -> serde_json::Result<()> {
     //     Player::with_values(2, 2, 3, 2, 0),
     // ];
 
-    let (names, players, actions): (Vec<String>, Vec<Vec<usize>>, Vec<Vec<Action>>) = serde_json::from_str(&read_to_string("./players.json").unwrap()).unwrap();
-    let players = players.into_iter().map(|stats| {
-        if stats.len() >= 6 {
-            Player::with_values(stats[0] as u8, stats[1] as u32, stats[2] as u8, stats[3] as u8, stats[4] as u8).make_target()
-        } else {
-            Player::with_values(stats[0] as u8, stats[1] as u32, stats[2] as u8, stats[3] as u8, stats[4] as u8)
+    // Players whose values are ranges or distributions are replaced with their expected values, and a concrete state is
+    // drawn from these ranges for every sample; the sensitivity of the best action to that uncertainty is then measured
+    let (names, uncertain_players, previous_actions) = read_uncertain_players("./players.json");
+    let players = uncertain_players.iter().map(UncertainPlayer::expected).collect::<Vec<_>>();
+    let uncertain = uncertain_players.iter().any(|player| !player.is_exact());
+
+    let start = Instant::now();
+    // The number of rounds that were already played, which is the number of rounds in players.json by default
+    let turn = turn.unwrap_or_else(|| previous_actions.first().map(|actions| actions.len()).unwrap_or(0));
+    let (constraints, round_constraints) = read_constraints("./constraints.json");
+
+    // With `--trajectories`, a few simulated games are kept for every action, and the most typical and the worst game
+    // of every printed action are shown under it
+    let mut statistics = Statistic::all();
+    if trajectories {
+        statistics.push(Statistic::Trajectories(16));
+    }
+
+    let settings = MonteCarloSettings {
+        samples,
+        max_rounds: 50usize.saturating_sub(turn).max(1),
+        round_offset: turn,
+        horizon,
+        statistics,
+        criterion,
+        uncertain_players: if uncertain { uncertain_players } else { Vec::new() },
+        round_constraints,
+    };
+    let scenarios = if uncertain { scenarios } else { 0 };
+
+    // With few players and few rounds left, the endgame is solved exactly instead, assuming that the other players
+    // play against each player (see `src/endgame.rs`). The solver ignores the model of the other players and the options
+    // that only apply to monte carlo, so it isn't used when one of them is given.
+    let rounds = horizon.rounds(settings.max_rounds);
+    let solvable = !uncertain
+        && settings.round_constraints.is_empty()
+        && !matches!(horizon, Horizon::Discounted { .. })
+        && is_small(&players, rounds, &EndgameSettings::default());
+    let monte_carlo_options = [
+        ("--risk", criterion != Criterion::Mean),
+        ("--infer", infer),
+        ("--policies", policies_path.is_some()),
+        ("--ensemble", ensemble_path.is_some()),
+        ("--session", use_session),
+        ("--trajectories", trajectories),
+        ("--exact", exact),
+        ("--genomes", use_genomes),
+    ].into_iter().filter(|(_, given)| *given).map(|(option, _)| option).collect::<Vec<_>>();
+    if use_endgame && solvable && monte_carlo_options.is_empty() {
+        println!("=== Endgame solver ===");
+        println!("Turn {}, players: {}, {} rounds left", turn + 1, players.iter().filter(|p| p.can_play()).count(), rounds);
+        println!("Objective: {:?}", objective);
+        println!("The other players are assumed to play against each player, instead of following the model; pass --no-endgame to run monte carlo.");
+        println!("Format: 'Action: guaranteed loss | probability in the optimal strategy', minimize loss.");
+        println!();
+
+        for index in 0..players.len() {
+            if !players[index].can_play() {
+                continue;
+            }
+
+            let solution = solve_endgame(&players, index, rounds, &constraints, &compute_loss);
+            println!("== Player {}: {} ==", index, names[index]);
+            println!("Value: {:.3} ({} states solved, {:.2?})", solution.value, solution.states, start.elapsed());
+
+            let mut actions = solution.action_values.iter().zip(solution.strategy.iter()).collect::<Vec<_>>();
+            actions.sort_by(|a, b| b.1.1.partial_cmp(&a.1.1).unwrap().then(a.0.1.partial_cmp(&b.0.1).unwrap()));
+            for ((action, value), (_, probability)) in actions {
+                match action {
+                    Action::Attack(n) => print!("Attack({})", names[*n]),
+                    x => print!("{:?}", x),
+                }
+                println!(": {:.3} | {:.1}%", value, probability * 100.0);
+            }
+            println!();
         }
-    }).collect::<Vec<_>>();
 
-    let mut previous_actions = Vec::with_capacity(players.len());
-    for n in 0..players.len() {
-        let mut tmp = Vec::with_capacity(actions.len());
-        for previous_actions in actions.iter() {
-            tmp.push(previous_actions[n]);
+        return Ok(());
+    }
+
+
+    // With `--infer`, the policy followed by every player is inferred from their previous actions, among a set of candidates,
+    // and the simulated games are played by policies drawn from that posterior
+    let mut candidates: Vec<(String, Box<dyn Policy + Sync>)> = vec![
+        (String::from("bot (uniform)"), Box::new(Bot::Uniform)),
+        (String::from("bot (builder)"), Box::new(Bot::Builder)),
+        (String::from("bot (aggressive)"), Box::new(Bot::Aggressive)),
+    ];
+    for temperature in [0.5, 1.0, 2.0] {
+        candidates.push((
+            format!("model (temperature {})", temperature),
+            Box::new(base_ai.with_temperature(temperature)),
+        ));
+    }
+    if infer {
+        if let Ok(raw) = std::fs::read_to_string("target/out.json") {
+            let genomes: Vec<SimpleAgent> = serde_json::from_str(&raw)?;
+            for (n, genome) in genomes.into_iter().enumerate() {
+                candidates.push((format!("genome #{}", n), Box::new(genome)));
+            }
         }
-        previous_actions.push(tmp);
     }
+    let (candidate_names, policies): (Vec<_>, Vec<_>) = candidates.into_iter().unzip();
 
-    std::mem::drop(actions);
+    let posterior = if infer {
+        infer_policies(&players, &previous_actions, &policies, &InferenceSettings::default())
+    } else {
+        Vec::new()
+    };
 
-    let mut pool = Pool::new(players.len() as u32);
-    let samples: usize = std::env::args().last().map(|s| s.parse::<usize>().ok()).flatten().unwrap_or(1000);
-    let res = std::sync::Mutex::new(Vec::new());
+    // The identity of the policy and of the objective, for the cache and the session; the models are identified by the
+    // files they were loaded from
+    let hash_file = |path: &str| std::fs::read(path).map(|bytes| fnv1a(&bytes)).unwrap_or(0);
+    let mut policy = format!("model:{:016x}", hash_file(&model_path));
+    if use_genomes || infer {
+        policy += &format!(",genomes:{:016x}", hash_file("target/out.json"));
+    }
+    if infer {
+        policy += ",infer";
+    }
+    if let Some(path) = &policies_path {
+        policy += &format!(",policies:{:016x}", hash_file(path));
+    }
+    if let (Some(path), Some(config)) = (&ensemble_path, &ensemble_config) {
+        policy += &format!(",ensemble:{:016x}", hash_file(path));
+        for member in config.members.iter() {
+            policy += &format!(":{:016x}", hash_file(&member.path.to_string_lossy()));
+        }
+    }
+    let mut objective_identity = serde_json::to_string(&objective)?;
+    if value_rounds.is_some() {
+        objective_identity += ";value";
+    }
 
-    let start = Instant::now();
-    const TURN: usize = 4;
-    let max_rounds = 50 - TURN;
-    // let max_rounds = agents[0].genome.len() - TURN;
+    // With `--session`, the search of the previous turn is loaded from target/session.json; if players.json now contains
+    // the actions of that turn, the samples of the simulated games that went through these actions are kept.
+    // The session replaces the cache, which would otherwise count these samples twice.
+    assert!(!(use_session && use_genomes), "--session can't be used with --genomes, since the first round and the rollouts are played by different policies");
+    let session_identity = format!("{};{};{:?};constraints:{:016x}", policy, objective_identity, horizon, hash_file("./constraints.json"));
+    let mut session = Session::new(session_identity.as_str(), &players, &previous_actions);
+    let mut session_samples = 0;
+    if use_session {
+        if let Some(previous) = Session::load("target/session.json") {
+            session = previous;
+            session_samples = session.advance_to(&session_identity, &players, &previous_actions);
+        }
+    }
+    let use_cache = use_cache && !use_session;
+    let session_ref = if use_session { Some(&session) } else { None };
 
-    let constraints: Vec<(usize, Action)> = serde_json::from_str(&read_to_string("./constraints.json").unwrap()).unwrap();
+    // The policies drawn per simulated game (the inferred policies, or the member of the ensemble) also play the current
+    // turn, with the same draw
+    let mut res = match &agents {
+        _ if infer => {
+            let posterior_ai = PosteriorAi {
+                policies: &policies,
+                posterior: &posterior,
+            };
 
-    pool.scoped(|scope| {
-        for index in 0..players.len() {
-            let players = &players;
-            let res = &res;
-            let constraints = constraints.clone();
-            let previous_actions = &previous_actions;
-            scope.execute(move || {
-                let (best_action, actions) = mc_best_action(
-                    players,
-                    index,
-                    &previous_actions,
-                    constraints,
-                    samples,
-                    max_rounds,
-                    TURN,
-                    ai,
-                    compute_loss
-                );
-
-                res.lock().unwrap().push((index, best_action, actions));
-            });
+            evaluate_players(&players, &previous_actions, &constraints, &settings, scenarios, session_ref, Rollout { first_ai: SameAi, ai: posterior_ai, compute_loss })
         }
-    });
+        _ if ensemble_config.is_some() => {
+            let ensemble = Ensemble::new(&ensemble_members, &ensemble_weights, ensemble_config.as_ref().unwrap().mode);
+
+            evaluate_players(&players, &previous_actions, &constraints, &settings, scenarios, session_ref, Rollout { first_ai: SameAi, ai: ensemble, compute_loss })
+        }
+        Some(agents) => {
+            let sample_agents = agents.len().div_ceil(2);
+            let rollout_ai = |p: &[Player], index, round, _previous_actions: &[Action], rng: &mut rand::rngs::ThreadRng| {
+                let agent = &agents[rng.gen_range(0..sample_agents)];
+                agent.get_action(p, index, round, rng)
+            };
+
+            evaluate_players(&players, &previous_actions, &constraints, &settings, scenarios, session_ref, Rollout { first_ai: ai, ai: rollout_ai, compute_loss })
+        }
+        None => evaluate_players(&players, &previous_actions, &constraints, &settings, scenarios, session_ref, Rollout { first_ai: SameAi, ai, compute_loss }),
+    };
+
+    // Unless `--no-cache` is given, the outcomes are topped up with the samples of previous runs on the same position,
+    // which are stored in target/mc_cache.json; the best action is then picked again from the merged outcomes, unless
+    // the criterion needs the losses of every sample, which only the current run has
+    let mut cached_samples = 0;
+    if use_cache {
+        let mut cache = Cache::load("target/mc_cache.json");
+        for (index, best_action, actions, _, _) in res.iter_mut() {
+            for (action, outcome) in actions.iter_mut() {
+                if *action == Action::None {
+                    continue;
+                }
+
+                let mut constraints = constraints.clone();
+                constraints.push((*index, *action));
+                let key = cache_key(&players, *index, &previous_actions, &constraints, &settings, &policy, &objective_identity);
+                cached_samples += cache.top_up(&key, outcome);
+            }
+
+            if !criterion.is_summable() {
+                continue;
+            }
+            let best = actions.iter()
+                .filter(|(action, _)| *action != Action::None)
+                .reduce(|best, other| if other.1.better_than(&best.1, criterion) { other } else { best });
+            if let Some((action, _)) = best {
+                *best_action = *action;
+            }
+        }
+
+        if let Err(err) = cache.save() {
+            eprintln!("Couldn't save target/mc_cache.json: {}", err);
+        }
+    }
 
-    let mut res = res.into_inner().unwrap();
+    if use_session {
+        for (index, _, actions, _, children) in res.iter_mut() {
+            session.record(*index, actions, std::mem::take(children));
+        }
+
+        if let Err(err) = session.save("target/session.json") {
+            eprintln!("Couldn't save target/session.json: {}", err);
+        }
+    }
 
     res.sort_by_key(|x| x.0);
 
@@ -109,12 +396,129 @@ fn main() -> serde_json::Result<()> {
         }
     };
 
+    let format_outcome = |outcome: &Outcome| {
+        print!(
+            ": {:.3}±{:.3}",
+            outcome.mean(),
+            outcome.confidence(),
+        );
+        if criterion != Criterion::Mean {
+            print!(" (score {:.3})", outcome.score(criterion));
+        }
+        print!(
+            " | win {:.1}%, eliminated {:.1}%, sieged next turn {:.1}%, lost an obelisk {:.1}%, loss quantiles:",
+            outcome.win_probability() * 100.0,
+            outcome.elimination_probability() * 100.0,
+            outcome.sieged_next_turn_probability() * 100.0,
+            outcome.obelisk_loss_probability() * 100.0,
+        );
+        for (q, loss) in outcome.quantiles() {
+            print!(" {:.0}%: {:.2}", q * 100.0, loss);
+        }
+        println!();
+
+        print!("    final obelisks:");
+        for (n, probability) in outcome.obelisk_distribution().into_iter().enumerate() {
+            if probability > 0.0 {
+                print!(" {}: {:.1}%", n, probability * 100.0);
+            }
+        }
+        println!();
+    };
+
+    // With `--exact`, the probability of being sieged during the current turn is computed exactly, by enumerating the
+    // actions of the other players, weighted by the predictions of the model
+    let distributions = if exact {
+        action_distributions(&players, &previous_actions, turn, &ModelPolicy::new(&model))
+    } else {
+        Vec::new()
+    };
+
+    let format_exact = |index: usize, action: Action| {
+        if !exact {
+            return;
+        }
+
+        let settings = ExactSettings::default();
+        let Some(next) = exact_next_turn(&players, index, action, &distributions, &settings) else {
+            let relevant = relevant_players(index, action, &distributions).len();
+            println!("    exact next turn: skipped, {} players can affect it (at most {} are enumerated)", relevant, settings.max_players);
+            return;
+        };
+        print!("    exact next turn: sieged {:.2}%, obelisks lost:", next.sieged * 100.0);
+        for (n, probability) in next.obelisk_losses.iter().enumerate() {
+            if *probability > 0.0 {
+                print!(" {}: {:.2}%", n, probability * 100.0);
+            }
+        }
+        println!(" (pruned {:.2}%)", next.pruned * 100.0);
+    };
+
+    let format_trajectory = |trajectory: &Trajectory| {
+        for (round, actions) in trajectory.actions.iter().enumerate() {
+            print!("      turn {}:", turn + round + 1);
+            for (n, action) in actions.iter().copied().enumerate() {
+                if action != Action::None {
+                    print!(" {}: ", names[n]);
+                    format_action(action);
+                    print!(";");
+                }
+            }
+            println!();
+        }
+
+        print!("      final obelisks:");
+        for (n, player) in trajectory.final_state.iter().enumerate() {
+            print!(" {}: {};", names[n], player.obelisks);
+        }
+        println!(" loss: {:.3}", trajectory.loss);
+    };
+
+    let format_trajectories = |outcome: &Outcome| {
+        if let Some(trajectory) = outcome.typical_trajectory() {
+            println!("    typical game:");
+            format_trajectory(trajectory);
+        }
+        if let Some(trajectory) = outcome.worst_trajectory() {
+            println!("    worst game:");
+            format_trajectory(trajectory);
+        }
+    };
+
     println!("=== Monte Carlo Method ===");
-    println!("Turn {}, players: {}", TURN + 1, players.iter().filter(|p| p.can_play()).count());
+    println!("Turn {}, players: {}", turn + 1, players.iter().filter(|p| p.can_play()).count());
     println!("{} samples, {}.", samples, description);
-    println!("Format: 'Action: loss±variance', minimize loss.");
+    if session_samples > 0 {
+        println!("{} samples were kept from the search of the previous turn, in target/session.json.", session_samples);
+    }
+    if cached_samples > 0 {
+        println!("{} samples from previous runs were added from target/mc_cache.json.", cached_samples);
+    }
+    println!("Objective: {:?}, criterion: {:?}, horizon: {:?}", objective, criterion, horizon);
+    println!("Format: 'Action: loss±variance | outcome statistics', minimize loss.");
     println!("Time taken: {:.2?}", start.elapsed());
+    if use_endgame && solvable {
+        println!("The endgame could be solved exactly, but the endgame solver doesn't support {}.", monte_carlo_options.join(", "));
+    }
+    if let Some(prediction_cache) = &prediction_cache {
+        let statistics = prediction_cache.statistics();
+        println!(
+            "Prediction cache: {:.1}% hits ({} hits, {} misses, {} entries).",
+            statistics.hit_rate() * 100.0, statistics.hits, statistics.misses, statistics.entries,
+        );
+    }
     println!("");
+    if infer {
+        println!("== Inferred policies: ==");
+        for (index, probabilities) in posterior.iter().enumerate() {
+            let (best, probability) = probabilities.iter().copied().enumerate()
+                .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+                .unwrap();
+            println!("Player {} ({}): {} ({:.1}%)", names[index], index, candidate_names[best], probability * 100.0);
+        }
+        println!();
+    }
+
     println!("== Constraints: ==");
 
     for (index, action) in constraints.iter().copied() {
@@ -122,28 +526,47 @@ fn main() -> serde_json::Result<()> {
         format_action(action);
         println!();
     }
+    for constraint in settings.round_constraints.iter() {
+        print!("Round +{}, player {} ({}): ", constraint.round, names[constraint.player], constraint.player);
+        match constraint.action {
+            PlannedAction::Fixed(action) => format_action(action),
+            PlannedAction::Dynamic(action) => print!("{:?}", action),
+        }
+        println!();
+    }
     println!();
 
-    for (index, best_action, mut actions) in res {
+    for (index, best_action, mut actions, sensitivity, _) in res {
         println!("== Player {}: {} ==", index, names[index]);
-        actions.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        actions.sort_by(|a, b| {
+            if a.1.better_than(&b.1, criterion) {
+                std::cmp::Ordering::Less
+            } else if b.1.better_than(&a.1, criterion) {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        });
 
         for (index2, action) in constraints.iter().copied() {
             if index2 != index {
                 continue;
             }
 
-            let (action, loss, variance) = actions.iter().find(|(a, _, _)| *a == action).unwrap();
+            let (action, outcome) = actions.iter().find(|(a, _)| *a == action).unwrap();
 
             print!("C::> ");
             format_action(*action);
-            println!(": {:.3}±{:.3}", loss, 1.96 * (variance / samples as f64).sqrt());
+            format_outcome(outcome);
+            format_exact(index, *action);
+            format_trajectories(outcome);
         }
 
-        for (action, loss, variance) in actions.into_iter().take(6) {
-            format_action(action);
-
-            println!(": {:.3}±{:.3}", loss, 1.96 * (variance / samples as f64).sqrt());
+        for (action, outcome) in actions.iter().take(6) {
+            format_action(*action);
+            format_outcome(outcome);
+            format_exact(index, *action);
+            format_trajectories(outcome);
         }
 
 
@@ -151,8 +574,68 @@ fn main() -> serde_json::Result<()> {
         format_action(best_action);
         println!();
 
+        if !sensitivity.is_empty() {
+            print!("   best action over {} drawn states:", scenarios);
+            for (action, frequency) in sensitivity {
+                print!(" ");
+                format_action(action);
+                print!(" {:.0}%", frequency * 100.0);
+            }
+            println!();
+        }
+
         println!();
     }
 
     Ok(())
 }
+
+// The best action of a player, the outcome of each of their actions, how often each action is the best one
+// in states drawn from the uncertain players and the samples of the children of the session
+type Evaluation = (usize, Action, Vec<(Action, Outcome)>, Vec<(Action, f64)>, Children);
+
+// Runs mc_best_action for every player, in parallel, or searches with the session if there is one;
+// if `scenarios` isn't zero, also runs best_action_sensitivity
+fn evaluate_players<First, Ai, Loss>(
+    players: &[Player],
+    previous_actions: &[Vec<Action>],
+    constraints: &[(usize, Action)],
+    settings: &MonteCarloSettings,
+    scenarios: usize,
+    session: Option<&Session>,
+    rollout: Rollout<First, Ai, Loss>,
+) -> Vec<Evaluation>
+where
+    First: FirstAi<Ai> + Copy + Send,
+    Ai: RolloutAi + Copy + Send,
+    Loss: Objective + Copy + Send,
+{
+    let mut pool = Pool::new(players.len() as u32);
+    let res = std::sync::Mutex::new(Vec::new());
+
+    pool.scoped(|scope| {
+        for index in 0..players.len() {
+            let res = &res;
+            let constraints = constraints.to_vec();
+            scope.execute(move || {
+                let (best_action, actions, children) = match session {
+                    Some(session) => session.search(index, &constraints, settings, rollout),
+                    None => {
+                        let (best_action, actions) = mc_best_action(players, index, previous_actions, constraints.clone(), settings, rollout);
+                        (best_action, actions, Children::new())
+                    }
+                };
+
+                let sensitivity = if scenarios > 0 {
+                    best_action_sensitivity(index, previous_actions, &constraints, settings, scenarios, rollout)
+                } else {
+                    Vec::new()
+                };
+
+                res.lock().unwrap().push((index, best_action, actions, sensitivity, children));
+            });
+        }
+    });
+
+    res.into_inner().unwrap()
+}
@@ -1,12 +1,28 @@
 use obelisk::generate_training::*;
 use obelisk::genetic_basic::*;
+use obelisk::model::*;
+use obelisk::input::read_policies;
+use obelisk::ensemble::*;
 use std::fs::File;
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::io::Write;
 
 fn main() -> std::io::Result<()> {
-    let agents = std::fs::read_to_string("target/out.json")?;
-    let agents: Vec<SimpleAgent> = serde_json::from_str(&agents).expect("Couldn't parse target/out.json");
+    // With `--model`, the games are played by the model instead of the agents in target/out.json, and
+    // `--policies <path>` gives the settings of each seat; with `--ensemble <path>`, they are played by several models
+    let mut use_model = false;
+    let mut policies_path: Option<String> = None;
+    let mut ensemble_path: Option<String> = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--model" => use_model = true,
+            "--policies" => policies_path = Some(args.next().expect("Expected a path after --policies")),
+            "--ensemble" => ensemble_path = Some(args.next().expect("Expected a path after --ensemble")),
+            raw => panic!("Unknown argument: {}", raw),
+        }
+    }
 
     let settings = TrainingSettings {
         n_data: 48000,
@@ -19,11 +35,29 @@ fn main() -> std::io::Result<()> {
 
     println!("{:#?}", settings);
 
-    let training_data = generate_training_data_simpleagent(
-        settings,
-        &agents,
-        agents.len() / 2
-    );
+    let seats = policies_path.as_deref().map(read_policies).unwrap_or_default();
+
+    let training_data = if let Some(path) = ensemble_path {
+        let config = EnsembleConfig::load(path);
+        let networks = config.load_networks(ModelBackend::from_env()).unwrap();
+        let members = networks.iter().map(|network| ModelPolicy::new(network).with_seats(&seats)).collect::<Vec<_>>();
+        let weights = config.weights();
+
+        generate_training_data_ensemble(settings, Ensemble::new(&members, &weights, config.mode))
+    } else if use_model {
+        let model = load_network("target/model.onnx", ModelBackend::from_env()).unwrap();
+
+        generate_training_data_model(settings, ModelPolicy::new(&model).with_seats(&seats))
+    } else {
+        let agents = std::fs::read_to_string("target/out.json")?;
+        let agents: Vec<SimpleAgent> = serde_json::from_str(&agents).expect("Couldn't parse target/out.json");
+
+        generate_training_data_simpleagent(
+            settings,
+            &agents,
+            agents.len() / 2
+        )
+    };
 
     let mut file = File::create(format!(
         "target/train-{}.json",
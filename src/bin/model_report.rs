@@ -0,0 +1,107 @@
+use obelisk::generate_training::*;
+use obelisk::model::*;
+use obelisk::report::*;
+use obelisk::train::encode_training;
+
+fn main() -> std::io::Result<()> {
+    let mut path = String::from("target/train-last.json");
+    let mut models: Vec<String> = Vec::new();
+    let mut batch_size: usize = 1024;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--model" => models.push(args.next().expect("Expected a path after --model")),
+            "--batch" => {
+                let raw = args.next().expect("Expected a number after --batch");
+                batch_size = raw.parse().expect("Expected a number after --batch");
+            }
+            raw => path = raw.to_string(),
+        }
+    }
+    if models.is_empty() {
+        models.push(String::from("target/model.onnx"));
+    }
+    assert!(models.len() <= 2, "At most two models can be compared");
+
+    let training_data = std::fs::read_to_string(&path)?;
+    let training_data: Vec<TrainingData> = serde_json::from_str(&training_data).expect("Couldn't parse the training data");
+
+    // Every player of every state is an example, unlike during training; the examples are encoded for each model, since
+    // they can see different numbers of players
+    let networks = models.iter().map(|model| load_network(model, ModelBackend::from_env()).unwrap()).collect::<Vec<_>>();
+    let datasets = networks.iter().map(|network| encode_training(network.encoder(), &training_data, 0.0, &mut rand::thread_rng())).collect::<Vec<_>>();
+    println!("{} games, {} examples, from {}", training_data.len(), datasets[0].len(), path);
+    // With more players than a model sees, the attacks on the opponents it leaves out aren't examples, and the two
+    // models can't be compared on the same examples
+    assert!(datasets.iter().all(|dataset| dataset.labels.len() == datasets[0].labels.len()), "The models see different examples");
+
+    let predictions = networks.iter().zip(datasets.iter()).map(|(network, dataset)| {
+        predict_dataset(network, dataset, batch_size).unwrap()
+    }).collect::<Vec<_>>();
+    let reports = predictions.iter().zip(datasets.iter()).map(|(prediction, dataset)| Report::new(prediction, &dataset.labels, &dataset.legal)).collect::<Vec<_>>();
+
+    let row = |name: &str, values: Vec<String>| {
+        print!("{:<24}", name);
+        for value in values {
+            print!("{:>24}", value);
+        }
+        println!();
+    };
+
+    println!();
+    row("", models.clone());
+    row("cross-entropy", reports.iter().map(|r| format!("{:.4}", r.cross_entropy)).collect());
+    row("top-1 accuracy", reports.iter().map(|r| format!("{:.2}%", r.top1 * 100.0)).collect());
+    row("top-3 accuracy", reports.iter().map(|r| format!("{:.2}%", r.top3 * 100.0)).collect());
+    row("attack precision", reports.iter().map(|r| format!("{:.2}%", r.attack_precision_recall().0 * 100.0)).collect());
+    row("attack recall", reports.iter().map(|r| format!("{:.2}%", r.attack_precision_recall().1 * 100.0)).collect());
+    row("calibration error", reports.iter().map(|r| format!("{:.4}", r.expected_calibration_error())).collect());
+    if predictions.len() == 2 {
+        println!();
+        println!("The models agree on the most likely action of {:.2}% of the examples.", agreement(&predictions[0], &predictions[1], datasets[0].len()) * 100.0);
+    }
+
+    let kinds = action_kinds();
+    for (model, report) in models.iter().zip(reports.iter()) {
+        println!();
+        println!("== {} ==", model);
+        println!("Confusion, by kind of action (rows: best action, columns: most likely prediction):");
+        print!("{:<10}", "");
+        for kind in kinds.iter() {
+            print!("{:>10}", kind);
+        }
+        println!("{:>10}", "recall");
+        for (kind, counts) in kinds.iter().zip(report.confusion.iter()) {
+            print!("{:<10}", kind);
+            for count in counts {
+                print!("{:>10}", count);
+            }
+            let total = counts.iter().sum::<usize>();
+            let correct = counts[kinds.iter().position(|k| k == kind).unwrap()];
+            if total > 0 {
+                println!("{:>9.1}%", correct as f64 / total as f64 * 100.0);
+            } else {
+                println!("{:>10}", "-");
+            }
+        }
+
+        println!();
+        println!("Calibration (confidence of the most likely prediction):");
+        for (n, bin) in report.calibration.iter().enumerate() {
+            if bin.count == 0 {
+                continue;
+            }
+            println!(
+                "{:>4.0}%-{:>3.0}%: {:>8} examples, mean confidence {:>5.1}%, accuracy {:>5.1}%",
+                n as f64 / CALIBRATION_BINS as f64 * 100.0,
+                (n + 1) as f64 / CALIBRATION_BINS as f64 * 100.0,
+                bin.count,
+                bin.mean_confidence() * 100.0,
+                bin.accuracy() * 100.0,
+            );
+        }
+    }
+
+    Ok(())
+}
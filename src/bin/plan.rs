@@ -0,0 +1,93 @@
+use obelisk::*;
+use obelisk::plan::*;
+use obelisk::monte_carlo::*;
+use obelisk::outcome::*;
+use obelisk::model::*;
+use obelisk::input::*;
+use std::time::Instant;
+
+// Usage: plan <player> [depth] [samples] [--horizon <horizon>]
+// The player is given by their name in players.json; the depth defaults to 3 rounds
+fn main() {
+    let model = load_network("target/model.onnx", ModelBackend::from_env()).unwrap();
+
+    let ai = ModelPolicy::new(&model);
+    let (names, players, previous_actions) = read_players("./players.json");
+    let (constraints, round_constraints) = read_constraints("./constraints.json");
+    let objective = read_objective("./objective.json");
+
+    // The number of rounds that were already played
+    let turn = previous_actions.first().map(|actions| actions.len()).unwrap_or(0);
+
+    let mut player = None;
+    let mut numbers = Vec::new();
+    let mut settings = PlannerSettings {
+        monte_carlo: MonteCarloSettings {
+            max_rounds: 50usize.saturating_sub(turn).max(1),
+            round_offset: turn,
+            statistics: vec![Statistic::Win, Statistic::Elimination],
+            round_constraints,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--horizon" {
+            let raw = args.next().expect("Expected a horizon after --horizon");
+            settings.monte_carlo.horizon = raw.parse().unwrap_or_else(|err| panic!("{}", err));
+        } else if let Some(index) = names.iter().position(|name| *name == arg) {
+            player = Some(index);
+        } else if let Ok(n) = arg.parse::<usize>() {
+            numbers.push(n);
+        } else {
+            panic!("Unknown player: {}", arg);
+        }
+    }
+
+    let index = player.expect("Expected the name of a player");
+    if let Some(depth) = numbers.first() {
+        settings.depth = *depth;
+    }
+    if let Some(samples) = numbers.get(1) {
+        settings.monte_carlo.samples = *samples;
+    }
+
+    let start = Instant::now();
+    let plans = best_plans(
+        &players,
+        index,
+        &previous_actions,
+        &constraints,
+        &settings,
+        Rollout { first_ai: ai, ai, compute_loss: &objective },
+    );
+
+    let format_step = |step: &PlannedAction| {
+        match step {
+            PlannedAction::Fixed(Action::Attack(n)) => format!("Attack({})", names[*n]),
+            PlannedAction::Fixed(x) => format!("{:?}", x),
+            PlannedAction::Dynamic(x) => format!("{:?}", x),
+        }
+    };
+
+    println!("=== Plans ===");
+    println!("Player: {}, {} rounds deep", names[index], settings.depth);
+    println!("{} samples per plan, {} while searching.", settings.monte_carlo.samples, settings.prune_samples);
+    println!("Objective: {:?}, horizon: {:?}, turn {}", objective, settings.monte_carlo.horizon, turn);
+    println!("Format: 'Plan: loss±variance (win probability, elimination probability)', minimize loss.");
+    println!("Time taken: {:.2?}", start.elapsed());
+    println!();
+
+    for plan in plans {
+        println!(
+            "{}: {:.3}±{:.3} (win {:.1}%, eliminated {:.1}%)",
+            plan.steps.iter().map(format_step).collect::<Vec<_>>().join(", "),
+            plan.outcome.mean(),
+            plan.outcome.confidence(),
+            plan.outcome.win_probability() * 100.0,
+            plan.outcome.elimination_probability() * 100.0
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use obelisk::*;
+use obelisk::importance::*;
+use obelisk::model::*;
+use obelisk::input::*;
+use std::time::Instant;
+
+// Usage: rare_events <player> [eliminated|sieged|obelisks:<n>] [rounds] [samples]
+// Estimates the probability that the event happens to the player within the next rounds, for each of their actions
+fn main() {
+    let model = load_network("target/model.onnx", ModelBackend::from_env()).unwrap();
+    let policy = ModelPolicy::new(&model);
+
+    let (names, players, previous_actions) = read_players("./players.json");
+    let (constraints, _round_constraints) = read_constraints("./constraints.json");
+
+    let mut player = None;
+    let mut event = RareEvent::Eliminated;
+    let mut numbers = Vec::new();
+
+    for arg in std::env::args().skip(1) {
+        if let Some(index) = names.iter().position(|name| *name == arg) {
+            player = Some(index);
+        } else if let Ok(n) = arg.parse::<usize>() {
+            numbers.push(n);
+        } else {
+            event = arg.parse().unwrap_or_else(|err| panic!("{}", err));
+        }
+    }
+
+    let index = player.expect("Expected the name of a player");
+    let mut settings = ImportanceSettings {
+        round_offset: previous_actions.first().map(|actions| actions.len()).unwrap_or(0),
+        ..Default::default()
+    };
+    if let Some(rounds) = numbers.first() {
+        settings.rounds = *rounds;
+    }
+    if let Some(samples) = numbers.get(1) {
+        settings.samples = *samples;
+    }
+
+    let format_action = |action| {
+        match action {
+            Action::Attack(n) => format!("Attack({})", names[n]),
+            x => format!("{:?}", x),
+        }
+    };
+
+    println!("=== Rare events ===");
+    println!("Player: {}, event: {:?} within {} rounds", names[index], event, settings.rounds);
+    println!("{} samples per action, attacks on {} are {} times more likely in the simulations.", settings.samples, names[index], settings.bias);
+    println!("Format: 'Action: probability±confidence (games with the event, effective samples)'.");
+    println!();
+
+    let start = Instant::now();
+    let actions = players[index].possible_actions(players.iter().enumerate().filter(|(n, _p)| *n != index));
+    for action in actions {
+        let mut constraints = constraints.iter().copied().filter(|(n, _)| *n != index).collect::<Vec<_>>();
+        constraints.push((index, action));
+
+        let estimate = importance_sampling(&players, &previous_actions, &constraints, index, event, &policy, &settings);
+
+        println!(
+            "{}: {:.4}%±{:.4}% ({}, {:.0})",
+            format_action(action),
+            estimate.probability() * 100.0,
+            estimate.confidence() * 100.0,
+            estimate.hits,
+            estimate.effective_samples()
+        );
+    }
+
+    println!();
+    println!("Time taken: {:.2?}", start.elapsed());
+}
@@ -0,0 +1,83 @@
+use obelisk::generate_training::*;
+use obelisk::mlp::Mlp;
+use obelisk::model::Encoder;
+use obelisk::train::*;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+fn main() -> std::io::Result<()> {
+    let mut settings = TrainSettings::default();
+    let mut path = String::from("target/train-last.json");
+    let mut fresh = false;
+    let mut schema = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fresh" => fresh = true,
+            "--schema" => schema = true,
+            "--max-players" => {
+                let raw = args.next().expect("Expected a number after --max-players");
+                settings.max_players = raw.parse().expect("Expected a number after --max-players");
+            }
+            "--epochs" => {
+                let raw = args.next().expect("Expected a number after --epochs");
+                settings.epochs = raw.parse().expect("Expected a number after --epochs");
+            }
+            raw => path = raw.to_string(),
+        }
+    }
+
+    if schema {
+        // Describes the input layout for train.py, which checks its own encoding against it
+        let schema = serde_json::to_string_pretty(&Encoder::new(settings.max_players).schema()).expect("Couldn't serialize the schema!");
+        return std::fs::write("target/encoder.json", schema);
+    }
+
+    let mut rng = rand::thread_rng();
+
+    let training_data = std::fs::read_to_string(&path)?;
+    let training_data: Vec<TrainingData> = serde_json::from_str(&training_data).expect("Couldn't parse the training data");
+    println!("{} training games", training_data.len());
+
+    let dataset = encode_training(Encoder::new(settings.max_players), &training_data, settings.skip_rate, &mut rng);
+    let (train, validation) = dataset.split(settings.validation_split, &mut rng);
+    println!("{} training examples, {} validation examples", train.len(), validation.len());
+
+    // Like train.py, training continues from the previous network unless `--fresh` is given
+    let mut mlp = match Mlp::load("target/weights.json") {
+        Ok(mlp) if !fresh && Encoder::from_input_size(mlp.inputs()) == Some(Encoder::new(settings.max_players)) => mlp,
+        _ => {
+            println!("Creating a new model!");
+            initialize(&settings, &mut rng)
+        }
+    };
+
+    // The trainer only fits the policy, so a value head would no longer match the policy layers it branches from
+    if mlp.value.take().is_some() {
+        eprintln!("Warning: the Rust trainer doesn't train the value head, so it is dropped; use train.py to train it");
+    }
+
+    let mut trainer = Trainer::new(&mlp, settings.clone());
+    for epoch in 0..settings.epochs {
+        let start = Instant::now();
+        let loss = trainer.epoch(&mut mlp, &train, &mut rng);
+        let (validation_loss, validation_accuracy) = evaluate(&mlp, &validation);
+
+        println!(
+            "Epoch {}/{}: loss {:.4}, validation loss {:.4}, validation accuracy {:.2}% ({:.1}s)",
+            epoch + 1,
+            settings.epochs,
+            loss,
+            validation_loss,
+            validation_accuracy * 100.0,
+            start.elapsed().as_secs_f64(),
+        );
+    }
+
+    let serialized = serde_json::to_string(&mlp).expect("Couldn't serialize the model!");
+    std::fs::write(format!(
+        "target/weights-{}.json",
+        SystemTime::now().duration_since(UNIX_EPOCH).expect("Uh oh").as_millis()
+    ), &serialized)?;
+    std::fs::write("target/weights.json", &serialized)
+}
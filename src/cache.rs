@@ -0,0 +1,269 @@
+// A transposition cache of monte carlo results, persisted across runs.
+// `evaluate` is rerun on near-identical positions every turn, so the outcome of every (position, action) pair is stored,
+// keyed by a hash of everything that changes the simulated games: the state, the histories, the constraints, the policy,
+// the horizon and the objective. New runs then add their samples to the cached ones instead of starting from zero.
+//
+// Only the statistics that can be summed are cached (the sums, the squared sums and the counts); the quantiles,
+// the losses kept for CVaR and the trajectories only cover the samples of the current run.
+// The file keeps at most `MAX_CACHE_ENTRIES` entries, the least recently used ones being dropped when it is saved.
+use super::*;
+use super::monte_carlo::*;
+use super::outcome::*;
+use super::plan::RoundConstraint;
+use super::uncertain::UncertainPlayer;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The summable part of an `Outcome`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CachedOutcome {
+    pub samples: usize,
+    pub sum: f64,
+    pub sum_square: f64,
+    pub wins: usize,
+    pub eliminations: usize,
+    pub sieged_next_turn: usize,
+    pub obelisk_losses: usize,
+    pub obelisks: Vec<usize>,
+}
+
+impl CachedOutcome {
+    /// Returns an outcome collecting `statistics`, with the samples of this entry
+    pub fn to_outcome(&self, statistics: &[Statistic]) -> Outcome {
+        Outcome {
+            samples: self.samples,
+            sum: self.sum,
+            sum_square: self.sum_square,
+            wins: self.wins,
+            eliminations: self.eliminations,
+            sieged_next_turn: self.sieged_next_turn,
+            obelisk_losses: self.obelisk_losses,
+            obelisks: self.obelisks.clone(),
+            ..Outcome::new(statistics)
+        }
+    }
+
+    /// Adds the samples of `other` to this entry
+    pub fn add(&mut self, other: &CachedOutcome) {
+        self.samples += other.samples;
+        self.sum += other.sum;
+        self.sum_square += other.sum_square;
+        self.wins += other.wins;
+        self.eliminations += other.eliminations;
+        self.sieged_next_turn += other.sieged_next_turn;
+        self.obelisk_losses += other.obelisk_losses;
+
+        if self.obelisks.len() < other.obelisks.len() {
+            self.obelisks.resize(other.obelisks.len(), 0);
+        }
+        for (n, count) in other.obelisks.iter().enumerate() {
+            self.obelisks[n] += count;
+        }
+    }
+}
+
+impl From<&Outcome> for CachedOutcome {
+    fn from(outcome: &Outcome) -> Self {
+        Self {
+            samples: outcome.samples,
+            sum: outcome.sum,
+            sum_square: outcome.sum_square,
+            wins: outcome.wins,
+            eliminations: outcome.eliminations,
+            sieged_next_turn: outcome.sieged_next_turn,
+            obelisk_losses: outcome.obelisk_losses,
+            obelisks: outcome.obelisks.clone(),
+        }
+    }
+}
+
+/// 64-bit FNV-1a hash; unlike `DefaultHasher`, it is guaranteed to stay the same across builds, which matters for a file cache
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash
+}
+
+/// Returns the key of the outcome of player `index`, when the players follow `constraints` during the first round
+/// (the constraint of player `index` being the evaluated action).
+/// `policy` and `objective` identify the policy that plays the simulated games and the objective that computes the loss;
+/// the cache can't tell them apart otherwise, so they must change whenever the model, the genomes or the objective change.
+pub fn cache_key(
+    players: &[Player],
+    index: usize,
+    previous_actions: &[Vec<Action>],
+    constraints: &[(usize, Action)],
+    settings: &MonteCarloSettings,
+    policy: &str,
+    objective: &str,
+) -> String {
+    #[derive(Serialize)]
+    struct Key<'a> {
+        players: &'a [Player],
+        uncertain_players: &'a [UncertainPlayer],
+        index: usize,
+        previous_actions: &'a [Vec<Action>],
+        constraints: Vec<(usize, Action)>,
+        round_constraints: Vec<RoundConstraint>,
+        policy: &'a str,
+        objective: &'a str,
+        max_rounds: usize,
+        round_offset: usize,
+        horizon: Horizon,
+        statistics: Vec<Statistic>,
+    }
+
+    // Later constraints override the earlier ones in monte carlo, so only the last constraint of every player is kept
+    let mut canonical: Vec<(usize, Action)> = Vec::new();
+    for (player, action) in constraints.iter().copied() {
+        canonical.retain(|(n, _)| *n != player);
+        canonical.push((player, action));
+    }
+    canonical.sort_by_key(|(player, _)| *player);
+
+    let mut round_constraints = settings.round_constraints.clone();
+    round_constraints.sort_by_key(|c| (c.round, c.player));
+
+    // Only the statistics that are cached change the entry
+    let statistics = settings.collected_statistics().into_iter().filter(|statistic| matches!(statistic,
+        Statistic::Win | Statistic::Elimination | Statistic::SiegedNextTurn | Statistic::ObeliskLoss | Statistic::Obelisks
+    )).collect();
+
+    let key = Key {
+        players,
+        uncertain_players: &settings.uncertain_players,
+        index,
+        previous_actions,
+        constraints: canonical,
+        round_constraints,
+        policy,
+        objective,
+        max_rounds: settings.max_rounds,
+        round_offset: settings.round_offset,
+        horizon: settings.horizon,
+        statistics,
+    };
+
+    format!("{:016x}", fnv1a(serde_json::to_string(&key).unwrap().as_bytes()))
+}
+
+/// The default number of entries that `Cache` keeps
+pub const MAX_CACHE_ENTRIES: usize = 20000;
+
+/// An entry of the cache file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    #[serde(flatten)]
+    pub outcome: CachedOutcome,
+    /// When the entry was last topped up, counted in calls to `Cache::top_up`
+    #[serde(default)]
+    pub last_used: u64,
+}
+
+/// Monte carlo outcomes stored in a JSON file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cache {
+    pub path: PathBuf,
+    pub entries: HashMap<String, CacheEntry>,
+    /// The number of entries kept when the cache is saved
+    pub max_entries: usize,
+    clock: u64,
+}
+
+impl Cache {
+    /// Loads the cache stored at `path`; if the file doesn't exist or can't be parsed, the cache starts empty
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let entries: HashMap<String, CacheEntry> = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|raw| serde_json::from_str(&raw).ok())
+            .unwrap_or_default();
+        let clock = entries.values().map(|entry| entry.last_used).max().unwrap_or(0);
+
+        Self { path, entries, max_entries: MAX_CACHE_ENTRIES, clock }
+    }
+
+    /// Drops the least recently used entries, then writes the cache
+    pub fn save(&mut self) -> std::io::Result<()> {
+        self.evict();
+        std::fs::write(&self.path, serde_json::to_string(&self.entries)?)
+    }
+
+    /// Drops the least recently used entries, until there are at most `max_entries` left
+    pub fn evict(&mut self) {
+        if self.entries.len() <= self.max_entries {
+            return;
+        }
+
+        let mut keys = self.entries.iter().map(|(key, entry)| (entry.last_used, key.clone())).collect::<Vec<_>>();
+        keys.sort_unstable();
+
+        for (_, key) in keys.into_iter().take(self.entries.len() - self.max_entries) {
+            self.entries.remove(&key);
+        }
+    }
+
+    /// Adds the cached samples of `key` to `outcome`, then stores the merged outcome back into the cache.
+    /// Returns the number of samples that came from the cache.
+    pub fn top_up(&mut self, key: &str, outcome: &mut Outcome) -> usize {
+        let cached = self.entries.get(key).map(|entry| entry.outcome.samples).unwrap_or(0);
+
+        if let Some(entry) = self.entries.get(key) {
+            outcome.merge(&entry.outcome.to_outcome(&outcome.statistics));
+        }
+        self.clock += 1;
+        self.entries.insert(key.to_string(), CacheEntry {
+            outcome: CachedOutcome::from(&*outcome),
+            last_used: self.clock,
+        });
+
+        cached
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cache() {
+        let players = vec![Player::new(); 3];
+        let previous_actions = vec![vec![]; 3];
+        let settings = MonteCarloSettings::default();
+
+        let key = |constraints: &[(usize, Action)], policy: &str| {
+            cache_key(&players, 0, &previous_actions, constraints, &settings, policy, "Heuristic")
+        };
+
+        // The order of the constraints doesn't matter, and later constraints override earlier ones
+        let a = key(&[(1, Action::Wall), (0, Action::Recruit)], "model");
+        assert_eq!(a, key(&[(0, Action::Skip), (0, Action::Recruit), (1, Action::Wall)], "model"));
+        assert_ne!(a, key(&[(0, Action::Recruit)], "model"));
+        assert_ne!(a, key(&[(1, Action::Wall), (0, Action::Recruit)], "genomes"));
+
+        let mut outcome = Outcome::new(&Statistic::all());
+        outcome.record(&players, &players, &players, 0, 1.0);
+
+        let mut cache = Cache::load("target/nonexistent_cache.json");
+        assert_eq!(cache.top_up(&a, &mut outcome.clone()), 0);
+
+        let mut topped_up = outcome.clone();
+        assert_eq!(cache.top_up(&a, &mut topped_up), 1);
+        assert_eq!(topped_up.samples, 2);
+        assert_eq!(topped_up.sum, 2.0);
+        assert_eq!(topped_up.obelisks, vec![0, 2]);
+        assert_eq!(cache.entries[&a].outcome.samples, 2);
+
+        // The least recently used entries are dropped first
+        let b = key(&[(0, Action::Wall)], "model");
+        cache.top_up(&b, &mut outcome.clone());
+        cache.top_up(&a, &mut outcome.clone());
+        cache.max_entries = 1;
+        cache.evict();
+        assert_eq!(cache.entries.keys().collect::<Vec<_>>(), vec![&a]);
+    }
+}
@@ -0,0 +1,258 @@
+// Evaluates the joint actions of a group of players (a coalition), to find out which alliances are worth proposing.
+// Every combination of the members' actions is simulated with monte carlo, and only the Pareto-optimal plans are kept:
+// a plan is discarded if another plan is at least as good for every member and strictly better for one of them.
+use super::*;
+use super::monte_carlo::*;
+use super::outcome::*;
+use super::objective::Objective;
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoalitionSettings {
+    /// Settings used to evaluate each joint plan
+    pub monte_carlo: MonteCarloSettings,
+    /// Number of samples used to evaluate each member's actions on their own, before pruning
+    pub prune_samples: usize,
+    /// Number of candidate actions kept for each member after pruning
+    pub max_actions: usize,
+    /// Whether members may attack each other in a joint plan
+    pub internal_attacks: bool,
+}
+
+impl Default for CoalitionSettings {
+    fn default() -> Self {
+        Self {
+            monte_carlo: MonteCarloSettings::default(),
+            prune_samples: 200,
+            max_actions: 4,
+            internal_attacks: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoalitionPlan {
+    /// The action taken by each member, in the same order as the members
+    pub actions: Vec<(usize, Action)>,
+    /// The outcome of the plan for each member, in the same order as the members
+    pub outcomes: Vec<Outcome>,
+}
+
+impl CoalitionPlan {
+    /// Returns true if `self` is at least as good as `other` for every member, and strictly better for one of them
+    pub fn dominates(&self, other: &CoalitionPlan) -> bool {
+        debug_assert!(self.outcomes.len() == other.outcomes.len());
+
+        self.outcomes.iter().zip(other.outcomes.iter()).all(|(a, b)| a.mean() <= b.mean())
+            && self.outcomes.iter().zip(other.outcomes.iter()).any(|(a, b)| a.mean() < b.mean())
+    }
+
+    pub fn total_loss(&self) -> f64 {
+        self.outcomes.iter().map(|outcome| outcome.mean()).sum()
+    }
+}
+
+/// Only keeps the plans that aren't dominated by any other plan, sorted by their total loss
+pub fn pareto_front(plans: Vec<CoalitionPlan>) -> Vec<CoalitionPlan> {
+    let mut res = plans
+        .iter()
+        .filter(|plan| !plans.iter().any(|other| other.dominates(plan)))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    res.sort_by(|a, b| a.total_loss().partial_cmp(&b.total_loss()).unwrap_or(Ordering::Equal));
+
+    res
+}
+
+/// Evaluates every joint plan of the players in `members` and returns the Pareto-optimal ones.
+/// Members that are constrained in `constraints` only consider the action that they are constrained to.
+pub fn coalition_analysis<First, Ai, Loss>(
+    players: &[Player],
+    members: &[usize],
+    previous_actions: &[Vec<Action>],
+    constraints: &[(usize, Action)],
+    settings: &CoalitionSettings,
+    rollout: Rollout<First, Ai, Loss>,
+) -> Vec<CoalitionPlan>
+where
+    First: FirstAi<Ai> + Copy,
+    Ai: RolloutAi + Copy,
+    Loss: Objective + Copy,
+{
+    assert!(previous_actions.len() == players.len());
+    assert!(!members.is_empty());
+
+    let candidates = members
+        .iter()
+        .copied()
+        .map(|member| {
+            coalition_candidates(players, member, members, previous_actions, constraints, settings, rollout)
+        })
+        .collect::<Vec<_>>();
+
+    let mut plans = Vec::new();
+    let mut choice = vec![0; members.len()];
+    let mut constraints = constraints.to_vec();
+    let n_constraints = constraints.len();
+
+    // Enumerate the cartesian product of the candidates, using `choice` as a mixed-radix counter
+    'outer: loop {
+        constraints.truncate(n_constraints);
+        let actions = members
+            .iter()
+            .copied()
+            .enumerate()
+            .map(|(n, member)| (member, candidates[n][choice[n]]))
+            .collect::<Vec<_>>();
+        constraints.extend(actions.iter().copied());
+
+        let outcomes = monte_carlo(
+            players,
+            previous_actions,
+            &constraints,
+            members,
+            &settings.monte_carlo,
+            rollout,
+        );
+
+        plans.push(CoalitionPlan { actions, outcomes });
+
+        for n in 0..choice.len() {
+            choice[n] += 1;
+            if choice[n] < candidates[n].len() {
+                continue 'outer;
+            }
+            choice[n] = 0;
+        }
+        break;
+    }
+
+    pareto_front(plans)
+}
+
+// Returns the actions that `member` should consider in a joint plan, pruned down to `settings.max_actions`
+// by evaluating each action on its own
+fn coalition_candidates<First, Ai, Loss>(
+    players: &[Player],
+    member: usize,
+    members: &[usize],
+    previous_actions: &[Vec<Action>],
+    constraints: &[(usize, Action)],
+    settings: &CoalitionSettings,
+    rollout: Rollout<First, Ai, Loss>,
+) -> Vec<Action>
+where
+    First: FirstAi<Ai> + Copy,
+    Ai: RolloutAi + Copy,
+    Loss: Objective + Copy,
+{
+    if let Some((_, action)) = constraints.iter().find(|(index, _)| *index == member) {
+        return vec![*action];
+    }
+
+    let mut actions = players[member]
+        .possible_actions(players.iter().enumerate().filter(|(n, _p)| *n != member))
+        .into_iter()
+        .filter(|action| match action {
+            Action::Attack(target) => settings.internal_attacks || !members.contains(target),
+            _ => true,
+        })
+        .collect::<Vec<_>>();
+
+    if actions.len() <= settings.max_actions || settings.prune_samples == 0 {
+        return actions;
+    }
+
+    let mut constraints = constraints.to_vec();
+    constraints.push((member, Action::None));
+
+    let prune_settings = MonteCarloSettings {
+        samples: settings.prune_samples,
+        statistics: Vec::new(),
+        ..settings.monte_carlo.clone()
+    };
+
+    let mut losses = actions
+        .drain(..)
+        .map(|action| {
+            *constraints.last_mut().unwrap() = (member, action);
+            let outcome = monte_carlo(
+                players,
+                previous_actions,
+                &constraints,
+                &[member],
+                &prune_settings,
+                rollout,
+            );
+
+            (action, outcome[0].mean())
+        })
+        .collect::<Vec<_>>();
+
+    losses.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+    losses.into_iter().take(settings.max_actions).map(|(action, _)| action).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn plan(losses: &[f64]) -> CoalitionPlan {
+        CoalitionPlan {
+            actions: vec![],
+            outcomes: losses.iter().map(|loss| {
+                let mut outcome = Outcome::new(&[]);
+                outcome.record(&[Player::new()], &[Player::new()], &[Player::new()], 0, *loss);
+                outcome
+            }).collect(),
+        }
+    }
+
+    fn means(plan: &CoalitionPlan) -> Vec<f64> {
+        plan.outcomes.iter().map(|outcome| outcome.mean()).collect()
+    }
+
+    #[test]
+    fn test_pareto_front() {
+        let plans = vec![
+            plan(&[1.0, 3.0]),
+            plan(&[2.0, 2.0]),
+            plan(&[3.0, 1.0]),
+            plan(&[2.0, 3.0]), // dominated by [2.0, 2.0] and [1.0, 3.0]
+            plan(&[3.0, 3.0]), // dominated by everything
+            plan(&[1.0, 3.0]), // equal plans don't dominate each other
+        ];
+
+        let front = pareto_front(plans);
+
+        assert_eq!(front.len(), 4);
+        assert_eq!(means(&front[0]), vec![1.0, 3.0]);
+        assert!(front.iter().all(|plan| means(plan) != vec![2.0, 3.0]));
+        assert!(front.iter().all(|plan| means(plan) != vec![3.0, 3.0]));
+    }
+
+    #[test]
+    fn test_coalition_candidates() {
+        let players = vec![
+            Player::with_values(1, 3, 1, 1, 0),
+            Player::with_values(1, 3, 1, 1, 0),
+            Player::with_values(1, 3, 1, 1, 0),
+        ];
+        let previous_actions = vec![vec![]; 3];
+        let settings = CoalitionSettings {
+            prune_samples: 0,
+            ..Default::default()
+        };
+        let ai = |_: &[Player], _, _, _: &[Action], _: &mut rand::rngs::ThreadRng| Action::Skip;
+        let rollout = Rollout { first_ai: ai, ai, compute_loss: &crate::objective::Win };
+
+        let actions = coalition_candidates(&players, 0, &[0, 1], &previous_actions, &[], &settings, rollout);
+        assert!(actions.contains(&Action::Attack(2)));
+        assert!(!actions.contains(&Action::Attack(1)));
+
+        let actions = coalition_candidates(&players, 0, &[0, 1], &previous_actions, &[(0, Action::Wall)], &settings, rollout);
+        assert_eq!(actions, vec![Action::Wall]);
+    }
+}
@@ -0,0 +1,284 @@
+// Exact solver for small simultaneous-move endgames.
+// With 2 or 3 players left and few rounds to go, the whole game tree is small enough to be solved by backward induction:
+// at every state, the players pick their actions simultaneously, which is a matrix game between player `index` (the rows)
+// and the joint actions of the other players (the columns), whose entries are the values of the next states.
+// Each matrix game is solved with a linear program, and the values of the states are memoised on a hash of the state.
+//
+// The other players are assumed to play against player `index`, which makes every stage a zero-sum game:
+// the value is the loss that player `index` can guarantee whatever the others do, and the strategy is the mixed strategy
+// that guarantees it. With two players and an objective like `Win`, this is the equilibrium of the game.
+use super::*;
+use super::cache::fnv1a;
+use super::objective::Objective;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EndgameSettings {
+    /// The solver is only used if at most `max_players` players can play
+    pub max_players: usize,
+    /// The solver is only used if the number of joint actions of the current turn, to the power of the number of rounds,
+    /// is below this bound
+    pub max_nodes: f64,
+}
+
+impl Default for EndgameSettings {
+    fn default() -> Self {
+        Self {
+            max_players: 3,
+            max_nodes: 1e6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EndgameSolution {
+    /// The loss that player `index` can guarantee
+    pub value: f64,
+    /// The optimal mixed strategy of player `index` for the current turn
+    pub strategy: Vec<(Action, f64)>,
+    /// The loss that each action guarantees, if player `index` plays optimally afterwards
+    pub action_values: Vec<(Action, f64)>,
+    /// Number of distinct states that were solved
+    pub states: usize,
+}
+
+// Returns the possible actions of player `n`, restricted to its constraint if there is one
+fn actions_of(players: &[Player], n: usize, constraints: &[(usize, Action)]) -> Vec<Action> {
+    let possible = players[n].possible_actions(players.iter().enumerate().filter(|(m, _p)| *m != n));
+
+    match constraints.iter().rev().find(|(m, _)| *m == n) {
+        Some((_, action)) if possible.contains(action) => vec![*action],
+        _ => possible,
+    }
+}
+
+// Returns every joint action of the players other than `index`, with `Action::None` for player `index`
+fn joint_actions(players: &[Player], index: usize, constraints: &[(usize, Action)]) -> Vec<Vec<Action>> {
+    let mut res = vec![vec![Action::None; players.len()]];
+
+    for n in 0..players.len() {
+        if n == index {
+            continue;
+        }
+
+        let actions = actions_of(players, n, constraints);
+        res = res.into_iter().flat_map(|joint| {
+            actions.iter().map(move |action| {
+                let mut joint = joint.clone();
+                joint[n] = *action;
+                joint
+            })
+        }).collect();
+    }
+
+    res
+}
+
+/// Returns true if the game can be solved exactly within `rounds` rounds
+pub fn is_small(players: &[Player], rounds: usize, settings: &EndgameSettings) -> bool {
+    if players.iter().filter(|p| p.can_play()).count() > settings.max_players || players.iter().any(|p| p.is_target) {
+        return false;
+    }
+
+    let joint: f64 = (0..players.len()).map(|n| actions_of(players, n, &[]).len() as f64).product();
+
+    joint.powi(rounds as i32) <= settings.max_nodes
+}
+
+// Hashes the parts of the state that `update` and the objectives depend on
+fn state_hash(players: &[Player], rounds: usize) -> u64 {
+    let mut bytes = Vec::with_capacity(players.len() * 20 + 8);
+    bytes.extend_from_slice(&(rounds as u64).to_le_bytes());
+
+    for player in players {
+        bytes.extend_from_slice(&player.soldiers.to_le_bytes());
+        bytes.extend_from_slice(&[player.walls, player.defense, player.barracks, player.obelisks, player.is_target as u8]);
+        bytes.extend_from_slice(&(player.victories as u32).to_le_bytes());
+        bytes.extend_from_slice(&(player.defeats as u32).to_le_bytes());
+    }
+
+    fnv1a(&bytes)
+}
+
+struct Solver<'a, Loss: Objective> {
+    index: usize,
+    compute_loss: &'a Loss,
+    memo: HashMap<u64, f64>,
+}
+
+impl<'a, Loss: Objective> Solver<'a, Loss> {
+    // Returns the matrix of the values of the next states, as `matrix[row][column]`, along with the actions of the rows
+    fn matrix(&mut self, players: &[Player], rounds: usize, constraints: &[(usize, Action)]) -> (Vec<Action>, Vec<Vec<f64>>) {
+        let rows = actions_of(players, self.index, &[]);
+        let columns = joint_actions(players, self.index, constraints);
+
+        let matrix = rows.iter().map(|action| {
+            columns.iter().map(|joint| {
+                let mut joint = joint.clone();
+                joint[self.index] = *action;
+                self.value(&update(players.to_vec(), &joint), rounds - 1)
+            }).collect()
+        }).collect();
+
+        (rows, matrix)
+    }
+
+    fn value(&mut self, players: &[Player], rounds: usize) -> f64 {
+        if rounds == 0 || players.iter().any(|p| p.won()) {
+            return self.compute_loss.loss(players, self.index);
+        }
+
+        let key = state_hash(players, rounds);
+        if let Some(value) = self.memo.get(&key) {
+            return *value;
+        }
+
+        let (_, matrix) = self.matrix(players, rounds, &[]);
+        let (value, _) = solve_matrix_game(&matrix);
+        self.memo.insert(key, value);
+
+        value
+    }
+}
+
+/// Solves the endgame for player `index`, `rounds` rounds deep (including the current turn);
+/// the other players follow `constraints` during the current turn
+pub fn solve_endgame<Loss: Objective>(
+    players: &[Player],
+    index: usize,
+    rounds: usize,
+    constraints: &[(usize, Action)],
+    compute_loss: &Loss,
+) -> EndgameSolution {
+    let mut solver = Solver {
+        index,
+        compute_loss,
+        memo: HashMap::new(),
+    };
+
+    if rounds == 0 || players.iter().any(|p| p.won()) {
+        let value = compute_loss.loss(players, index);
+        return EndgameSolution {
+            value,
+            strategy: vec![(Action::None, 1.0)],
+            action_values: vec![(Action::None, value)],
+            states: 0,
+        };
+    }
+
+    let constraints = constraints.iter().copied().filter(|(n, _)| *n != index).collect::<Vec<_>>();
+    let (rows, matrix) = solver.matrix(players, rounds, &constraints);
+    let (value, strategy) = solve_matrix_game(&matrix);
+
+    // The value of a pure action is its worst case over the joint actions of the other players
+    let action_values = rows.iter().zip(matrix.iter()).map(|(action, row)| {
+        (*action, row.iter().copied().fold(f64::NEG_INFINITY, f64::max))
+    }).collect();
+
+    EndgameSolution {
+        value,
+        strategy: rows.into_iter().zip(strategy).collect(),
+        action_values,
+        states: solver.memo.len() + 1,
+    }
+}
+
+/// Solves the zero-sum matrix game in which the row player minimizes `matrix[row][column]` and the column player
+/// maximizes it. Returns the value of the game and the optimal mixed strategy of the row player.
+///
+/// The game is turned into the linear program `max Σw s.t. Mw ≤ 1, w ≥ 0` of the column player, `M` being the
+/// payoff matrix of the row player as a maximizer, shifted to be positive; the strategy of the row player is then
+/// read from the dual values of the constraints, in the final simplex tableau.
+pub fn solve_matrix_game(matrix: &[Vec<f64>]) -> (f64, Vec<f64>) {
+    const EPSILON: f64 = 1e-12;
+
+    let m = matrix.len();
+    let n = matrix.first().map(|row| row.len()).unwrap_or(0);
+    assert!(m > 0 && n > 0 && matrix.iter().all(|row| row.len() == n));
+
+    let min = matrix.iter().flatten().copied().fold(f64::INFINITY, f64::min);
+    let max = matrix.iter().flatten().copied().fold(f64::NEG_INFINITY, f64::max);
+    if max - min < EPSILON {
+        return (max, vec![1.0 / m as f64; m]);
+    }
+
+    // M = shift - matrix, with every entry ≥ 1
+    let shift = max + 1.0;
+
+    // Tableau with the m constraints, then the objective row; the columns are w, the slacks and the right hand side
+    let width = n + m + 1;
+    let mut tableau = vec![vec![0.0; width]; m + 1];
+    for (i, row) in matrix.iter().enumerate() {
+        for (j, x) in row.iter().enumerate() {
+            tableau[i][j] = shift - x;
+        }
+        tableau[i][n + i] = 1.0;
+        tableau[i][width - 1] = 1.0;
+    }
+    tableau[m][0..n].iter_mut().for_each(|x| *x = -1.0);
+    let mut basis = (n..n + m).collect::<Vec<_>>();
+
+    // Bland's rule, which can't cycle
+    while let Some(column) = (0..width - 1).find(|&j| tableau[m][j] < -EPSILON) {
+        let pivot = (0..m)
+            .filter(|&i| tableau[i][column] > EPSILON)
+            .min_by(|&a, &b| {
+                let ratio = |i: usize| tableau[i][width - 1] / tableau[i][column];
+                ratio(a).partial_cmp(&ratio(b)).unwrap().then(basis[a].cmp(&basis[b]))
+            })
+            .expect("The linear program of a matrix game is bounded");
+
+        let factor = tableau[pivot][column];
+        tableau[pivot].iter_mut().for_each(|x| *x /= factor);
+
+        let pivot_row = tableau[pivot].clone();
+        for (i, row) in tableau.iter_mut().enumerate() {
+            if i != pivot && row[column].abs() > EPSILON {
+                let factor = row[column];
+                row.iter_mut().zip(pivot_row.iter()).for_each(|(x, y)| *x -= factor * y);
+            }
+        }
+
+        basis[pivot] = column;
+    }
+
+    let sum = tableau[m][width - 1];
+    let strategy = (0..m).map(|i| tableau[m][n + i].max(0.0) / sum).collect::<Vec<_>>();
+
+    // The value of the game with M is 1 / sum, and matrix = shift - M
+    (shift - 1.0 / sum, strategy)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::objective::ValueFn;
+
+    #[test]
+    fn test_endgame() {
+        let (value, strategy) = solve_matrix_game(&[vec![1.0, -1.0], vec![-1.0, 1.0]]);
+        assert!(value.abs() < 1e-9);
+        assert!((strategy[0] - 0.5).abs() < 1e-9 && (strategy[1] - 0.5).abs() < 1e-9);
+
+        let (value, strategy) = solve_matrix_game(&[vec![3.0, 1.0], vec![2.0, 2.5], vec![4.0, 4.0]]);
+        assert!((value - 2.2).abs() < 1e-9);
+        assert!((strategy[0] - 0.2).abs() < 1e-9 && (strategy[1] - 0.8).abs() < 1e-9 && strategy[2].abs() < 1e-9);
+
+        // Player 1 can get through the wall of player 0, unless player 0 defends
+        let players = vec![
+            Player::with_values(1, 0, 1, 2, 0),
+            Player::with_values(1, 2, 1, 2, 0),
+        ];
+        let loss = ValueFn(|p: &[Player], index: usize| -(p[index].obelisks as f64));
+
+        assert!(is_small(&players, 2, &EndgameSettings::default()));
+        let solution = solve_endgame(&players, 0, 1, &[], &loss);
+        assert_eq!(solution.value, -2.0);
+        assert_eq!(solution.strategy.iter().find(|(action, _)| *action == Action::Defend).unwrap().1, 1.0);
+        assert_eq!(solution.action_values.iter().find(|(action, _)| *action == Action::Obelisk).unwrap().1, -1.0);
+
+        // If player 1 is forced to build, player 0 is better off building an obelisk
+        let solution = solve_endgame(&players, 0, 1, &[(1, Action::Wall)], &loss);
+        assert_eq!(solution.value, -3.0);
+    }
+}
@@ -0,0 +1,244 @@
+// Several models played together, typically several generations of the model trained by self-play, so that the
+// simulated opponents aren't all of the style of the last generation.
+// An `Ensemble` either averages the predictions of its members, weighted, or draws one member per simulated game
+// (and, in the latter case, every player of that game follows the same member).
+use super::*;
+use super::inference::Policy;
+use super::model::{load_network, ModelBackend, ModelPolicy, ModelPrec, Network};
+use super::monte_carlo::RolloutAi;
+use rand::rngs::ThreadRng;
+use rand::seq::SliceRandom;
+use std::path::{Path, PathBuf};
+use tract_onnx::prelude::TractResult;
+
+/// How the members of an `Ensemble` are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum EnsembleMode {
+    /// The action distributions of the members are averaged, weighted
+    #[default]
+    Average,
+    /// A member is drawn at the start of every simulated game, according to the weights
+    PerGame,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EnsembleMember {
+    pub path: PathBuf,
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+}
+
+/// The content of an `ensemble.json` file, for instance
+/// `{"members": [{"path": "target/model-1.onnx", "weight": 2.0}, {"path": "target/model-2.onnx"}], "mode": "PerGame"}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EnsembleConfig {
+    pub members: Vec<EnsembleMember>,
+    #[serde(default)]
+    pub mode: EnsembleMode,
+}
+
+impl EnsembleConfig {
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        let raw = std::fs::read_to_string(path).unwrap_or_else(|_| panic!("Couldn't open {}", path.display()));
+
+        serde_json::from_str(&raw).unwrap_or_else(|_| panic!("Couldn't parse {}", path.display()))
+    }
+
+    /// Loads the model of every member, in order
+    pub fn load_networks(&self, backend: ModelBackend) -> TractResult<Vec<Network>> {
+        self.members.iter().map(|member| load_network(&member.path, backend)).collect()
+    }
+
+    pub fn weights(&self) -> Vec<f64> {
+        self.members.iter().map(|member| member.weight).collect()
+    }
+}
+
+/// Several `ModelPolicy`s combined into one policy (see `EnsembleMode`)
+#[derive(Clone, Copy)]
+pub struct Ensemble<'a> {
+    pub members: &'a [ModelPolicy<'a>],
+    /// The weight of each member; they don't have to sum up to 1, but they must be non-negative, with a positive sum
+    pub weights: &'a [f64],
+    pub mode: EnsembleMode,
+}
+
+impl<'a> Ensemble<'a> {
+    pub fn new(members: &'a [ModelPolicy<'a>], weights: &'a [f64], mode: EnsembleMode) -> Self {
+        assert!(!members.is_empty(), "An ensemble needs at least one member");
+        assert_eq!(members.len(), weights.len());
+        assert!(
+            weights.iter().all(|weight| weight.is_finite() && *weight >= 0.0) && weights.iter().sum::<f64>() > 0.0,
+            "The weights of an ensemble must be finite and non-negative, with a positive sum, got {:?}",
+            weights
+        );
+
+        Self { members, weights, mode }
+    }
+
+    // Draws a member according to the weights
+    fn draw_member(&self, rng: &mut ThreadRng) -> usize {
+        (0..self.members.len()).collect::<Vec<_>>().choose_weighted(rng, |&n| self.weights[n]).copied().unwrap_or(0)
+    }
+
+    /// Returns the weighted average of the predictions of the members for player `index`, from the most to the least likely action
+    pub fn predict(&self, players: &[Player], index: usize, previous_actions: &[Action]) -> Vec<(Action, ModelPrec)> {
+        let predictions = self.members.iter().map(|member| member.predict(players, index, previous_actions)).collect();
+
+        average(predictions, self.weights)
+    }
+
+    /// Returns the weighted average of the predictions of the members for every player, with one batch per member
+    pub fn predict_all(&self, players: &[Player], previous_actions: &[Vec<Action>]) -> Vec<Vec<(Action, ModelPrec)>> {
+        let mut predictions = self.members.iter().map(|member| member.predict_all(players, previous_actions).into_iter()).collect::<Vec<_>>();
+
+        (0..players.len()).map(|_| {
+            average(predictions.iter_mut().map(|member| member.next().unwrap()).collect(), self.weights)
+        }).collect()
+    }
+}
+
+// Averages the predictions of several members, with `weights`
+fn average(predictions: Vec<Vec<(Action, ModelPrec)>>, weights: &[f64]) -> Vec<(Action, ModelPrec)> {
+    let total = weights.iter().sum::<f64>();
+    let mut res: Vec<(Action, ModelPrec)> = Vec::new();
+
+    for (member, weight) in predictions.into_iter().zip(weights.iter()) {
+        let weight = (weight / total) as ModelPrec;
+
+        for (action, prob) in member {
+            match res.iter_mut().find(|(a, _)| *a == action) {
+                Some((_, sum)) => *sum += weight * prob,
+                None => res.push((action, weight * prob)),
+            }
+        }
+    }
+
+    res.sort_unstable_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+
+    res
+}
+
+// Draws an action from averaged predictions
+fn sample_average(predictions: &[(Action, ModelPrec)], rng: &mut ThreadRng) -> Action {
+    predictions.choose_weighted(rng, |(_, prob)| *prob).map(|(action, _)| *action).unwrap_or(Action::None)
+}
+
+/// Outside of a simulated game, both modes play the same mixture of the members
+impl<'a> Policy for Ensemble<'a> {
+    fn probability(&self, players: &[Player], index: usize, _round: usize, previous_actions: &[Action], action: Action) -> f64 {
+        self.predict(players, index, previous_actions)
+            .into_iter()
+            .find(|(a, _)| *a == action)
+            .map(|(_, prob)| prob as f64)
+            .unwrap_or(0.0)
+    }
+
+    fn sample(&self, players: &[Player], index: usize, round: usize, previous_actions: &[Action], rng: &mut ThreadRng) -> Action {
+        match self.mode {
+            EnsembleMode::Average => sample_average(&self.predict(players, index, previous_actions), rng),
+            EnsembleMode::PerGame => self.members[self.draw_member(rng)].sample(players, index, round, previous_actions, rng),
+        }
+    }
+
+    fn distribution(&self, players: &[Player], index: usize, _round: usize, previous_actions: &[Action]) -> Vec<(Action, f64)> {
+        self.predict(players, index, previous_actions)
+            .into_iter()
+            .map(|(action, prob)| (action, prob as f64))
+            .collect()
+    }
+}
+
+impl<'a> RolloutAi for Ensemble<'a> {
+    /// The member that plays the game, with `EnsembleMode::PerGame`
+    type Game = Option<usize>;
+
+    fn new_game(&self, _players: &[Player], rng: &mut ThreadRng) -> Option<usize> {
+        match self.mode {
+            EnsembleMode::Average => None,
+            EnsembleMode::PerGame => Some(self.draw_member(rng)),
+        }
+    }
+
+    fn action(
+        &self,
+        game: &Option<usize>,
+        players: &[Player],
+        index: usize,
+        round: usize,
+        previous_actions: &[Action],
+        rng: &mut ThreadRng,
+    ) -> Action {
+        match game {
+            Some(member) => self.members[*member].action(&(), players, index, round, previous_actions, rng),
+            None => sample_average(&self.predict(players, index, previous_actions), rng),
+        }
+    }
+
+    fn actions(
+        &self,
+        game: &Option<usize>,
+        players: &[Player],
+        round: usize,
+        previous_actions: &[Vec<Action>],
+        rng: &mut ThreadRng,
+    ) -> Vec<Action> {
+        match game {
+            Some(member) => self.members[*member].actions(&(), players, round, previous_actions, rng),
+            None => self.predict_all(players, previous_actions)
+                .iter()
+                .map(|predictions| sample_average(predictions, rng))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::train::{initialize, TrainSettings};
+
+    #[test]
+    fn test_ensemble() {
+        let settings = TrainSettings {
+            hidden: vec![8],
+            ..Default::default()
+        };
+        let mut rng = rand::thread_rng();
+        let networks = [Network::Native(initialize(&settings, &mut rng)), Network::Native(initialize(&settings, &mut rng))];
+        let members = networks.iter().map(ModelPolicy::new).collect::<Vec<_>>();
+        let weights = [3.0, 1.0];
+
+        let players = vec![Player::with_values(2, 1, 4, 2, 0), Player::with_values(4, 3, 2, 2, 1), Player::new()];
+        let previous_actions = vec![vec![Action::Wall], vec![Action::Recruit], vec![Action::Skip]];
+
+        let ensemble = Ensemble::new(&members, &weights, EnsembleMode::Average);
+        let first = members[0].predict(&players, 1, &previous_actions[1]);
+        let second = members[1].predict(&players, 1, &previous_actions[1]);
+        let averaged = ensemble.predict(&players, 1, &previous_actions[1]);
+
+        assert_eq!(averaged.len(), first.len());
+        assert!((averaged.iter().map(|(_, prob)| prob).sum::<ModelPrec>() - 1.0).abs() < 1e-5);
+        for (action, prob) in averaged.iter() {
+            let of = |predictions: &[(Action, ModelPrec)]| predictions.iter().find(|(a, _)| a == action).unwrap().1;
+            assert!((prob - (0.75 * of(&first) + 0.25 * of(&second))).abs() < 1e-6);
+        }
+        assert_eq!(ensemble.predict_all(&players, &previous_actions)[1], averaged);
+
+        // With one member drawn per game, every player of the game follows that member
+        let ensemble = Ensemble::new(&members[1..], &weights[1..], EnsembleMode::PerGame);
+        let game = ensemble.new_game(&players, &mut rng);
+        assert_eq!(game, Some(0));
+        let actions = ensemble.actions(&game, &players, 1, &previous_actions, &mut rng);
+        assert!(second.iter().any(|(action, _)| *action == actions[1]));
+
+        let config: EnsembleConfig = serde_json::from_str(r#"{"members": [{"path": "target/model-1.onnx", "weight": 2.0}, {"path": "target/model-2.onnx"}]}"#).unwrap();
+        assert_eq!(config.weights(), vec![2.0, 1.0]);
+        assert_eq!(config.mode, EnsembleMode::Average);
+    }
+}
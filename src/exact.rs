@@ -0,0 +1,261 @@
+// Exact evaluation of the next turn: instead of sampling, every joint action of the other players is enumerated,
+// weighted by the probability that a policy (usually the model) gives to it, and `update` is applied to each of them.
+// This answers short-horizon questions like "can anyone siege me this turn?" without sampling noise.
+//
+// Many actions have the same effect on the sieges of the turn: building a wall, a barrack or an obelisk and skipping
+// only change the player that takes them, after the sieges are resolved. These actions are thus grouped together
+// (see `action_class`), which keeps the enumeration exact while reducing the number of joint actions.
+// Only the players whose actions can affect the player are enumerated: the ones that may attack it or its target, and
+// recursively the ones that may attack them (see `relevant_players`); the others can't change its state.
+// Joint actions less likely than `ExactSettings::threshold` are pruned; the total probability that was pruned is reported.
+// On large tables where everyone may attack everyone, the enumeration is exponential in the number of players and the
+// threshold prunes most of the probability, so it is skipped beyond `ExactSettings::max_players` relevant players.
+use super::*;
+use super::inference::Policy;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExactSettings {
+    /// Joint actions whose probability is below this threshold aren't enumerated
+    pub threshold: f64,
+    /// The largest number of relevant players (see `relevant_players`) that are enumerated
+    pub max_players: usize,
+}
+
+impl Default for ExactSettings {
+    fn default() -> Self {
+        Self {
+            threshold: 1e-4,
+            max_players: 6,
+        }
+    }
+}
+
+/// The exact distribution of the state of a player after the next turn
+#[derive(Debug, Clone, PartialEq)]
+pub struct NextTurn {
+    /// The possible states of the player, with their probability, from the most to the least likely
+    pub states: Vec<(Player, f64)>,
+    /// Probability of being sieged at least once
+    pub sieged: f64,
+    /// Probability of losing `n` obelisks to sieges, indexed by `n`
+    pub obelisk_losses: Vec<f64>,
+    /// Probability of the joint actions that were pruned; the other probabilities sum up to `1 - pruned`
+    pub pruned: f64,
+}
+
+/// Returns a representative of the actions that have the same effect as `action` on the sieges of the turn
+#[inline]
+pub fn action_class(action: Action) -> Action {
+    match action {
+        Action::Wall | Action::Barracks | Action::Obelisk | Action::Skip => Action::Skip,
+        x => x,
+    }
+}
+
+/// Returns, for every player, the probability of each class of actions (see `action_class`) according to `policy`
+pub fn action_distributions<P: Policy>(
+    players: &[Player],
+    previous_actions: &[Vec<Action>],
+    round: usize,
+    policy: &P,
+) -> Vec<Vec<(Action, f64)>> {
+    (0..players.len()).map(|index| {
+        if !players[index].can_play() {
+            return vec![(Action::None, 1.0)];
+        }
+
+        let mut res: Vec<(Action, f64)> = Vec::new();
+        for (action, probability) in policy.distribution(players, index, round, &previous_actions[index]) {
+            let class = action_class(action);
+            match res.iter_mut().find(|(a, _)| *a == class) {
+                Some((_, p)) => *p += probability,
+                None => res.push((class, probability)),
+            }
+        }
+
+        res
+    }).collect()
+}
+
+/// Returns the players whose actions can change the state of player `index` after the next turn, if they take `action`
+/// and if every other player follows `distributions`, sorted and including `index`: the players that may attack a relevant
+/// player, and the players that a relevant player may attack. The sieges of the other players only involve each other.
+pub fn relevant_players(index: usize, action: Action, distributions: &[Vec<(Action, f64)>]) -> Vec<usize> {
+    let targets = |n: usize| -> Vec<usize> {
+        let actions = if n == index {
+            vec![action]
+        } else {
+            distributions[n].iter().filter(|(_, p)| *p > 0.0).map(|(a, _)| *a).collect()
+        };
+
+        actions.into_iter().filter_map(|action| match action {
+            Action::Attack(target) => Some(target),
+            _ => None,
+        }).collect()
+    };
+
+    let mut relevant = vec![false; distributions.len()];
+    let mut stack = vec![index];
+    relevant[index] = true;
+
+    while let Some(n) = stack.pop() {
+        let attackers = (0..distributions.len()).filter(|&m| targets(m).contains(&n));
+        for m in targets(n).into_iter().chain(attackers) {
+            if !relevant[m] {
+                relevant[m] = true;
+                stack.push(m);
+            }
+        }
+    }
+
+    (0..distributions.len()).filter(|&n| relevant[n]).collect()
+}
+
+/// Computes the exact distribution of the state of player `index` after the next turn, if they take `action`
+/// and if every other player follows `distributions` (see `action_distributions`).
+/// Returns `None` if more than `settings.max_players` players are relevant (see `relevant_players`).
+pub fn exact_next_turn(
+    players: &[Player],
+    index: usize,
+    action: Action,
+    distributions: &[Vec<(Action, f64)>],
+    settings: &ExactSettings,
+) -> Option<NextTurn> {
+    assert!(distributions.len() == players.len());
+
+    let relevant = relevant_players(index, action, distributions);
+    if relevant.len() > settings.max_players {
+        return None;
+    }
+
+    // The players that aren't relevant take any of their actions, which only involves the other irrelevant players
+    let mut actions = distributions.iter()
+        .map(|distribution| distribution.first().map(|(a, _)| *a).unwrap_or(Action::None))
+        .collect::<Vec<_>>();
+    actions[index] = action;
+
+    let mut enumeration = Enumeration {
+        players,
+        index,
+        distributions,
+        settings,
+        opponents: relevant.into_iter().filter(|&n| n != index).collect(),
+        actions,
+        res: NextTurn {
+            states: Vec::new(),
+            sieged: 0.0,
+            obelisk_losses: Vec::new(),
+            pruned: 0.0,
+        },
+    };
+    enumeration.enumerate(0, 1.0);
+
+    let mut res = enumeration.res;
+    res.states.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+    Some(res)
+}
+
+// The state of the enumeration of the joint actions of the relevant opponents
+struct Enumeration<'a> {
+    players: &'a [Player],
+    index: usize,
+    distributions: &'a [Vec<(Action, f64)>],
+    settings: &'a ExactSettings,
+    opponents: Vec<usize>,
+    actions: Vec<Action>,
+    res: NextTurn,
+}
+
+impl Enumeration<'_> {
+    // Enumerates the actions of the opponents from `opponents[k]` onwards, `probability` being the probability of the
+    // actions chosen so far
+    fn enumerate(&mut self, k: usize, probability: f64) {
+        let Some(&n) = self.opponents.get(k) else {
+            self.record(probability);
+            return;
+        };
+
+        for (action, p) in self.distributions[n].iter().copied() {
+            let probability = probability * p;
+            if probability < self.settings.threshold {
+                self.res.pruned += probability;
+                continue;
+            }
+
+            self.actions[n] = action;
+            self.enumerate(k + 1, probability);
+        }
+    }
+
+    fn record(&mut self, probability: f64) {
+        let res = &mut self.res;
+        let next = update(self.players.to_vec(), &self.actions);
+        let (before, after) = (&self.players[self.index], &next[self.index]);
+
+        let defeats = after.defeats - before.defeats;
+        if defeats > 0 {
+            res.sieged += probability;
+        }
+        if res.obelisk_losses.len() <= defeats {
+            res.obelisk_losses.resize(defeats + 1, 0.0);
+        }
+        res.obelisk_losses[defeats] += probability;
+
+        match res.states.iter_mut().find(|(p, _)| p == after && p.defeats == after.defeats && p.victories == after.victories) {
+            Some((_, p)) => *p += probability,
+            None => res.states.push((after.clone(), probability)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_exact_next_turn() {
+        let players = vec![
+            Player::with_values(1, 1, 1, 2, 0),
+            Player::with_values(1, 5, 1, 1, 0),
+            Player::with_values(1, 2, 1, 1, 0),
+        ];
+        let distributions = vec![
+            vec![],
+            vec![(Action::Attack(0), 0.3), (Action::Skip, 0.7)],
+            vec![(Action::Attack(0), 0.5), (Action::Defend, 0.5)],
+        ];
+
+        // Player 2 alone can't get through the wall and the soldier of player 0, so only the attacks of player 1 matter
+        let next = exact_next_turn(&players, 0, Action::Skip, &distributions, &ExactSettings::default()).unwrap();
+        assert!((next.sieged - 0.3).abs() < 1e-9);
+        assert!((next.obelisk_losses[1] - 0.3).abs() < 1e-9);
+        assert_eq!(next.pruned, 0.0);
+        assert!((next.states.iter().map(|(_, p)| p).sum::<f64>() - 1.0).abs() < 1e-9);
+
+        // Defending doubles the walls, which stops the joint attack but not player 1 alone
+        let next = exact_next_turn(&players, 0, Action::Defend, &distributions, &ExactSettings::default()).unwrap();
+        assert!((next.sieged - 0.15).abs() < 1e-9);
+
+        let settings = ExactSettings { threshold: 0.2, ..Default::default() };
+        let next = exact_next_turn(&players, 0, Action::Skip, &distributions, &settings).unwrap();
+        assert!((next.pruned - 0.3).abs() < 1e-9);
+
+        // A pair of players that only attack each other can't change the state of player 0, unless it attacks them
+        let mut players = players;
+        players.extend([Player::with_values(1, 2, 1, 1, 0), Player::with_values(1, 2, 1, 1, 0)]);
+        let distributions = [distributions, vec![
+            vec![(Action::Attack(4), 0.5), (Action::Skip, 0.5)],
+            vec![(Action::Attack(3), 0.5), (Action::Defend, 0.5)],
+        ]].concat();
+        assert_eq!(relevant_players(0, Action::Skip, &distributions), vec![0, 1, 2]);
+        assert_eq!(relevant_players(0, Action::Attack(3), &distributions), vec![0, 1, 2, 3, 4]);
+        let next = exact_next_turn(&players, 0, Action::Skip, &distributions, &ExactSettings::default()).unwrap();
+        assert!((next.sieged - 0.3).abs() < 1e-9);
+
+        // Beyond `max_players` relevant players, the enumeration is skipped
+        let settings = ExactSettings { max_players: 4, ..Default::default() };
+        assert!(exact_next_turn(&players, 0, Action::Skip, &distributions, &settings).is_some());
+        assert!(exact_next_turn(&players, 0, Action::Attack(3), &distributions, &settings).is_none());
+    }
+}
@@ -1,6 +1,9 @@
 // Generates training data from SimpleAgents and in-training AIs
 use super::genetic_basic::*;
 use super::monte_carlo::*;
+use super::objective::*;
+use super::model::ModelPolicy;
+use super::ensemble::Ensemble;
 use super::*;
 use float_duration::{FloatDuration, FromDuration};
 use rand::prelude::*;
@@ -14,6 +17,8 @@ pub struct TrainingSettings {
     pub initial_actions: std::ops::Range<usize>,
     pub initial_noise: f64,
     pub max_rounds: usize,
+    /// The horizon of the simulations; with `Horizon::GameEnd`, the games are simulated up to `max_rounds`
+    pub horizon: Horizon,
     pub n_players: std::ops::Range<usize>,
     pub threads: usize,
 }
@@ -26,6 +31,7 @@ impl Default for TrainingSettings {
             initial_actions: 0..40,
             initial_noise: 0.2,
             max_rounds: 50,
+            horizon: Horizon::GameEnd,
             n_players: 4..16,
             threads: num_cpus::get(),
         }
@@ -37,6 +43,10 @@ pub struct TrainingData {
     pub previous_actions: Vec<Vec<Action>>,
     pub players: Vec<Player>,
     pub best_actions: Vec<(Action, f64, f64)>,
+    /// The mean loss of every player over the simulated games, when every player follows the rollout policy;
+    /// the target of the value head (see `ModelValue`)
+    #[serde(default)]
+    pub values: Vec<f64>,
 }
 
 impl TrainingData {
@@ -49,8 +59,14 @@ impl TrainingData {
             previous_actions,
             players,
             best_actions,
+            values: Vec::new(),
         }
     }
+
+    /// Returns the actions taken by player `index` so far; `previous_actions` is stored round by round
+    pub fn history(&self, index: usize) -> Vec<Action> {
+        self.previous_actions.iter().map(|actions| actions[index]).collect()
+    }
 }
 
 pub fn generate_training_data_simpleagent(
@@ -64,19 +80,33 @@ pub fn generate_training_data_simpleagent(
         action
     };
 
-    let compute_loss = crate::genetic_basic::compute_loss;
+    let compute_loss = &Heuristic;
 
     generate_training_data(settings, ai, compute_loss)
 }
 
+/// Generates training data from games played by the model, each player following its seat's settings in `policy`
+pub fn generate_training_data_model(settings: TrainingSettings, policy: ModelPolicy) -> Vec<TrainingData> {
+    let compute_loss = &Heuristic;
+
+    generate_training_data(settings, policy, compute_loss)
+}
+
+/// Generates training data from games played by an ensemble of models
+pub fn generate_training_data_ensemble(settings: TrainingSettings, ensemble: Ensemble) -> Vec<TrainingData> {
+    let compute_loss = &Heuristic;
+
+    generate_training_data(settings, ensemble, compute_loss)
+}
+
 pub fn generate_training_data<Ai, Loss>(
     settings: TrainingSettings,
     ai: Ai,
     compute_loss: Loss,
 ) -> Vec<TrainingData>
 where
-    Ai: for<'c> AiFn<'c, rand::rngs::ThreadRng> + Copy + Send,
-    Loss: for<'c> Fn(&'c [Player], usize) -> f64 + Copy + Send,
+    Ai: RolloutAi + Copy + Send,
+    Loss: Objective + Copy + Send,
 {
     use std::time::Instant;
     let res = Mutex::new(Vec::new());
@@ -137,14 +167,15 @@ fn generate_training_data_sub<Ai, Loss>(
     rng: &mut rand::rngs::ThreadRng,
 ) -> TrainingData
 where
-    Ai: for<'c> AiFn<'c, rand::rngs::ThreadRng> + Copy,
-    Loss: for<'c> Fn(&'c [Player], usize) -> f64 + Copy,
+    Ai: RolloutAi + Copy,
+    Loss: Objective + Copy,
 {
     use std::cmp::Ordering;
 
     let mut players = vec![Player::new(); rng.gen_range(settings.n_players.clone())];
     let initial_rounds = rng.gen_range(settings.initial_actions.clone());
     let mut previous_actions: Vec<Vec<Action>> = Vec::with_capacity(initial_rounds);
+    let game = ai.new_game(&players, rng);
 
     for round in 0..initial_rounds {
         let actions = (0..players.len())
@@ -161,7 +192,7 @@ where
                         .into()
                 } else {
                     let previous_actions = previous_actions.iter().map(|v| v[n]).collect::<Vec<_>>();
-                    ai(&players, n, round, &previous_actions, rng)
+                    ai.action(&game, &players, n, round, &previous_actions, rng)
                 }
             })
             .collect::<Vec<_>>();
@@ -174,26 +205,39 @@ where
     }
 
     let mut best_actions = Vec::with_capacity(players.len());
+    let history = (0..players.len())
+        .map(|n| previous_actions.iter().map(|actions| actions[n]).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    let mc_settings = MonteCarloSettings {
+        samples: settings.samples,
+        max_rounds: settings.max_rounds - initial_rounds,
+        round_offset: initial_rounds,
+        horizon: settings.horizon,
+        ..Default::default()
+    };
+
+    let rollout = Rollout { first_ai: SameAi, ai, compute_loss };
 
     for player_index in 0..players.len() {
-        let (best, mut losses) = mc_best_action(
-            &players,
-            player_index,
-            &previous_actions,
-            vec![],
-            settings.samples,
-            settings.max_rounds - initial_rounds,
-            initial_rounds,
-            ai,
-            compute_loss,
-        );
-
-        losses.sort_by(|(_, a, _), (_, b, _)| a.partial_cmp(&b).unwrap_or(Ordering::Equal));
-
-        let (_, loss, variance) = losses[0];
-        let confidence = 1.96 * (variance / settings.samples as f64).sqrt();
-
-        best_actions.push((best, loss, confidence));
+        let (best, mut losses) = mc_best_action(&players, player_index, &history, vec![], &mc_settings, rollout);
+
+        losses.sort_by(|(_, a), (_, b)| a.mean().partial_cmp(&b.mean()).unwrap_or(Ordering::Equal));
+
+        let outcome = &losses[0].1;
+
+        best_actions.push((best, outcome.mean(), outcome.confidence()));
+    }
+
+    // The value targets come from games in which nobody is constrained
+    let indices = (0..players.len()).collect::<Vec<_>>();
+    let values = monte_carlo(&players, &history, &[], &indices, &mc_settings, rollout)
+        .iter()
+        .map(|outcome| outcome.mean())
+        .collect();
+
+    TrainingData {
+        values,
+        ..TrainingData::new(previous_actions, players, best_actions)
     }
-    TrainingData::new(previous_actions, players, best_actions)
 }
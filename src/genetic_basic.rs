@@ -2,6 +2,7 @@ use super::*;
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
+use super::inference::Policy;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SimpleAgentAction {
@@ -128,15 +129,7 @@ impl SimpleAgent {
         }
 
         if self.genome[step] == SimpleAgentAction::Attack {
-            let targets: SmallVec<[usize; 10]> = players
-                .iter()
-                .enumerate()
-                .filter(|&(n, p)| {
-                    let strength = p.walls as u32 * if p.defense > 0 { 2 } else { 1 } + p.soldiers;
-                    return n != index && strength < players[index].soldiers && p.can_be_attacked();
-                })
-                .map(|(n, _p)| n)
-                .collect::<SmallVec<_>>();
+            let targets = attack_targets(players, index);
 
             if targets.len() > 0 {
                 return Action::Attack(*targets.choose(rng).unwrap());
@@ -149,6 +142,48 @@ impl SimpleAgent {
     }
 }
 
+// The players that `index` is strong enough to siege
+#[inline]
+fn attack_targets(players: &[Player], index: usize) -> SmallVec<[usize; 10]> {
+    players
+        .iter()
+        .enumerate()
+        .filter(|&(n, p)| {
+            let strength = p.walls as u32 * if p.defense > 0 { 2 } else { 1 } + p.soldiers;
+            return n != index && strength < players[index].soldiers && p.can_be_attacked();
+        })
+        .map(|(n, _p)| n)
+        .collect::<SmallVec<_>>()
+}
+
+impl Policy for SimpleAgent {
+    fn probability(&self, players: &[Player], index: usize, round: usize, _previous_actions: &[Action], action: Action) -> f64 {
+        let expected = if !players[index].can_play() {
+            Action::None
+        } else if round >= self.genome.len() {
+            Action::Skip
+        } else if self.genome[round] == SimpleAgentAction::Attack {
+            let targets = attack_targets(players, index);
+            if !targets.is_empty() {
+                return match action {
+                    Action::Attack(target) if targets.contains(&target) => 1.0 / targets.len() as f64,
+                    _ => 0.0,
+                };
+            }
+            Action::Skip
+        } else {
+            self.genome[round].into()
+        };
+
+        if action == expected { 1.0 } else { 0.0 }
+    }
+
+    #[inline]
+    fn sample(&self, players: &[Player], index: usize, round: usize, _previous_actions: &[Action], rng: &mut ThreadRng) -> Action {
+        self.get_action(players, index, round, rng)
+    }
+}
+
 impl std::fmt::Display for SimpleAgent {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         use SimpleAgentAction::*;
@@ -194,48 +229,7 @@ impl std::fmt::Display for SimpleAgent {
     }
 }
 
-pub fn compute_loss(players: &[Player], index: usize) -> f64 {
-    let iter = players.iter().enumerate().filter(|(n, _p)| *n != index);
-
-    let (max_obelisks, max_barracks, max_soldiers, max_walls) = iter
-        .map(|(_, x)| {
-            (
-                x.obelisks as f64,
-                x.barracks as f64,
-                x.soldiers as f64,
-                x.walls as f64,
-            )
-        })
-        .reduce(|acc, act| {
-            (
-                acc.0.max(act.0),
-                acc.1.max(act.1),
-                acc.2.max(act.2),
-                acc.3.max(act.3),
-            )
-        })
-        .unwrap_or((0.0, 0.0, 0.0, 0.0));
-
-    let player = &players[index];
-
-    let mut res = (10.0 + max_obelisks) / 2.0 - player.obelisks as f64
-        + (max_barracks - player.barracks as f64) / 5.0
-        + (max_soldiers - player.soldiers as f64) / 10.0
-        + (max_walls - player.walls as f64) / 5.0;
-
-    if player.obelisks == 0 {
-        res += 4.0;
-    }
-
-    if max_obelisks == 10.0 {
-        res += 2.0; // penalty for not winning the game first
-    }
-
-    res += player.defeats as f64 * 0.75; // penalty for losing sieges
-    res += (player.victories as f64 * -0.5).exp() * 3.0; // bonus for winning sieges
-
-    res
-}
+pub use crate::objective::heuristic_loss as compute_loss;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct SimulationSettings {
@@ -0,0 +1,244 @@
+// Importance sampling of rare events, like "probability that a player is eliminated within 5 turns".
+// With plain monte carlo, such events are only seen in a handful of samples, so their probability can't be estimated precisely.
+// Instead, the games are simulated with a proposal policy, in which the other players are more likely to attack the target,
+// and every game is weighted by the likelihood ratio between the nominal policy and the proposal policy:
+// the weighted mean of the event is an unbiased estimate of its probability under the nominal policy, with a much lower variance.
+use super::*;
+use super::inference::Policy;
+use rand::prelude::*;
+
+/// An event happening to the target player
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RareEvent {
+    /// The player has no obelisks left
+    Eliminated,
+    /// The player is sieged at least once
+    Sieged,
+    /// The player loses at least `n` obelisks to sieges
+    LostObelisks(usize),
+}
+
+impl RareEvent {
+    #[inline]
+    pub fn happened(&self, initial: &Player, current: &Player) -> bool {
+        match self {
+            RareEvent::Eliminated => current.lost(),
+            RareEvent::Sieged => current.defeats > initial.defeats,
+            RareEvent::LostObelisks(n) => current.defeats - initial.defeats >= *n,
+        }
+    }
+}
+
+impl std::str::FromStr for RareEvent {
+    type Err = String;
+
+    /// Parses `eliminated`, `sieged` or `obelisks:<n>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "eliminated" => Ok(RareEvent::Eliminated),
+            None if s == "sieged" => Ok(RareEvent::Sieged),
+            Some(("obelisks", n)) => n.parse().map(RareEvent::LostObelisks).map_err(|_| format!("Invalid event: {}", s)),
+            _ => Err(format!("Invalid event: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportanceSettings {
+    pub samples: usize,
+    /// Number of simulated rounds, including the current turn
+    pub rounds: usize,
+    pub round_offset: usize,
+    /// The probability that another player attacks the target is multiplied by `bias` in the proposal policy, before normalizing;
+    /// a bias of 1 is plain monte carlo
+    pub bias: f64,
+}
+
+impl Default for ImportanceSettings {
+    fn default() -> Self {
+        Self {
+            samples: 10000,
+            rounds: 5,
+            round_offset: 0,
+            bias: 5.0,
+        }
+    }
+}
+
+/// The estimated probability of a rare event
+#[derive(Debug, Clone, PartialEq)]
+pub struct RareEstimate {
+    pub samples: usize,
+    /// Number of simulated games in which the event happened
+    pub hits: usize,
+    /// Sum of the likelihood ratios of the games in which the event happened, and of their squares
+    pub sum: f64,
+    pub sum_square: f64,
+    /// Sum of the likelihood ratios of every game, and of their squares
+    pub sum_weights: f64,
+    pub sum_square_weights: f64,
+}
+
+impl RareEstimate {
+    /// The unbiased estimate of the probability of the event
+    pub fn probability(&self) -> f64 {
+        if self.samples == 0 {
+            return 0.0;
+        }
+
+        self.sum / self.samples as f64
+    }
+
+    /// Half-width of the 95% confidence interval of the probability
+    pub fn confidence(&self) -> f64 {
+        if self.samples == 0 {
+            return 0.0;
+        }
+
+        let mean = self.probability();
+        let variance = (self.sum_square / self.samples as f64 - mean * mean).max(0.0);
+
+        1.96 * (variance / self.samples as f64).sqrt()
+    }
+
+    /// The number of unweighted samples that would give the same precision; if it is much lower than `samples`,
+    /// then the proposal policy is too far from the nominal policy
+    pub fn effective_samples(&self) -> f64 {
+        if self.sum_square_weights == 0.0 {
+            return 0.0;
+        }
+
+        self.sum_weights * self.sum_weights / self.sum_square_weights
+    }
+}
+
+// Returns the nominal and the proposal distribution of the actions of player `n`
+fn biased_distribution<P: Policy>(
+    players: &[Player],
+    n: usize,
+    target: usize,
+    round: usize,
+    previous_actions: &[Action],
+    policy: &P,
+    bias: f64,
+) -> Vec<(Action, f64, f64)> {
+    let nominal = policy.distribution(players, n, round, previous_actions);
+    let weight = |action: Action| if n != target && action == Action::Attack(target) { bias } else { 1.0 };
+    let sum: f64 = nominal.iter().map(|(action, p)| p * weight(*action)).sum();
+
+    nominal.into_iter().map(|(action, p)| (action, p, p * weight(action) / sum)).collect()
+}
+
+/// Estimates the probability that `event` happens to player `target` within `settings.rounds` rounds, every player
+/// following `policy`, except for the `constraints` during the first round
+pub fn importance_sampling<P: Policy>(
+    players: &[Player],
+    previous_actions: &[Vec<Action>],
+    constraints: &[(usize, Action)],
+    target: usize,
+    event: RareEvent,
+    policy: &P,
+    settings: &ImportanceSettings,
+) -> RareEstimate {
+    assert!(previous_actions.len() == players.len());
+
+    let mut rng = rand::thread_rng();
+    let mut res = RareEstimate {
+        samples: 0,
+        hits: 0,
+        sum: 0.0,
+        sum_square: 0.0,
+        sum_weights: 0.0,
+        sum_square_weights: 0.0,
+    };
+
+    for _n in 0..settings.samples {
+        let mut state = players.to_vec();
+        let mut history = previous_actions.to_vec();
+        let mut weight = 1.0;
+        let mut happened = false;
+
+        for round in 0..settings.rounds {
+            if state.iter().any(|p| p.won()) {
+                break;
+            }
+
+            let mut actions = Vec::with_capacity(state.len());
+            for (n, previous) in history.iter().enumerate() {
+                let constraint = constraints.iter().find(|(index, _)| round == 0 && *index == n);
+
+                let action = match constraint {
+                    Some((_, action)) => *action,
+                    None => {
+                        let distribution = biased_distribution(
+                            &state, n, target, settings.round_offset + round, previous, policy, settings.bias
+                        );
+                        let (action, p, q) = distribution.choose_weighted(&mut rng, |(_, _, q)| *q)
+                            .copied()
+                            .unwrap_or((distribution.first().map(|(action, _, _)| *action).unwrap_or(Action::None), 1.0, 1.0));
+                        weight *= p / q;
+                        action
+                    }
+                };
+
+                actions.push(action);
+            }
+
+            state = update(state, &actions);
+            for (previous, action) in history.iter_mut().zip(actions) {
+                previous.push(action);
+            }
+
+            if event.happened(&players[target], &state[target]) {
+                happened = true;
+                break;
+            }
+        }
+
+        res.samples += 1;
+        res.sum_weights += weight;
+        res.sum_square_weights += weight * weight;
+        if happened {
+            res.hits += 1;
+            res.sum += weight;
+            res.sum_square += weight * weight;
+        }
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::inference::Bot;
+    use super::super::exact::*;
+
+    #[test]
+    fn test_importance_sampling() {
+        let players = vec![
+            Player::with_values(0, 0, 1, 1, 0),
+            Player::with_values(1, 2, 1, 1, 0),
+            Player::with_values(1, 2, 1, 1, 0),
+            Player::with_values(1, 2, 1, 1, 0),
+        ];
+        let previous_actions = vec![vec![]; 4];
+        let constraints = [(0, Action::Skip)];
+
+        let distributions = action_distributions(&players, &previous_actions, 0, &Bot::Uniform);
+        let exact = exact_next_turn(&players, 0, Action::Skip, &distributions, &ExactSettings { threshold: 0.0, ..Default::default() }).unwrap().sieged;
+
+        let settings = ImportanceSettings {
+            samples: 20000,
+            rounds: 1,
+            ..Default::default()
+        };
+        let estimate = importance_sampling(&players, &previous_actions, &constraints, 0, RareEvent::Sieged, &Bot::Uniform, &settings);
+
+        assert!((estimate.probability() - exact).abs() < 2.0 * estimate.confidence());
+        assert!(estimate.hits > estimate.samples / 4);
+        assert!(estimate.effective_samples() < estimate.samples as f64);
+
+        assert_eq!("obelisks:2".parse::<RareEvent>(), Ok(RareEvent::LostObelisks(2)));
+    }
+}
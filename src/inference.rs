@@ -0,0 +1,268 @@
+// Bayesian inference of the policy that each player follows, given the actions they took so far.
+// A set of candidate policies (genomes, the model at different temperatures, hand-written bots) is scored against the
+// history of every player: the likelihood of a policy is the product of the probabilities that it gives to the actions
+// that the player took, in the states that the player was in. These states are reconstructed by replaying the game.
+// The resulting posterior can then be sampled from in monte carlo, with `PosteriorAi`.
+use super::*;
+use super::monte_carlo::RolloutAi;
+use rand::prelude::*;
+use rand::rngs::ThreadRng;
+
+/// A policy whose action probabilities are known, so that the likelihood of observed actions can be computed
+pub trait Policy {
+    /// Returns the probability that player `index` takes `action` during `round`, given the state of the game and their previous actions
+    fn probability(&self, players: &[Player], index: usize, round: usize, previous_actions: &[Action], action: Action) -> f64;
+
+    /// Draws an action for player `index`
+    fn sample(&self, players: &[Player], index: usize, round: usize, previous_actions: &[Action], rng: &mut ThreadRng) -> Action;
+
+    /// Returns the probability of each possible action of player `index`
+    fn distribution(&self, players: &[Player], index: usize, round: usize, previous_actions: &[Action]) -> Vec<(Action, f64)> {
+        players[index]
+            .possible_actions(players.iter().enumerate().filter(|(n, _p)| *n != index))
+            .into_iter()
+            .map(|action| (action, self.probability(players, index, round, previous_actions, action)))
+            .collect()
+    }
+}
+
+impl<P: Policy + ?Sized> Policy for &P {
+    #[inline]
+    fn probability(&self, players: &[Player], index: usize, round: usize, previous_actions: &[Action], action: Action) -> f64 {
+        (**self).probability(players, index, round, previous_actions, action)
+    }
+
+    #[inline]
+    fn sample(&self, players: &[Player], index: usize, round: usize, previous_actions: &[Action], rng: &mut ThreadRng) -> Action {
+        (**self).sample(players, index, round, previous_actions, rng)
+    }
+
+    #[inline]
+    fn distribution(&self, players: &[Player], index: usize, round: usize, previous_actions: &[Action]) -> Vec<(Action, f64)> {
+        (**self).distribution(players, index, round, previous_actions)
+    }
+}
+
+impl<P: Policy + ?Sized> Policy for Box<P> {
+    #[inline]
+    fn probability(&self, players: &[Player], index: usize, round: usize, previous_actions: &[Action], action: Action) -> f64 {
+        (**self).probability(players, index, round, previous_actions, action)
+    }
+
+    #[inline]
+    fn sample(&self, players: &[Player], index: usize, round: usize, previous_actions: &[Action], rng: &mut ThreadRng) -> Action {
+        (**self).sample(players, index, round, previous_actions, rng)
+    }
+
+    #[inline]
+    fn distribution(&self, players: &[Player], index: usize, round: usize, previous_actions: &[Action]) -> Vec<(Action, f64)> {
+        (**self).distribution(players, index, round, previous_actions)
+    }
+}
+
+/// Simple hand-written policies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Bot {
+    /// Picks any of the possible actions, uniformly
+    Uniform,
+    /// Never attacks, and picks any of the other actions uniformly
+    Builder,
+    /// Attacks a random player half of the time, if it can
+    Aggressive,
+}
+
+impl Bot {
+    // Returns the possible actions, along with their weight
+    fn weights(&self, players: &[Player], index: usize) -> Vec<(Action, f64)> {
+        let actions = players[index].possible_actions(players.iter().enumerate().filter(|(n, _p)| *n != index));
+        let attacks = actions.iter().filter(|a| matches!(a, Action::Attack(_))).count();
+        let others = actions.len() - attacks;
+
+        actions.into_iter().map(|action| {
+            let is_attack = matches!(action, Action::Attack(_));
+            let weight = match self {
+                Bot::Uniform => 1.0,
+                Bot::Builder => if is_attack { 0.0 } else { 1.0 },
+                Bot::Aggressive if attacks == 0 => 1.0,
+                Bot::Aggressive => if is_attack { 0.5 / attacks as f64 } else { 0.5 / others as f64 },
+            };
+            (action, weight)
+        }).collect()
+    }
+}
+
+impl Policy for Bot {
+    fn probability(&self, players: &[Player], index: usize, _round: usize, _previous_actions: &[Action], action: Action) -> f64 {
+        let weights = self.weights(players, index);
+        let sum: f64 = weights.iter().map(|(_, w)| w).sum();
+
+        weights.iter().find(|(a, _)| *a == action).map(|(_, w)| w / sum).unwrap_or(0.0)
+    }
+
+    fn sample(&self, players: &[Player], index: usize, _round: usize, _previous_actions: &[Action], rng: &mut ThreadRng) -> Action {
+        let weights = self.weights(players, index);
+
+        weights.choose_weighted(rng, |(_, w)| *w).map(|(a, _)| *a).unwrap_or(Action::None)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InferenceSettings {
+    /// Probability that a player deviates from their policy and plays a uniformly random action; this keeps a single
+    /// unexpected action from ruling out a policy, which matters for deterministic policies like the genomes
+    pub epsilon: f64,
+    /// Prior probability of each policy; if empty, every policy is equally likely
+    pub prior: Vec<f64>,
+}
+
+impl Default for InferenceSettings {
+    fn default() -> Self {
+        Self {
+            epsilon: 0.05,
+            prior: Vec::new(),
+        }
+    }
+}
+
+/// Reconstructs the state of the game before each round, by replaying `previous_actions` from the start of the game.
+/// Every player starts with `Player::new()`, except for targets, which start with their current values.
+/// Actions that aren't possible in the reconstructed state (if the history is incomplete) are replaced with `Skip`.
+/// Returns one state per round in `previous_actions`.
+pub fn replay_history(players: &[Player], previous_actions: &[Vec<Action>]) -> Vec<Vec<Player>> {
+    let rounds = previous_actions.iter().map(|actions| actions.len()).min().unwrap_or(0);
+
+    let mut state = players.iter().map(|p| if p.is_target { p.clone() } else { Player::new() }).collect::<Vec<_>>();
+    let mut res = Vec::with_capacity(rounds);
+
+    for round in 0..rounds {
+        let actions = previous_actions.iter().enumerate().map(|(index, actions)| {
+            let possible = state[index].possible_actions(state.iter().enumerate().filter(|(n, _p)| *n != index));
+            if possible.contains(&actions[round]) { actions[round] } else { Action::Skip }
+        }).collect::<Vec<_>>();
+        res.push(state.clone());
+        state = update(state, &actions);
+    }
+
+    res
+}
+
+/// Computes, for every player, the posterior probability of each of the `policies`, given their `previous_actions`.
+/// Returns one vector of probabilities per player, in the same order as `policies`.
+pub fn infer_policies<P: Policy>(
+    players: &[Player],
+    previous_actions: &[Vec<Action>],
+    policies: &[P],
+    settings: &InferenceSettings,
+) -> Vec<Vec<f64>> {
+    assert!(previous_actions.len() == players.len());
+    assert!(settings.prior.is_empty() || settings.prior.len() == policies.len());
+
+    let history = replay_history(players, previous_actions);
+
+    (0..players.len()).map(|index| {
+        let mut log_likelihoods = policies.iter().enumerate().map(|(n, _)| {
+            if settings.prior.is_empty() { 0.0 } else { settings.prior[n].ln() }
+        }).collect::<Vec<_>>();
+
+        for (round, state) in history.iter().enumerate() {
+            if !state[index].can_play() {
+                continue;
+            }
+
+            let action = previous_actions[index][round];
+            let previous = &previous_actions[index][0..round];
+            let n_actions = state[index].possible_actions(state.iter().enumerate().filter(|(n, _p)| *n != index)).len();
+
+            for (log_likelihood, policy) in log_likelihoods.iter_mut().zip(policies.iter()) {
+                let probability = policy.probability(state, index, round, previous, action);
+                *log_likelihood += ((1.0 - settings.epsilon) * probability + settings.epsilon / n_actions as f64).ln();
+            }
+        }
+
+        normalize_log(log_likelihoods)
+    }).collect()
+}
+
+// Turns log-probabilities into probabilities that sum up to 1
+fn normalize_log(log_probabilities: Vec<f64>) -> Vec<f64> {
+    let max = log_probabilities.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if max == f64::NEG_INFINITY {
+        return vec![1.0 / log_probabilities.len() as f64; log_probabilities.len()];
+    }
+
+    let res = log_probabilities.into_iter().map(|x| (x - max).exp()).collect::<Vec<_>>();
+    let sum: f64 = res.iter().sum();
+
+    res.into_iter().map(|x| x / sum).collect()
+}
+
+/// Plays every player according to a policy drawn from their posterior, at the start of each simulated game
+#[derive(Debug)]
+pub struct PosteriorAi<'a, P: Policy> {
+    pub policies: &'a [P],
+    /// The probability of each policy, for each player (see `infer_policies`)
+    pub posterior: &'a [Vec<f64>],
+}
+
+impl<'a, P: Policy> Clone for PosteriorAi<'a, P> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, P: Policy> Copy for PosteriorAi<'a, P> {}
+
+impl<'a, P: Policy> RolloutAi for PosteriorAi<'a, P> {
+    /// The index of the policy followed by each player
+    type Game = Vec<usize>;
+
+    fn new_game(&self, players: &[Player], rng: &mut ThreadRng) -> Vec<usize> {
+        (0..players.len()).map(|index| {
+            let probabilities = &self.posterior[index];
+            (0..probabilities.len()).collect::<Vec<_>>().choose_weighted(rng, |&n| probabilities[n]).copied().unwrap_or(0)
+        }).collect()
+    }
+
+    #[inline]
+    fn action(
+        &self,
+        game: &Vec<usize>,
+        players: &[Player],
+        index: usize,
+        round: usize,
+        previous_actions: &[Action],
+        rng: &mut ThreadRng,
+    ) -> Action {
+        self.policies[game[index]].sample(players, index, round, previous_actions, rng)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_infer_policies() {
+        let players = vec![Player::new(); 3];
+        let policies = [Bot::Builder, Bot::Aggressive];
+
+        let previous_actions = vec![
+            vec![Action::Recruit, Action::Attack(2), Action::Recruit, Action::Attack(1)],
+            vec![Action::Wall, Action::Obelisk, Action::Wall, Action::Barracks],
+            vec![Action::Recruit, Action::Skip, Action::Wall, Action::Recruit],
+        ];
+
+        let history = replay_history(&players, &previous_actions);
+        assert_eq!(history.len(), 4);
+        assert_eq!(history[0], players);
+        assert_eq!(history[1][1].walls, 2);
+
+        let posterior = infer_policies(&players, &previous_actions, &policies, &InferenceSettings::default());
+
+        assert!(posterior[0][1] > 0.9);
+        assert!(posterior[1][0] > 0.7);
+        for probabilities in posterior.iter() {
+            assert!((probabilities.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+// Parsing of the `players.json`, `constraints.json` and `objective.json` files used by the command-line tools
+use super::*;
+use super::objective::ObjectiveConfig;
+use super::uncertain::{Stat, UncertainPlayer};
+use super::plan::{RoundConstraint, PlannedAction};
+use super::model::ModelPolicySettings;
+use std::fs::read_to_string;
+use std::path::Path;
+
+/// Reads a `players.json` file, containing the names, the values (`[walls, soldiers, barracks, obelisks, defense]`)
+/// and the previous actions (one array per round) of every player.
+/// If a player's values contain a 6th element, then that player is a target.
+///
+/// Returns the names, the players and the previous actions of each player.
+/// Uncertain values (see `read_uncertain_players`) are replaced with their expected value.
+pub fn read_players(path: impl AsRef<Path>) -> (Vec<String>, Vec<Player>, Vec<Vec<Action>>) {
+    let (names, players, previous_actions) = read_uncertain_players(path);
+
+    (names, players.iter().map(UncertainPlayer::expected).collect(), previous_actions)
+}
+
+/// Reads a `players.json` file, like `read_players`, except that each value can also be a range (`{"min": 3, "max": 7}`)
+/// or a distribution (`[[3, 1.0], [5, 2.0]]`), for the values that aren't exactly known.
+pub fn read_uncertain_players(path: impl AsRef<Path>) -> (Vec<String>, Vec<UncertainPlayer>, Vec<Vec<Action>>) {
+    let path = path.as_ref();
+    let raw = read_to_string(path).unwrap_or_else(|_| panic!("Couldn't open {}", path.display()));
+
+    let (names, players, actions): (Vec<String>, Vec<Vec<Stat>>, Vec<Vec<Action>>) =
+        serde_json::from_str(&raw).unwrap_or_else(|_| panic!("Couldn't parse {}", path.display()));
+
+    let players = players.into_iter().map(|mut stats| {
+        let is_target = stats.len() >= 6;
+        let mut stats = stats.drain(0..5);
+
+        UncertainPlayer {
+            walls: stats.next().unwrap(),
+            soldiers: stats.next().unwrap(),
+            barracks: stats.next().unwrap(),
+            obelisks: stats.next().unwrap(),
+            defense: stats.next().unwrap(),
+            is_target,
+        }
+    }).collect::<Vec<_>>();
+
+    let mut previous_actions = Vec::with_capacity(players.len());
+    for n in 0..players.len() {
+        let mut tmp = Vec::with_capacity(actions.len());
+        for previous_actions in actions.iter() {
+            tmp.push(previous_actions[n]);
+        }
+        previous_actions.push(tmp);
+    }
+
+    (names, players, previous_actions)
+}
+
+/// Reads a `constraints.json` file, containing a list of `[player, action]` pairs, for the first simulated round,
+/// and of `{"round": round, "player": player, "action": action}` objects, for any round (see `RoundConstraint`).
+///
+/// Returns the constraints of the first round, as `(player, action)` pairs, and the other constraints.
+pub fn read_constraints(path: impl AsRef<Path>) -> (Vec<(usize, Action)>, Vec<RoundConstraint>) {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Entry {
+        Pair(usize, Action),
+        Round(RoundConstraint),
+    }
+
+    let path = path.as_ref();
+    let raw = read_to_string(path).unwrap_or_else(|_| panic!("Couldn't open {}", path.display()));
+
+    let entries: Vec<Entry> = serde_json::from_str(&raw).unwrap_or_else(|_| panic!("Couldn't parse {}", path.display()));
+
+    let mut constraints = Vec::new();
+    let mut round_constraints = Vec::new();
+    for entry in entries {
+        match entry {
+            Entry::Pair(player, action) => constraints.push((player, action)),
+            Entry::Round(RoundConstraint { round: 0, player, action: PlannedAction::Fixed(action) }) => constraints.push((player, action)),
+            Entry::Round(constraint) => round_constraints.push(constraint),
+        }
+    }
+
+    (constraints, round_constraints)
+}
+
+/// Reads an `objective.json` file, containing an `ObjectiveConfig`; if the file doesn't exist, the heuristic objective is used
+pub fn read_objective(path: impl AsRef<Path>) -> ObjectiveConfig {
+    if path.as_ref().exists() {
+        ObjectiveConfig::load(path)
+    } else {
+        ObjectiveConfig::default()
+    }
+}
+
+/// Reads a `policies.json` file, containing the `ModelPolicySettings` of every player, in the order of `players.json`
+/// (for instance `[{"temperature": 0.5}, {"argmax": true, "epsilon": 0.1}, {"top_k": 3}]`); `{}` stands for the model as is.
+pub fn read_policies(path: impl AsRef<Path>) -> Vec<ModelPolicySettings> {
+    let path = path.as_ref();
+    let raw = read_to_string(path).unwrap_or_else(|_| panic!("Couldn't open {}", path.display()));
+
+    serde_json::from_str(&raw).unwrap_or_else(|err| panic!("Couldn't parse {}: {}", path.display(), err))
+}
@@ -1,12 +1,28 @@
 #![feature(trait_alias)]
-#![feature(type_alias_impl_trait)]
 
 use serde::{Deserialize, Serialize};
 
 pub mod genetic_basic;
 pub mod monte_carlo;
+pub mod outcome;
+pub mod objective;
 pub mod generate_training;
 pub mod model;
+pub mod input;
+pub mod coalition;
+pub mod inference;
+pub mod uncertain;
+pub mod plan;
+pub mod exact;
+pub mod importance;
+pub mod cache;
+pub mod session;
+pub mod endgame;
+pub mod mlp;
+pub mod train;
+pub mod prediction_cache;
+pub mod ensemble;
+pub mod report;
 
 pub const MAX_WALLS: u8 = 10;
 pub const MAX_BARRACKS: u8 = 10;
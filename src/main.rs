@@ -3,7 +3,7 @@ use obelisk::*;
 use obelisk::model::*;
 
 fn main() {
-    let model = load_model("target/model.onnx").unwrap();
+    let model = load_network("target/model.onnx", ModelBackend::from_env()).unwrap();
 
     let players = vec![
         Player::with_values(2, 1, 3, 2, 0),
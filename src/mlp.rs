@@ -0,0 +1,442 @@
+// Native evaluation of the policy network, without tract.
+// The network trained by `train.py` is a small stack of dense layers, so running it through tract costs more in overhead
+// than in arithmetic. This evaluator reads the weights of the dense layers, either from the initializers of the ONNX file
+// or from a JSON weights file exported by `convert.py`, and computes them with plain matrix-vector products.
+// The weights of every layer are stored row-major, one row per output, so that each dot product reads contiguous memory.
+use super::*;
+use super::model::{encoder_version, ModelPrec};
+use std::collections::HashMap;
+use std::path::Path;
+use tract_onnx::pb;
+use tract_onnx::prelude::*;
+use tract_onnx::prelude::tract_data::anyhow::{bail, Context};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Activation {
+    Linear,
+    Relu,
+    Sigmoid,
+    Softmax,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Dense {
+    pub inputs: usize,
+    pub outputs: usize,
+    /// `weights[o * inputs + i]` is the weight from input `i` to output `o`
+    pub weights: Vec<ModelPrec>,
+    pub bias: Vec<ModelPrec>,
+    pub activation: Activation,
+}
+
+impl Dense {
+    pub(crate) fn forward(&self, input: &[ModelPrec], output: &mut [ModelPrec]) {
+        debug_assert!(input.len() == self.inputs && output.len() == self.outputs);
+
+        for ((out, row), bias) in output.iter_mut().zip(self.weights.chunks_exact(self.inputs)).zip(self.bias.iter()) {
+            *out = row.iter().zip(input.iter()).map(|(w, x)| w * x).sum::<ModelPrec>() + bias;
+        }
+
+        match self.activation {
+            Activation::Linear => {}
+            Activation::Relu => output.iter_mut().for_each(|x| *x = x.max(0.0)),
+            Activation::Sigmoid => output.iter_mut().for_each(|x| *x = 1.0 / (1.0 + (-*x).exp())),
+            Activation::Softmax => {
+                let max = output.iter().copied().fold(ModelPrec::NEG_INFINITY, ModelPrec::max);
+                let mut sum = 0.0;
+                for x in output.iter_mut() {
+                    *x = (*x - max).exp();
+                    sum += *x;
+                }
+                output.iter_mut().for_each(|x| *x /= sum);
+            }
+        }
+    }
+}
+
+/// The second output of a network, which predicts the loss of the player (see `ModelValue`)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValueHead {
+    /// The value layers take the output of the first `from` layers of the policy
+    pub from: usize,
+    pub layers: Vec<Dense>,
+}
+
+/// A stack of dense layers, with an optional value head branching from it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mlp {
+    pub layers: Vec<Dense>,
+    #[serde(default)]
+    pub value: Option<ValueHead>,
+    /// The version of the encoder that the network was trained with (see `Encoder`), if it is known
+    #[serde(default)]
+    pub encoder_version: Option<u32>,
+}
+
+impl Mlp {
+    pub fn inputs(&self) -> usize {
+        self.layers.first().map(|layer| layer.inputs).unwrap_or(0)
+    }
+
+    pub fn outputs(&self) -> usize {
+        self.layers.last().map(|layer| layer.outputs).unwrap_or(0)
+    }
+
+    /// Loads the weights from a JSON weights file (if the extension is `.json`) or from the initializers of an ONNX file
+    pub fn load(path: impl AsRef<Path>) -> TractResult<Self> {
+        let path = path.as_ref();
+
+        if path.extension().map(|ext| ext == "json").unwrap_or(false) {
+            let raw = std::fs::read_to_string(path)?;
+            Ok(serde_json::from_str(&raw)?)
+        } else {
+            Self::from_onnx(&tract_onnx::onnx().proto_model_for_path(path)?)
+        }
+    }
+
+    /// Reads the dense layers of an ONNX graph, made of `MatMul` or `Gemm` nodes, followed by an optional `Add`
+    /// of the bias and an optional activation. The first output of the graph is the policy; if there is a second output,
+    /// it is the value head, which must branch from the layers of the policy.
+    pub fn from_onnx(proto: &pb::ModelProto) -> TractResult<Self> {
+        let graph = proto.graph.as_ref().context("The ONNX model has no graph")?;
+
+        let mut initializers: HashMap<&str, Tensor> = HashMap::new();
+        for initializer in graph.initializer.iter() {
+            initializers.insert(&initializer.name, Tensor::try_from(initializer)?);
+        }
+        for node in graph.node.iter().filter(|node| node.op_type == "Constant") {
+            if let (Some(output), Some(value)) = (node.output.first(), node.attribute.iter().find_map(|a| a.t.as_ref())) {
+                initializers.insert(output, Tensor::try_from(value)?);
+            }
+        }
+
+        let constant = |name: &str| -> Option<Vec<ModelPrec>> {
+            let tensor = initializers.get(name)?.cast_to::<ModelPrec>().ok()?;
+            Some(tensor.as_slice::<ModelPrec>().ok()?.to_vec())
+        };
+        let shape = |name: &str| initializers.get(name).map(|tensor| tensor.shape().to_vec());
+
+        // Every dense layer, along with the layer that it takes its input from (`None` for the input of the model)
+        let mut layers: Vec<(Option<usize>, Dense)> = Vec::new();
+        // The layer that produces each tensor
+        let mut sources: HashMap<&str, Option<usize>> = graph.input.iter()
+            .filter(|input| !initializers.contains_key(input.name.as_str()))
+            .map(|input| (input.name.as_str(), None))
+            .collect();
+
+        for node in graph.node.iter() {
+            let source = node.input.iter().find_map(|name| sources.get(name.as_str()).copied());
+            let output = node.output.first().map(|name| name.as_str()).unwrap_or("");
+
+            match node.op_type.as_str() {
+                "MatMul" | "Gemm" => {
+                    let parent = source.context("MatMul without input")?;
+                    let name = node.input.get(1).context("MatMul without weights")?;
+                    let (weights, shape) = constant(name).zip(shape(name)).context("The weights aren't constant")?;
+                    if shape.len() != 2 {
+                        bail!("Expected 2D weights, got {:?}", shape);
+                    }
+
+                    let transposed = node.attribute.iter().any(|a| a.name == "transB" && a.i != 0);
+                    let (inputs, outputs) = if transposed { (shape[1], shape[0]) } else { (shape[0], shape[1]) };
+
+                    // Stored as [inputs, outputs] in ONNX, unless transB is set
+                    let weights = if transposed {
+                        weights
+                    } else {
+                        (0..outputs).flat_map(|o| (0..inputs).map(move |i| (o, i))).map(|(o, i)| weights[i * outputs + o]).collect()
+                    };
+
+                    let bias = match node.input.get(2) {
+                        Some(name) => constant(name).context("The bias isn't constant")?,
+                        None => vec![0.0; outputs],
+                    };
+
+                    layers.push((parent, Dense {
+                        inputs,
+                        outputs,
+                        weights,
+                        bias,
+                        activation: Activation::Linear,
+                    }));
+                    sources.insert(output, Some(layers.len() - 1));
+                }
+                "Add" => {
+                    let id = source.flatten().context("Add before any dense layer")?;
+                    let layer = &mut layers[id].1;
+                    let bias = node.input.iter().find_map(|name| constant(name)).context("The bias isn't constant")?;
+                    if bias.len() != layer.outputs {
+                        bail!("Expected a bias of size {}, got {}", layer.outputs, bias.len());
+                    }
+                    layer.bias.iter_mut().zip(bias).for_each(|(b, x)| *b += x);
+                    sources.insert(output, Some(id));
+                }
+                "Relu" | "Sigmoid" | "Softmax" => {
+                    let id = source.flatten().with_context(|| format!("{} before any dense layer", node.op_type))?;
+                    layers[id].1.activation = match node.op_type.as_str() {
+                        "Relu" => Activation::Relu,
+                        "Sigmoid" => Activation::Sigmoid,
+                        _ => Activation::Softmax,
+                    };
+                    sources.insert(output, Some(id));
+                }
+                "Identity" | "Dropout" | "Flatten" | "Reshape" | "Cast" => {
+                    if let Some(source) = source {
+                        sources.insert(output, source);
+                    }
+                }
+                "Constant" => {}
+                op => bail!("Unsupported operation in the native model: {}", op),
+            }
+        }
+
+        // Returns the layers that lead to the output `n` of the graph
+        let chain = |n: usize| -> TractResult<Vec<usize>> {
+            let output = graph.output.get(n).context("Missing output")?;
+            let mut current = *sources.get(output.name.as_str()).context("The output isn't computed by dense layers")?;
+            let mut res = Vec::new();
+            while let Some(id) = current {
+                res.push(id);
+                current = layers[id].0;
+            }
+            res.reverse();
+            Ok(res)
+        };
+
+        let policy = chain(0)?;
+        if policy.is_empty() {
+            bail!("The ONNX model has no dense layer");
+        }
+
+        let value = if graph.output.len() > 1 {
+            let value = chain(1)?;
+            let from = policy.iter().zip(value.iter()).take_while(|(a, b)| a == b).count();
+            if from == value.len() {
+                bail!("The value head has no layer of its own");
+            }
+            Some(ValueHead {
+                from,
+                layers: value[from..].iter().map(|id| layers[*id].1.clone()).collect(),
+            })
+        } else {
+            None
+        };
+
+        Ok(Self {
+            layers: policy.iter().map(|id| layers[*id].1.clone()).collect(),
+            value,
+            encoder_version: encoder_version(proto)?,
+        })
+    }
+
+    /// Runs the network on `rows` inputs, stored one after the other in `input`; returns the outputs, in the same layout
+    pub fn forward(&self, input: &[ModelPrec], rows: usize) -> Vec<ModelPrec> {
+        assert!(input.len() == rows * self.inputs());
+
+        forward_layers(self.layers.iter(), input, self.inputs())
+    }
+
+    /// Runs the value head on `rows` inputs; returns one value per input, or `None` if the network has no value head
+    pub fn forward_value(&self, input: &[ModelPrec], rows: usize) -> Option<Vec<ModelPrec>> {
+        assert!(input.len() == rows * self.inputs());
+        let value = self.value.as_ref()?;
+
+        Some(forward_layers(self.layers[0..value.from].iter().chain(value.layers.iter()), input, self.inputs()))
+    }
+}
+
+// Runs `layers` one after the other on every row of `input`
+fn forward_layers<'l>(layers: impl Iterator<Item = &'l Dense> + Clone, input: &[ModelPrec], inputs: usize) -> Vec<ModelPrec> {
+    let width = layers.clone().map(|layer| layer.outputs).max().unwrap_or(0);
+    let outputs = layers.clone().last().map(|layer| layer.outputs).unwrap_or(inputs);
+    let mut a = vec![0.0; width];
+    let mut b = vec![0.0; width];
+    let mut res = Vec::with_capacity(input.len() / inputs * outputs);
+
+    for row in input.chunks_exact(inputs) {
+        let mut current: &[ModelPrec] = row;
+        for layer in layers.clone() {
+            layer.forward(current, &mut a[0..layer.outputs]);
+            std::mem::swap(&mut a, &mut b);
+            current = &b[0..layer.outputs];
+        }
+        res.extend_from_slice(current);
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::model::*;
+    use super::super::monte_carlo::*;
+    use super::super::objective::*;
+    use rand::Rng;
+
+    // Adds a dense layer named `name` to the graph, reading `input` and writing `output`
+    fn add_dense(graph: &mut pb::GraphProto, name: &str, input: &str, output: &str, size: (usize, usize), activation: &str, rng: &mut impl Rng) {
+        let (inputs, outputs) = size;
+        graph.initializer.push(pb::TensorProto {
+            name: format!("{}_w", name),
+            dims: vec![inputs as i64, outputs as i64],
+            data_type: 1,
+            float_data: (0..inputs * outputs).map(|_| rng.gen_range(-0.3..0.3)).collect(),
+            ..Default::default()
+        });
+        graph.initializer.push(pb::TensorProto {
+            name: format!("{}_b", name),
+            dims: vec![outputs as i64],
+            data_type: 1,
+            float_data: (0..outputs).map(|_| rng.gen_range(-0.1..0.1)).collect(),
+            ..Default::default()
+        });
+
+        for (op_type, input, node_output) in [
+            ("MatMul", vec![input.to_string(), format!("{}_w", name)], format!("{}_m", name)),
+            ("Add", vec![format!("{}_m", name), format!("{}_b", name)], format!("{}_z", name)),
+            (activation, vec![format!("{}_z", name)], output.to_string()),
+        ] {
+            graph.node.push(pb::NodeProto {
+                op_type: op_type.to_string(),
+                input,
+                output: vec![node_output],
+                ..Default::default()
+            });
+        }
+    }
+
+    // Builds a network with the layers `sizes`; with `value`, a value head with a hidden layer of that size branches
+    // from the last hidden layer
+    fn dense_graph(sizes: &[usize], value: Option<usize>, rng: &mut impl Rng) -> pb::ModelProto {
+        let mut graph = pb::GraphProto::default();
+        let tensor_type = |name: &str| pb::ValueInfoProto {
+            name: name.to_string(),
+            r#type: Some(pb::TypeProto {
+                value: Some(pb::type_proto::Value::TensorType(pb::type_proto::Tensor {
+                    elem_type: 1,
+                    shape: None,
+                })),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        graph.input.push(tensor_type("input"));
+        graph.output.push(tensor_type("output"));
+
+        let mut last = String::from("input");
+        for (n, window) in sizes.windows(2).enumerate() {
+            let is_last = n + 2 == sizes.len();
+            let output = if is_last { String::from("output") } else { format!("a{}", n) };
+
+            if is_last {
+                if let Some(hidden) = value {
+                    graph.output.push(tensor_type("value"));
+                    add_dense(&mut graph, "v0", &last, "v0_a", (window[0], hidden), "Relu", rng);
+                    add_dense(&mut graph, "v1", "v0_a", "value", (hidden, 1), "Sigmoid", rng);
+                }
+            }
+
+            add_dense(&mut graph, &format!("l{}", n), &last, &output, (window[0], window[1]), if is_last { "Softmax" } else { "Relu" }, rng);
+            last = output;
+        }
+
+        pb::ModelProto {
+            ir_version: 7,
+            opset_import: vec![pb::OperatorSetIdProto { domain: String::new(), version: 13 }],
+            metadata_props: vec![pb::StringStringEntryProto {
+                key: ENCODER_VERSION_KEY.to_string(),
+                value: ENCODER_VERSION.to_string(),
+            }],
+            graph: Some(graph),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_native_model() {
+        let mut rng = rand::thread_rng();
+        let proto = dense_graph(&[INPUT_SIZE, 24, MAX_ACTIONS], None, &mut rng);
+
+        let tract = Network::Tract(Box::new(model_for_proto(&proto).unwrap()));
+        let native = Network::Native(Mlp::from_onnx(&proto).unwrap());
+        assert_eq!(native_layers(&native), 2);
+        assert!(matches!(&native, Network::Native(mlp) if mlp.encoder_version == Some(ENCODER_VERSION)));
+
+        let players = vec![
+            Player::with_values(2, 1, 4, 2, 0),
+            Player::with_values(4, 3, 2, 2, 1),
+            Player::with_values(3, 0, 2, 3, 2),
+        ];
+        let previous_actions = vec![vec![Action::Wall, Action::Attack(1)], vec![Action::Recruit, Action::Skip], vec![Action::Defend, Action::Obelisk]];
+
+        let tract = ModelPolicy::new(&tract).predict_all(&players, &previous_actions);
+        let native = ModelPolicy::new(&native).predict_all(&players, &previous_actions);
+
+        for (a, b) in tract.iter().zip(native.iter()) {
+            assert_eq!(a.len(), b.len());
+            for ((action_a, p_a), (action_b, p_b)) in a.iter().zip(b.iter()) {
+                assert!((p_a - p_b).abs() < 1e-5, "{:?} {} != {:?} {}", action_a, p_a, action_b, p_b);
+            }
+        }
+
+        let mlp = Mlp::from_onnx(&proto).unwrap();
+        let json = serde_json::to_string(&mlp).unwrap();
+        assert_eq!(serde_json::from_str::<Mlp>(&json).unwrap(), mlp);
+    }
+
+    #[test]
+    fn test_value_head() {
+        let mut rng = rand::thread_rng();
+        let proto = dense_graph(&[INPUT_SIZE, 24, 16, MAX_ACTIONS], Some(8), &mut rng);
+
+        let mlp = Mlp::from_onnx(&proto).unwrap();
+        assert_eq!(mlp.value.as_ref().map(|value| (value.from, value.layers.len())), Some((2, 2)));
+
+        let tract = Network::Tract(Box::new(model_for_proto(&proto).unwrap()));
+        let native = Network::Native(mlp);
+        assert!(tract.has_value() && native.has_value());
+
+        let players = vec![Player::with_values(2, 1, 4, 2, 0), Player::with_values(4, 3, 2, 2, 1)];
+        let queries = (0..2).map(|index| ModelQuery {
+            players: &players,
+            index,
+            previous_actions: &[Action::Wall, Action::Recruit],
+            actions: &[],
+        }).collect::<Vec<_>>();
+
+        let values = run_value_batch(&tract, &queries).unwrap();
+        for (a, b) in values.iter().zip(run_value_batch(&native, &queries).unwrap()) {
+            assert!(*a > 0.0 && *a < 1.0);
+            assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+        }
+
+        // Truncated rollouts are bootstrapped from the value head, given the history of the player
+        let skip = |_p: &[Player], _index: usize, _round: usize, _previous: &[Action], _rng: &mut rand::rngs::ThreadRng| Action::Skip;
+        let settings = MonteCarloSettings {
+            samples: 3,
+            horizon: Horizon::Rounds(2),
+            ..Default::default()
+        };
+        let loss = Bootstrapped { objective: Win, value: ModelValue::new(&native) };
+        let previous_actions = vec![vec![Action::Wall], vec![Action::Recruit]];
+        let outcome = monte_carlo(&players, &previous_actions, &[], &[1], &settings, Rollout { first_ai: skip, ai: skip, compute_loss: loss }).pop().unwrap();
+
+        let final_state = update(update(players.clone(), &[Action::Skip; 2]), &[Action::Skip; 2]);
+        let history = [Action::Recruit, Action::Skip, Action::Skip];
+        let expected = run_value_batch(&native, &[ModelQuery {
+            players: &final_state,
+            index: 1,
+            previous_actions: &history,
+            actions: &[],
+        }]).unwrap()[0];
+        assert!((outcome.mean() - expected as f64).abs() < 1e-6);
+    }
+
+    fn native_layers(network: &Network) -> usize {
+        match network {
+            Network::Native(mlp) => mlp.layers.len(),
+            _ => 0,
+        }
+    }
+}
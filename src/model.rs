@@ -1,16 +1,30 @@
 use std::path::Path;
-use std::hash::Hash;
-use std::fmt::{Display, Debug};
-use std::borrow::Borrow;
 use tract_onnx::prelude::*;
+use tract_onnx::prelude::tract_data::internal::DimLike;
 use super::*;
-use super::monte_carlo::AiFn;
+use super::monte_carlo::RolloutAi;
+use super::inference::Policy;
+use super::mlp::Mlp;
+use super::generate_training::TrainingData;
+use super::objective::Objective;
+use super::prediction_cache::PredictionCache;
 
 pub const N_ACTIONS: usize = 8;
+/// The number of players that a model sees when it doesn't record its capacity (see `Encoder`)
 pub const MAX_PLAYERS: usize = 16;
 pub const ACTION_ATTACK: usize = 7;
-pub const MAX_ACTIONS: usize = ACTION_ATTACK + MAX_PLAYERS - 1;
-pub const INPUT_SIZE: usize = MAX_ACTIONS * N_ACTIONS + 6 * MAX_PLAYERS;
+pub const MAX_ACTIONS: usize = max_actions(MAX_PLAYERS);
+pub const INPUT_SIZE: usize = input_size(MAX_PLAYERS);
+
+/// The size of the output of a model that sees `max_players` players: one attack per opponent
+pub const fn max_actions(max_players: usize) -> usize {
+    ACTION_ATTACK + max_players - 1
+}
+
+/// The size of the input of a model that sees `max_players` players
+pub const fn input_size(max_players: usize) -> usize {
+    max_actions(max_players) * N_ACTIONS + 6 * max_players
+}
 
 pub type ModelPrec = f32;
 const DATUM_PREC: DatumType = DatumType::F32;
@@ -20,11 +34,14 @@ pub const MAX_BARRACKS: ModelPrec = 10.0;
 pub const MAX_OBELISKS: ModelPrec = 10.0;
 pub const SOLDIERS_SCALE: ModelPrec = 5.0;
 
+// Writes the last `N_ACTIONS` actions of the player, most recent first, as one-hot vectors of `max_actions` values
 fn convert_previous_actions(
     actions: &[Action],
     inverse_permutation: &[usize],
-) -> [[ModelPrec; MAX_ACTIONS]; N_ACTIONS] {
-    let mut res = [[0.0; MAX_ACTIONS]; N_ACTIONS];
+    max_players: usize,
+    input: &mut [ModelPrec],
+) {
+    let max_actions = max_actions(max_players);
 
     for (n, action) in actions
         .iter()
@@ -34,10 +51,8 @@ fn convert_previous_actions(
         .take(N_ACTIONS)
         .enumerate()
     {
-        res[n] = categorize_action(action, inverse_permutation)
+        categorize_action(action, inverse_permutation, max_players, &mut input[n * max_actions..(n + 1) * max_actions]);
     }
-
-    res
 }
 
 fn convert_player(player: &Player) -> [ModelPrec; 6] {
@@ -51,7 +66,9 @@ fn convert_player(player: &Player) -> [ModelPrec; 6] {
     ]
 }
 
-fn get_action_index(action: Action, inverse_permutation: &[usize]) -> usize {
+// Attacks are ordered like the opponents in the input, from the strongest to the weakest; the attacks on the opponents
+// that were left out of the input (see `compute_permutation`) share the output of the weakest opponent that is in it
+fn get_action_index(action: Action, inverse_permutation: &[usize], max_players: usize) -> usize {
     match action {
         Action::None => 0,
         Action::Wall => 1,
@@ -60,29 +77,21 @@ fn get_action_index(action: Action, inverse_permutation: &[usize]) -> usize {
         Action::Obelisk => 4,
         Action::Defend => 5,
         Action::Skip => 6,
-        Action::Attack(n) => {
-            7 + inverse_permutation[n]
-            // if n < player_index {
-            //     7 + n
-            // } else {
-            //     7 + n - 1
-            // }
-        }
+        Action::Attack(n) => ACTION_ATTACK + inverse_permutation[n].saturating_sub(1).min(max_players - 2),
     }
 }
 
-fn categorize_action(action: Action, inverse_permutation: &[usize]) -> [ModelPrec; MAX_ACTIONS] {
-    let mut res = [0.0; MAX_ACTIONS];
+fn categorize_action(action: Action, inverse_permutation: &[usize], max_players: usize, res: &mut [ModelPrec]) {
+    // The indices are checked against actions.csv in `test_encoder`
+    let index = get_action_index(action, inverse_permutation, max_players);
 
-    // TODO: generate this code from actions.csv?
-    let index = get_action_index(action, inverse_permutation);
-
-    debug_assert!(index < MAX_ACTIONS);
+    debug_assert!(index < res.len());
     res[index] = 1.0;
-
-    return res;
 }
 
+// Orders the players for the input: player `player_index` first, then the other players from the strongest to the
+// weakest (walls, doubled if they defend, plus soldiers). With more players than the model can see, only the first ones
+// are encoded, so the weakest opponents are left out.
 #[inline]
 fn compute_permutation(players: &[Player], player_index: usize) -> (Vec<usize>, Vec<usize>) {
     let mut permutation = Vec::with_capacity(players.len());
@@ -110,48 +119,258 @@ fn compute_permutation(players: &[Player], player_index: usize) -> (Vec<usize>,
     (permutation, inverse_permutation)
 }
 
+// Encodes the state of the game from the point of view of player `player_index`; also returns the inverse permutation
+// of the players, which maps the players to the attack actions of the output
 #[inline]
-pub fn run_model(
-    model: &Model,
+fn encode_input(
     previous_actions: &[Action],
     players: &[Player],
     player_index: usize,
-    actions: &[Action],
-) -> TractResult<Vec<(Action, ModelPrec)>> {
-    let (permutation, inverse_permutation) = compute_permutation(players, player_index);
+    max_players: usize,
+    input: &mut [ModelPrec],
+) -> Vec<usize> {
+    debug_assert!(input.len() == input_size(max_players));
 
-    let previous_actions = convert_previous_actions(previous_actions, &inverse_permutation);
-    let mut input = [0.0; INPUT_SIZE];
+    let (_, inverse_permutation) = compute_permutation(players, player_index);
 
-    for (n, prev) in previous_actions.into_iter().enumerate() {
-        let index = n * MAX_ACTIONS;
-        let slice = &mut input[index..(index + MAX_ACTIONS)];
-        slice.copy_from_slice(&prev);
-    }
+    let players_offset = N_ACTIONS * max_actions(max_players);
+    convert_previous_actions(previous_actions, &inverse_permutation, max_players, &mut input[..players_offset]);
 
     for (n, player) in players.iter().enumerate() {
-        let index = permutation[n] * 6 + N_ACTIONS * MAX_ACTIONS;
+        if inverse_permutation[n] >= max_players {
+            continue;
+        }
+
+        let index = inverse_permutation[n] * 6 + players_offset;
 
         let slice = &mut input[index..(index+6)];
         let converted = convert_player(player);
         slice.copy_from_slice(&converted);
     }
 
-    let tensor = Tensor::from_shape(&[1, INPUT_SIZE], &input)?;
+    inverse_permutation
+}
+
+/// The version of the input layout of the model; it must be increased whenever the encoding changes,
+/// so that the models trained with another encoding are refused:
+/// - 1: the first recorded version
+/// - 2: the number of players is recorded in the model, and the first attack is the one on the strongest opponent
+/// - 3: each player is encoded in its own slot (`inverse_permutation`), instead of the slot of the player at its index
+pub const ENCODER_VERSION: u32 = 3;
+/// The key of the ONNX metadata entry that records the encoder version of a model
+pub const ENCODER_VERSION_KEY: &str = "encoder_version";
+/// The key of the ONNX metadata entry that records the number of players that a model sees
+pub const MAX_PLAYERS_KEY: &str = "max_players";
+
+/// A feature of a player in the input of the model
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeatureSchema {
+    pub name: String,
+    /// The offset of the feature within the features of a player
+    pub offset: usize,
+    /// How the value is computed: `x / scale`, `1 - exp(-x / scale)` or `x > scale`
+    pub transform: String,
+    pub scale: ModelPrec,
+}
+
+/// A description of the input and output layout of the model, for the scripts that train it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncoderSchema {
+    pub version: u32,
+    pub input_size: usize,
+    /// The previous actions of the player, most recent first, as `history_length` one-hot vectors of `max_actions` values
+    pub history_offset: usize,
+    pub history_length: usize,
+    /// The features of `max_players` players: the player itself, then the other players by decreasing strength
+    /// (walls, doubled if they defend, plus soldiers); the missing players are left at zero, and the weakest opponents
+    /// are left out in larger games
+    pub players_offset: usize,
+    pub max_players: usize,
+    pub player_features: Vec<FeatureSchema>,
+    /// The index of every action in the one-hot vectors and in the output; attacks are offset by the rank of the target
+    /// among the opponents, and the attacks on the opponents that are left out share the last output
+    pub actions: Vec<(String, usize)>,
+    pub max_actions: usize,
+}
+
+/// The encoding of the states for the model, shared by `run_model` and the training code.
+/// The number of players that the model sees, `max_players`, is a property of the model (see `Network::encoder`);
+/// in larger games, the weakest opponents are left out of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encoder {
+    pub max_players: usize,
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self::new(MAX_PLAYERS)
+    }
+}
+
+impl Encoder {
+    pub fn new(max_players: usize) -> Self {
+        assert!(max_players >= 2, "The model must see at least one opponent");
+
+        Self { max_players }
+    }
+
+    /// Returns the encoder whose input has `input_size` values, if there is one
+    pub fn from_input_size(input_size: usize) -> Option<Self> {
+        let per_player = 6 + N_ACTIONS;
+        let fixed = (ACTION_ATTACK - 1) * N_ACTIONS;
+
+        (input_size > fixed && (input_size - fixed).is_multiple_of(per_player) && (input_size - fixed) / per_player >= 2)
+            .then(|| Self::new((input_size - fixed) / per_player))
+    }
+
+    pub fn version(&self) -> u32 {
+        ENCODER_VERSION
+    }
+
+    pub fn input_size(&self) -> usize {
+        input_size(self.max_players)
+    }
+
+    pub fn max_actions(&self) -> usize {
+        max_actions(self.max_players)
+    }
+
+    /// Encodes the state from the point of view of player `index` into `input`, which must be zeroed beforehand;
+    /// returns the inverse permutation of the players, to be given to `action_index`
+    pub fn encode(&self, previous_actions: &[Action], players: &[Player], index: usize, input: &mut [ModelPrec]) -> Vec<usize> {
+        encode_input(previous_actions, players, index, self.max_players, input)
+    }
+
+    /// Returns the index of `action` in the output of the model
+    pub fn action_index(&self, action: Action, inverse_permutation: &[usize]) -> usize {
+        get_action_index(action, inverse_permutation, self.max_players)
+    }
+
+    /// Encodes the state of `data` for player `index`, along with the index of its best action and the sorted indices of
+    /// its possible actions (the attacks on the opponents that were left out of the input share one output).
+    /// Returns `None` if the player couldn't play, or if its best action attacks an opponent that was left out of the input.
+    pub fn encode_training(&self, data: &TrainingData, index: usize) -> Option<(Vec<ModelPrec>, usize, Vec<usize>)> {
+        let action = data.best_actions.get(index)?.0;
+        if action == Action::None {
+            return None;
+        }
+
+        let mut input = vec![0.0; self.input_size()];
+        let inverse_permutation = self.encode(&data.history(index), &data.players, index, &mut input);
+
+        // The attacks on the opponents that were left out of the input have no output of their own
+        if let Action::Attack(target) = action {
+            if inverse_permutation[target] >= self.max_players {
+                return None;
+            }
+        }
+
+        let opponents = data.players.iter().enumerate().filter(|(n, _p)| *n != index);
+        let mut legal = data.players[index].possible_actions(opponents).into_iter()
+            .map(|action| self.action_index(action, &inverse_permutation))
+            .collect::<Vec<_>>();
+        legal.sort_unstable();
+        legal.dedup();
+
+        Some((input, self.action_index(action, &inverse_permutation), legal))
+    }
+
+    pub fn schema(&self) -> EncoderSchema {
+        let feature = |name: &str, offset, transform: &str, scale| FeatureSchema {
+            name: name.to_string(),
+            offset,
+            transform: transform.to_string(),
+            scale,
+        };
+        let actions = [Action::None, Action::Wall, Action::Recruit, Action::Barracks, Action::Obelisk, Action::Defend, Action::Skip, Action::Attack(0)];
 
-    let prediction = model.run(tvec!(tensor))?;
+        EncoderSchema {
+            version: ENCODER_VERSION,
+            input_size: self.input_size(),
+            history_offset: 0,
+            history_length: N_ACTIONS,
+            players_offset: N_ACTIONS * self.max_actions(),
+            max_players: self.max_players,
+            player_features: vec![
+                feature("walls", 0, "x / scale", MAX_WALLS),
+                feature("soldiers", 1, "1 - exp(-x / scale)", SOLDIERS_SCALE),
+                feature("barracks", 2, "x / scale", MAX_BARRACKS),
+                feature("obelisks", 3, "x / scale", MAX_OBELISKS),
+                feature("defense", 4, "x > scale", 0.0),
+                feature("defense", 5, "x > scale", 1.0),
+            ],
+            actions: actions.iter().map(|action| {
+                let name = match action {
+                    Action::Attack(_) => String::from("Attack"),
+                    action => format!("{:?}", action),
+                };
+                // Attacking the strongest opponent, which comes right after the player
+                (name, get_action_index(*action, &[1], self.max_players))
+            }).collect(),
+            max_actions: self.max_actions(),
+        }
+    }
 
-    let prediction: Vec<ModelPrec> = prediction[0].to_array_view::<ModelPrec>()?.iter().cloned().collect::<Vec<_>>();
+    /// Checks that a model trained with encoder `version` can be run; the models that don't record it predate the
+    /// versioning of the encoder (version 0), and are refused like the models of any other version
+    pub fn check_version(&self, version: Option<u32>) -> TractResult<()> {
+        match version {
+            Some(ENCODER_VERSION) => Ok(()),
+            Some(version) => tract_onnx::prelude::tract_data::anyhow::bail!(
+                "The model was trained with encoder version {}, but this build encodes the states with version {}; train or convert it again",
+                version,
+                ENCODER_VERSION,
+            ),
+            None => tract_onnx::prelude::tract_data::anyhow::bail!(
+                "The model doesn't record its encoder version, so it predates version 1, but this build encodes the states with version {}; train or convert it again",
+                ENCODER_VERSION,
+            ),
+        }
+    }
+}
 
-    // println!("{:?}", prediction);
+/// Returns the encoder version recorded in the metadata of an ONNX model, if any
+pub fn encoder_version(proto: &tract_onnx::pb::ModelProto) -> TractResult<Option<u32>> {
+    match proto.metadata_props.iter().find(|entry| entry.key == ENCODER_VERSION_KEY) {
+        Some(entry) => Ok(Some(entry.value.trim().parse()?)),
+        None => Ok(None),
+    }
+}
+
+/// Returns the encoder of an ONNX model, whose number of players is recorded in its metadata (`MAX_PLAYERS` if it isn't)
+pub fn model_encoder(proto: &tract_onnx::pb::ModelProto) -> TractResult<Encoder> {
+    match proto.metadata_props.iter().find(|entry| entry.key == MAX_PLAYERS_KEY) {
+        Some(entry) => {
+            let max_players: usize = entry.value.trim().parse()?;
+            if max_players < 2 {
+                tract_onnx::prelude::tract_data::anyhow::bail!("The model sees {} players, it must see at least 2", max_players);
+            }
+
+            Ok(Encoder::new(max_players))
+        }
+        None => Ok(Encoder::default()),
+    }
+}
+
+// Returns the normalized probabilities of `actions` in `prediction`, from the most to the least likely
+#[inline]
+fn decode_prediction(prediction: &[ModelPrec], actions: &[Action], inverse_permutation: &[usize], max_players: usize) -> Vec<(Action, ModelPrec)> {
+    let indices = actions.iter().map(|action| get_action_index(*action, inverse_permutation, max_players)).collect::<Vec<_>>();
+
+    // The attacks on the opponents that were left out of the input share the last attack output, whose probability is
+    // split evenly between them, so that it isn't counted once per opponent
+    let mut shared = vec![0usize; prediction.len()];
+    for index in indices.iter().copied() {
+        shared[index] += 1;
+    }
 
     let mut res = Vec::with_capacity(actions.len());
     let mut sum = 0.0;
 
-    for action in actions.iter().copied() {
-        let index = get_action_index(action, &inverse_permutation);
-        res.push((action, prediction[index]));
-        sum += prediction[index];
+    for (action, index) in actions.iter().copied().zip(indices.iter().copied()) {
+        let prob = prediction[index] / shared[index] as ModelPrec;
+        res.push((action, prob));
+        sum += prob;
     }
 
     for x in res.iter_mut() {
@@ -160,64 +379,739 @@ pub fn run_model(
 
     res.sort_unstable_by(|(_, a), (_, b)| b.partial_cmp(&a).unwrap_or(std::cmp::Ordering::Equal));
 
-    Ok(res)
+    res
 }
 
-// Workaround for issue https://github.com/rust-lang/rust/issues/55997
-mod workaround_55997 {
-    use super::*;
+/// A row of a batch given to `run_model_batch`: the probabilities of `actions` are predicted for player `index`
+#[derive(Debug, Clone, Copy)]
+pub struct ModelQuery<'b> {
+    pub players: &'b [Player],
+    pub index: usize,
+    pub previous_actions: &'b [Action],
+    pub actions: &'b [Action],
+}
 
-    pub type ModelFact = impl Fact + Hash + Clone + 'static;
-    pub type ModelOp = impl Debug + Display + AsRef<dyn Op + 'static> + AsMut<dyn Op + 'static> + Clone + Hash + 'static;
-    pub type ModelGraph = impl Borrow<Graph<ModelFact, ModelOp>> + Hash;
-    pub type Model = SimplePlan<ModelFact, ModelOp, ModelGraph>;
+/// Runs the model on every query at once, as a single `[queries.len(), input size]` tensor, which is much faster than
+/// running it once per query; the queries can come from different states.
+/// Returns the probabilities of the actions of every query, in the same order as `queries`.
+pub fn run_model_batch(model: &Network, queries: &[ModelQuery]) -> TractResult<Vec<Vec<(Action, ModelPrec)>>> {
+    run_model_batch_cached(model, None, queries)
+}
 
-    pub fn load_model(path: impl AsRef<Path>) -> TractResult<Model> {
-        // debug_assert!(INPUT_SIZE == 272);
-        tract_onnx::onnx()
-            .model_for_path(path)?
-            .with_input_fact(0, InferenceFact::dt_shape(
-                DATUM_PREC,
-                &[1, INPUT_SIZE],
-            ))?
-            .with_output_fact(0, InferenceFact::dt_shape(
-                DATUM_PREC,
-                &[1, MAX_ACTIONS]
-            ))?
-            .into_optimized()?
-            .into_runnable()
+/// Runs the policy head of the model on `rows` inputs encoded by its `Encoder`, and returns its `[rows, max actions]` output
+pub fn run_policy(model: &Network, input: &[ModelPrec], rows: usize) -> TractResult<Vec<ModelPrec>> {
+    match model {
+        Network::Tract(model) => {
+            let tensor = Tensor::from_shape(&[rows, input.len() / rows.max(1)], input)?;
+            Ok(model.run(tvec!(tensor))?[0].as_slice::<ModelPrec>()?.to_vec())
+        }
+        Network::Native(mlp) => Ok(mlp.forward(input, rows)),
     }
 }
-pub use workaround_55997::*;
 
-pub type ModelFn<'a> = impl 'a + Copy + Send + (for<'c> AiFn<'c, rand::rngs::ThreadRng>);
+/// Like `run_model_batch`, but the outputs of the model are looked up in `cache` first, and only the inputs that
+/// are not there go through the model, as a single batch
+pub fn run_model_batch_cached(
+    model: &Network,
+    cache: Option<&PredictionCache>,
+    queries: &[ModelQuery],
+) -> TractResult<Vec<Vec<(Action, ModelPrec)>>> {
+    if queries.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let encoder = model.encoder();
+    let (input_size, max_actions) = (encoder.input_size(), encoder.max_actions());
+    let mut input = vec![0.0; queries.len() * input_size];
+    let inverse_permutations = queries.iter().zip(input.chunks_mut(input_size)).map(|(query, input)| {
+        encoder.encode(query.previous_actions, query.players, query.index, input)
+    }).collect::<Vec<_>>();
 
-pub fn wrap_model<'a>(model: &'a Model) -> ModelFn<'a> {
-    use rand::Rng;
-    move |players: &[Player], index: usize, _round: usize, previous_actions: &[Action], rng: &mut rand::rngs::ThreadRng| {
+    let prediction = if let Some(cache) = cache {
+        let keys = input.chunks(input_size).map(PredictionCache::key).collect::<Vec<_>>();
+        let mut prediction = vec![0.0; queries.len() * max_actions];
+        let mut missing = Vec::new();
+
+        for (n, key) in keys.iter().enumerate() {
+            match cache.get(*key) {
+                Some(output) => prediction[n * max_actions..(n + 1) * max_actions].copy_from_slice(&output),
+                None => missing.push(n),
+            }
+        }
+
+        if !missing.is_empty() {
+            let missing_input = missing.iter().flat_map(|&n| input[n * input_size..(n + 1) * input_size].iter().copied()).collect::<Vec<_>>();
+            let missing_prediction = run_policy(model, &missing_input, missing.len())?;
+
+            for (&n, output) in missing.iter().zip(missing_prediction.chunks(max_actions)) {
+                prediction[n * max_actions..(n + 1) * max_actions].copy_from_slice(output);
+                cache.insert(keys[n], output.to_vec());
+            }
+        }
+
+        prediction
+    } else {
+        run_policy(model, &input, queries.len())?
+    };
+
+    Ok(queries.iter().zip(inverse_permutations.iter()).zip(prediction.chunks(max_actions)).map(|((query, inverse_permutation), prediction)| {
+        decode_prediction(prediction, query.actions, inverse_permutation, encoder.max_players)
+    }).collect())
+}
+
+/// Runs the value head of the model on every query at once; the actions of the queries are ignored.
+/// Returns the predicted loss of the player of every query, in the same order as `queries`.
+pub fn run_value_batch(model: &Network, queries: &[ModelQuery]) -> TractResult<Vec<ModelPrec>> {
+    if !model.has_value() {
+        tract_onnx::prelude::tract_data::anyhow::bail!("The model has no value head");
+    }
+    if queries.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let encoder = model.encoder();
+    let mut input = vec![0.0; queries.len() * encoder.input_size()];
+    for (query, input) in queries.iter().zip(input.chunks_mut(encoder.input_size())) {
+        encoder.encode(query.previous_actions, query.players, query.index, input);
+    }
+
+    match model {
+        Network::Tract(model) => {
+            let tensor = Tensor::from_shape(&[queries.len(), encoder.input_size()], &input)?;
+            Ok(model.run(tvec!(tensor))?[1].as_slice::<ModelPrec>()?.to_vec())
+        }
+        Network::Native(mlp) => Ok(mlp.forward_value(&input, queries.len()).unwrap()),
+    }
+}
+
+#[inline]
+pub fn run_model(
+    model: &Network,
+    previous_actions: &[Action],
+    players: &[Player],
+    player_index: usize,
+    actions: &[Action],
+) -> TractResult<Vec<(Action, ModelPrec)>> {
+    let query = ModelQuery {
+        players,
+        index: player_index,
+        previous_actions,
+        actions,
+    };
+
+    Ok(run_model_batch(model, &[query])?.pop().unwrap())
+}
+
+/// The model, optimized by tract
+pub type Model = TypedSimplePlan<TypedModel>;
+
+/// Loads the model, with a symbolic batch dimension (see `run_model_batch`)
+pub fn load_model(path: impl AsRef<Path>) -> TractResult<Model> {
+    model_for_proto(&tract_onnx::onnx().proto_model_for_path(path)?)
+}
+
+/// Loads the model from an ONNX protobuf that is already in memory; refuses models trained with another encoder
+pub fn model_for_proto(proto: &tract_onnx::pb::ModelProto) -> TractResult<Model> {
+    let encoder = model_encoder(proto)?;
+    encoder.check_version(encoder_version(proto)?)?;
+    optimize_model(tract_onnx::onnx().model_for_proto_model(proto)?, encoder)
+}
+
+fn optimize_model(model: InferenceModel, encoder: Encoder) -> TractResult<Model> {
+    let has_value = model.outputs.len() > 1;
+    let mut model = model
+        .with_input_fact(0, InferenceFact::dt_shape(
+            DATUM_PREC,
+            tvec!(TDim::from('N'), encoder.input_size().to_dim()),
+        ))?
+        .with_output_fact(0, InferenceFact::dt_shape(
+            DATUM_PREC,
+            tvec!(TDim::from('N'), encoder.max_actions().to_dim()),
+        ))?;
+
+    // The second output, if any, is the value head (see `run_value_batch`)
+    if has_value {
+        model = model.with_output_fact(1, InferenceFact::dt_shape(
+            DATUM_PREC,
+            tvec!(TDim::from('N'), 1.to_dim()),
+        ))?;
+    }
+
+    model.into_optimized()?.into_runnable()
+}
+
+/// Which implementation runs the model
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ModelBackend {
+    /// The ONNX model, optimized and run by tract
+    #[default]
+    Tract,
+    /// The dense layers of the model, evaluated natively (see `mlp.rs`)
+    Native,
+}
+
+impl ModelBackend {
+    /// Reads the backend from the `OBELISK_BACKEND` environment variable, defaulting to tract
+    pub fn from_env() -> Self {
+        match std::env::var("OBELISK_BACKEND") {
+            Ok(raw) => raw.parse().unwrap_or_else(|err| panic!("{}", err)),
+            Err(_) => Self::default(),
+        }
+    }
+}
+
+impl std::str::FromStr for ModelBackend {
+    type Err = String;
+
+    /// Parses `tract` or `native`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tract" => Ok(ModelBackend::Tract),
+            "native" => Ok(ModelBackend::Native),
+            _ => Err(format!("Invalid model backend: {}", s)),
+        }
+    }
+}
+
+/// The model, loaded with one of the backends
+pub enum Network {
+    Tract(Box<Model>),
+    Native(Mlp),
+}
+
+impl Network {
+    /// Returns the encoder of the inputs of the model, which depends on the number of players it sees
+    pub fn encoder(&self) -> Encoder {
+        let input_size = match self {
+            Network::Tract(model) => model.model().input_fact(0)
+                .and_then(|fact| fact.to_typed_fact()?.shape[1].to_usize())
+                .unwrap_or(INPUT_SIZE),
+            Network::Native(mlp) => mlp.inputs(),
+        };
+
+        Encoder::from_input_size(input_size).unwrap_or_default()
+    }
+
+    /// Returns true if the model has a value head, the second output that predicts the loss of the player
+    pub fn has_value(&self) -> bool {
+        match self {
+            Network::Tract(model) => model.model().outputs.len() > 1,
+            Network::Native(mlp) => mlp.value.is_some(),
+        }
+    }
+}
+
+/// Loads the model stored at `path` with `backend`. The weights files written by `convert.py` and the `train` binary
+/// (with a `.json` extension) can only be run natively, so they are always loaded with the native backend.
+/// Exactly the file at `path` is loaded, so that the model can be identified by it.
+pub fn load_network(path: impl AsRef<Path>, backend: ModelBackend) -> TractResult<Network> {
+    let path = path.as_ref();
+    let backend = if path.extension().map(|ext| ext == "json").unwrap_or(false) { ModelBackend::Native } else { backend };
+
+    match backend {
+        ModelBackend::Tract => Ok(Network::Tract(Box::new(load_model(path)?))),
+        ModelBackend::Native => {
+            let mlp = Mlp::load(path)?;
+            Encoder::default().check_version(mlp.encoder_version)?;
+
+            Ok(Network::Native(mlp))
+        }
+    }
+}
+
+/// How the predictions of the model are turned into a policy, to model players that are more or less greedy than
+/// the ones the model was trained on
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelPolicySettings {
+    /// Sharpens (`< 1`) or flattens (`> 1`) the predictions; must be positive
+    #[serde(deserialize_with = "deserialize_temperature")]
+    pub temperature: ModelPrec,
+    /// Only the `top_k` most likely actions can be played
+    pub top_k: Option<usize>,
+    /// Probability of playing an action drawn uniformly among the possible actions instead
+    #[serde(deserialize_with = "deserialize_epsilon")]
+    pub epsilon: ModelPrec,
+    /// Always plays the most likely action (except with probability `epsilon`)
+    pub argmax: bool,
+}
+
+impl Default for ModelPolicySettings {
+    fn default() -> Self {
+        Self {
+            temperature: 1.0,
+            top_k: None,
+            epsilon: 0.0,
+            argmax: false,
+        }
+    }
+}
+
+impl ModelPolicySettings {
+    /// Applies the settings to `predictions`, sorted from the most to the least likely action; the result is also sorted,
+    /// and the actions that can't be played are removed. A temperature of 0 plays the most likely action, like `argmax`.
+    pub fn apply(&self, mut predictions: Vec<(Action, ModelPrec)>) -> Vec<(Action, ModelPrec)> {
+        let possible = predictions.len() as ModelPrec;
+        let argmax = |predictions: &mut Vec<(Action, ModelPrec)>| {
+            for (n, (_, prob)) in predictions.iter_mut().enumerate() {
+                *prob = if n == 0 { 1.0 } else { 0.0 };
+            }
+        };
+
+        if self.argmax || self.temperature <= 0.0 {
+            argmax(&mut predictions);
+        } else {
+            if self.temperature != 1.0 {
+                for (_, prob) in predictions.iter_mut() {
+                    *prob = prob.powf(1.0 / self.temperature);
+                }
+            }
+            if let Some(top_k) = self.top_k {
+                for (_, prob) in predictions.iter_mut().skip(top_k.max(1)) {
+                    *prob = 0.0;
+                }
+            }
+
+            // A low temperature can round every probability down to 0, in which case the most likely action is played
+            let sum = predictions.iter().map(|(_, prob)| *prob).sum::<ModelPrec>();
+            if sum > 0.0 && sum.is_finite() {
+                for (_, prob) in predictions.iter_mut() {
+                    *prob /= sum;
+                }
+            } else {
+                argmax(&mut predictions);
+            }
+        }
+
+        if self.epsilon > 0.0 {
+            for (_, prob) in predictions.iter_mut() {
+                *prob = (1.0 - self.epsilon) * *prob + self.epsilon / possible;
+            }
+        }
+
+        predictions.retain(|(_, prob)| *prob > 0.0);
+
+        predictions
+    }
+}
+
+fn deserialize_temperature<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<ModelPrec, D::Error> {
+    let temperature = ModelPrec::deserialize(deserializer)?;
+    if temperature > 0.0 && temperature.is_finite() {
+        Ok(temperature)
+    } else {
+        Err(serde::de::Error::custom(format!("The temperature must be positive, got {}", temperature)))
+    }
+}
+
+fn deserialize_epsilon<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<ModelPrec, D::Error> {
+    let epsilon = ModelPrec::deserialize(deserializer)?;
+    if (0.0..=1.0).contains(&epsilon) {
+        Ok(epsilon)
+    } else {
+        Err(serde::de::Error::custom(format!("Epsilon must be between 0 and 1, got {}", epsilon)))
+    }
+}
+
+/// The model as a `Policy`, with its predictions transformed by `ModelPolicySettings`, which can differ for each seat
+#[derive(Clone, Copy)]
+pub struct ModelPolicy<'a> {
+    pub model: &'a Network,
+    pub settings: ModelPolicySettings,
+    /// The settings of each player, by index; the players without one use `settings`
+    pub seats: &'a [ModelPolicySettings],
+    pub cache: Option<&'a PredictionCache>,
+}
+
+impl<'a> ModelPolicy<'a> {
+    pub fn new(model: &'a Network) -> Self {
+        Self {
+            model,
+            settings: ModelPolicySettings::default(),
+            seats: &[],
+            cache: None,
+        }
+    }
+
+    pub fn with_settings(mut self, settings: ModelPolicySettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    pub fn with_temperature(mut self, temperature: ModelPrec) -> Self {
+        self.settings.temperature = temperature;
+        self
+    }
+
+    pub fn with_seats(mut self, seats: &'a [ModelPolicySettings]) -> Self {
+        self.seats = seats;
+        self
+    }
+
+    /// Memoises the predictions of the model in `cache`, which can be shared with other policies using the same model
+    pub fn with_cache(mut self, cache: &'a PredictionCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Returns the settings of player `index`
+    pub fn settings_of(&self, index: usize) -> &ModelPolicySettings {
+        self.seats.get(index).unwrap_or(&self.settings)
+    }
+
+    /// Returns the probability of each possible action of player `index`
+    pub fn predict(&self, players: &[Player], index: usize, previous_actions: &[Action]) -> Vec<(Action, ModelPrec)> {
         let possible_actions = players[index].possible_actions(
             players.iter().enumerate().filter(|(x, _p)| *x != index),
         );
 
-        let predictions = run_model(
-            model,
+        let query = ModelQuery {
+            players,
+            index,
             previous_actions,
+            actions: &possible_actions,
+        };
+        let predictions = run_model_batch_cached(self.model, self.cache, &[query]).unwrap().pop().unwrap();
+
+        self.settings_of(index).apply(predictions)
+    }
+
+    /// Returns the probability of each possible action of every player, running the model once for all the players that can play
+    pub fn predict_all(&self, players: &[Player], previous_actions: &[Vec<Action>]) -> Vec<Vec<(Action, ModelPrec)>> {
+        let possible_actions = (0..players.len()).map(|index| {
+            players[index].possible_actions(players.iter().enumerate().filter(|(x, _p)| *x != index))
+        }).collect::<Vec<_>>();
+
+        let queries = (0..players.len()).filter(|&index| players[index].can_play()).map(|index| ModelQuery {
+            players,
+            index,
+            previous_actions: &previous_actions[index],
+            actions: &possible_actions[index],
+        }).collect::<Vec<_>>();
+
+        let mut predictions = run_model_batch_cached(self.model, self.cache, &queries).unwrap().into_iter();
+
+        (0..players.len()).map(|index| {
+            if players[index].can_play() {
+                self.settings_of(index).apply(predictions.next().unwrap())
+            } else {
+                vec![(Action::None, 1.0)]
+            }
+        }).collect()
+    }
+}
+
+/// The value head of the model as an objective: the predicted loss of the player.
+/// It is meant to bootstrap the games that are cut short by the horizon of the simulation, for instance
+/// `Bootstrapped { objective: Heuristic, value: ModelValue::new(&model) }` with `Horizon::Rounds(k)`; the loss it predicts
+/// is the one of the objective that the training data was generated with (`Heuristic`), which the games that ended must
+/// also be measured with.
+#[derive(Clone, Copy)]
+pub struct ModelValue<'a> {
+    pub model: &'a Network,
+}
+
+impl<'a> ModelValue<'a> {
+    pub fn new(model: &'a Network) -> Self {
+        assert!(model.has_value(), "The model has no value head");
+
+        Self { model }
+    }
+}
+
+impl<'a> Objective for ModelValue<'a> {
+    /// Without the history of the player, the model is given an empty history, which makes its prediction less accurate
+    fn loss(&self, players: &[Player], index: usize) -> f64 {
+        self.loss_with_history(players, index, &[])
+    }
+
+    fn loss_with_history(&self, players: &[Player], index: usize, history: &[Vec<Action>]) -> f64 {
+        let query = ModelQuery {
             players,
             index,
-            &possible_actions
-        ).unwrap();
+            previous_actions: history.get(index).map(|actions| actions.as_slice()).unwrap_or(&[]),
+            actions: &[],
+        };
+
+        run_value_batch(self.model, &[query]).unwrap()[0] as f64
+    }
+}
+
+// Draws an action from the predictions of the model
+fn sample_prediction(predictions: &[(Action, ModelPrec)], rng: &mut rand::rngs::ThreadRng) -> Action {
+    use rand::Rng;
+
+    let choice = rng.gen::<ModelPrec>();
+    let mut sum = 0.0;
+    for (action, prob) in predictions.iter().copied() {
+        sum += prob;
+        if sum > choice {
+            return action
+        }
+    }
 
-        let best_action = predictions[0].0;
+    predictions.first().map(|(action, _)| *action).unwrap_or(Action::None)
+}
+
+impl<'a> Policy for ModelPolicy<'a> {
+    fn probability(&self, players: &[Player], index: usize, _round: usize, previous_actions: &[Action], action: Action) -> f64 {
+        self.predict(players, index, previous_actions)
+            .into_iter()
+            .find(|(a, _)| *a == action)
+            .map(|(_, prob)| prob as f64)
+            .unwrap_or(0.0)
+    }
+
+    fn sample(&self, players: &[Player], index: usize, _round: usize, previous_actions: &[Action], rng: &mut rand::rngs::ThreadRng) -> Action {
+        sample_prediction(&self.predict(players, index, previous_actions), rng)
+    }
+
+    fn distribution(&self, players: &[Player], index: usize, _round: usize, previous_actions: &[Action]) -> Vec<(Action, f64)> {
+        self.predict(players, index, previous_actions)
+            .into_iter()
+            .map(|(action, prob)| (action, prob as f64))
+            .collect()
+    }
+}
+
+/// In monte carlo, the model predicts the actions of every player of a round in a single batch
+impl<'a> RolloutAi for ModelPolicy<'a> {
+    type Game = ();
+
+    #[inline]
+    fn new_game(&self, _players: &[Player], _rng: &mut rand::rngs::ThreadRng) {}
+
+    #[inline]
+    fn action(
+        &self,
+        _game: &(),
+        players: &[Player],
+        index: usize,
+        round: usize,
+        previous_actions: &[Action],
+        rng: &mut rand::rngs::ThreadRng,
+    ) -> Action {
+        self.sample(players, index, round, previous_actions, rng)
+    }
+
+    fn actions(
+        &self,
+        _game: &(),
+        players: &[Player],
+        _round: usize,
+        previous_actions: &[Vec<Action>],
+        rng: &mut rand::rngs::ThreadRng,
+    ) -> Vec<Action> {
+        self.predict_all(players, previous_actions)
+            .iter()
+            .map(|predictions| sample_prediction(predictions, rng))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
 
-        let choice = rng.gen::<ModelPrec>();
-        let mut sum = 0.0;
+    #[test]
+    fn test_oversized_table() {
+        // The opponent n has n walls, so from the point of view of player 0, the opponents go from 5 down to 1
+        let players = (0..6).map(|n| Player::with_values(n as u8, if n == 0 { 3 } else { 0 }, 0, 1, 0)).collect::<Vec<_>>();
+        let encoder = Encoder::new(4);
+        assert_eq!(encoder.max_actions(), ACTION_ATTACK + 3);
+        assert_eq!(Encoder::from_input_size(encoder.input_size()), Some(encoder));
+        assert_eq!(Encoder::from_input_size(INPUT_SIZE), Some(Encoder::default()));
+
+        // The 2 strongest opponents get their own attack, the attacks on the 3 others share the last one
+        let mut input = vec![0.0; encoder.input_size()];
+        let inverse_permutation = encoder.encode(&[Action::Attack(1)], &players, 0, &mut input);
+        let attacks = (1..6).map(|n| encoder.action_index(Action::Attack(n), &inverse_permutation)).collect::<Vec<_>>();
+        assert_eq!(attacks, vec![9, 9, 9, 8, 7]);
+        assert_eq!(input[9], 1.0);
+
+        // Only the player and its 3 strongest opponents are in the input
+        let schema = encoder.schema();
+        for (slot, walls) in [(0, 0), (1, 5), (2, 4), (3, 3)] {
+            assert!((input[schema.players_offset + slot * 6] - walls as ModelPrec / MAX_WALLS).abs() < 1e-6);
+        }
+        assert_eq!(input.len(), schema.players_offset + 4 * 6);
+
+        // Attacking a dropped opponent isn't a training example
+        let best_actions = vec![(Action::Attack(1), 0.0, 0.0), (Action::None, 0.0, 0.0), (Action::Attack(4), 0.0, 0.0)];
+        let data = TrainingData::new(vec![], players.clone(), best_actions);
+        assert!(encoder.encode_training(&data, 0).is_none());
+        assert_eq!(encoder.encode_training(&data, 2).unwrap().1, ACTION_ATTACK + 1);
+
+        // The possible attacks on the dropped opponents are one possible output
+        let data = TrainingData::new(vec![], players.clone(), vec![(Action::Attack(5), 0.0, 0.0); 6]);
+        let (_, label, legal) = encoder.encode_training(&data, 0).unwrap();
+        assert_eq!(label, ACTION_ATTACK);
+        assert_eq!(legal.iter().filter(|&&n| n >= ACTION_ATTACK).copied().collect::<Vec<_>>(), vec![7, 8, 9]);
+
+        // With as many players as the model sees, the weakest opponent gets the last output
+        let players = (0..MAX_PLAYERS).map(|n| Player::with_values(n as u8, 1, 0, 1, 0)).collect::<Vec<_>>();
+        let mut input = vec![0.0; INPUT_SIZE];
+        let inverse_permutation = Encoder::default().encode(&[], &players, 0, &mut input);
+        assert_eq!(Encoder::default().action_index(Action::Attack(1), &inverse_permutation), MAX_ACTIONS - 1);
+
+        // The capacity of a model comes from its size, and a smaller model still plays every attack
+        let settings = super::super::train::TrainSettings {
+            hidden: vec![8],
+            max_players: 4,
+            ..Default::default()
+        };
+        let network = Network::Native(super::super::train::initialize(&settings, &mut rand::thread_rng()));
+        assert_eq!(network.encoder(), encoder);
+        let players = (0..20).map(|n| Player::with_values((n % 10) as u8, 1, 0, 1, 0)).collect::<Vec<_>>();
+        let predictions = ModelPolicy::new(&network).predict(&players, 3, &[Action::Attack(19)]);
+        assert_eq!(predictions.iter().filter(|(action, _)| matches!(action, Action::Attack(_))).count(), 19);
+        assert!((predictions.iter().map(|(_, prob)| prob).sum::<ModelPrec>() - 1.0).abs() < 1e-5);
+
+        // The 17 opponents after the 2 strongest split the last attack output, which weighs as much as any other output
+        let mut input = vec![0.0; encoder.input_size()];
+        let inverse_permutation = encoder.encode(&[], &players, 3, &mut input);
+        let attacks = (0..20).filter(|n| *n != 3).map(Action::Attack).collect::<Vec<_>>();
+        let actions = [&[Action::Skip][..], &attacks].concat();
+        let predictions = decode_prediction(&vec![0.1; encoder.max_actions()], &actions, &inverse_permutation, encoder.max_players);
         for (action, prob) in predictions {
-            sum += prob;
-            if sum > choice {
-                return action
+            let shared = encoder.action_index(action, &inverse_permutation) == encoder.max_actions() - 1;
+            let expected = if shared { 0.25 / 17.0 } else { 0.25 };
+            assert!((prob - expected).abs() < 1e-6, "{:?} {}", action, prob);
+        }
+
+        let mut proto = tract_onnx::pb::ModelProto::default();
+        proto.metadata_props.push(tract_onnx::pb::StringStringEntryProto {
+            key: MAX_PLAYERS_KEY.to_string(),
+            value: String::from("20"),
+        });
+        assert_eq!(model_encoder(&proto).unwrap(), Encoder::new(20));
+    }
+
+    #[test]
+    fn test_player_slots() {
+        // From the point of view of player 0, the opponents go from the strongest to the weakest: 2, 3, 1
+        let players = vec![
+            Player::with_values(1, 0, 0, 1, 0),
+            Player::with_values(2, 0, 0, 1, 0),
+            Player::with_values(6, 0, 0, 1, 0),
+            Player::with_values(4, 0, 0, 1, 0),
+        ];
+        let encoder = Encoder::default();
+        let mut input = vec![0.0; encoder.input_size()];
+        let inverse_permutation = encoder.encode(&[], &players, 0, &mut input);
+        assert_eq!(inverse_permutation, vec![0, 3, 1, 2]);
+
+        // Each player is encoded in its own slot, so the walls decrease from slot 1 to slot 3
+        let players_offset = encoder.schema().players_offset;
+        let walls = (0..4).map(|slot| input[players_offset + slot * 6] * MAX_WALLS).collect::<Vec<_>>();
+        assert_eq!(walls, vec![1.0, 6.0, 4.0, 2.0]);
+    }
+
+    #[test]
+    fn test_policy_settings() {
+        let predictions = vec![(Action::Wall, 0.5), (Action::Recruit, 0.3), (Action::Skip, 0.2)];
+        let close = |a: &[(Action, ModelPrec)], b: &[(Action, ModelPrec)]| {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| x.0 == y.0 && (x.1 - y.1).abs() < 1e-6)
+        };
+
+        assert!(close(&ModelPolicySettings::default().apply(predictions.clone()), &predictions));
+
+        let settings: ModelPolicySettings = serde_json::from_str(r#"{"top_k": 2}"#).unwrap();
+        assert_eq!(settings.temperature, 1.0);
+        assert!(close(&settings.apply(predictions.clone()), &[(Action::Wall, 0.625), (Action::Recruit, 0.375)]));
+
+        let settings = ModelPolicySettings { temperature: 0.5, ..Default::default() };
+        assert!(close(&settings.apply(predictions.clone()), &[(Action::Wall, 0.25 / 0.38), (Action::Recruit, 0.09 / 0.38), (Action::Skip, 0.04 / 0.38)]));
+
+        let settings = ModelPolicySettings { argmax: true, epsilon: 0.3, ..Default::default() };
+        assert!(close(&settings.apply(predictions.clone()), &[(Action::Wall, 0.8), (Action::Recruit, 0.1), (Action::Skip, 0.1)]));
+        let settings = ModelPolicySettings { argmax: true, ..Default::default() };
+        assert!(close(&settings.apply(predictions.clone()), &[(Action::Wall, 1.0)]));
+
+        // Temperatures that leave nothing to normalize play the most likely action instead of dividing by 0
+        for temperature in [0.0, 1e-3] {
+            let settings = ModelPolicySettings { temperature, ..Default::default() };
+            assert!(close(&settings.apply(predictions.clone()), &[(Action::Wall, 1.0)]), "{}", temperature);
+        }
+        let settings = ModelPolicySettings { top_k: Some(1), ..Default::default() };
+        assert!(close(&settings.apply(vec![(Action::Wall, 0.0), (Action::Skip, 0.0)]), &[(Action::Wall, 1.0)]));
+
+        for raw in [r#"{"temperature": 0}"#, r#"{"temperature": -1}"#, r#"{"epsilon": 1.5}"#, r#"{"epsilon": -0.1}"#] {
+            assert!(serde_json::from_str::<ModelPolicySettings>(raw).is_err(), "{}", raw);
+        }
+        assert!(serde_json::from_str::<ModelPolicySettings>(r#"{"temperature": 2, "epsilon": 1}"#).is_ok());
+    }
+
+    #[test]
+    fn test_encoder() {
+        let encoder = Encoder::default();
+        let schema = encoder.schema();
+
+        // The action map matches the one used by train.py
+        let csv = std::fs::read_to_string("actions.csv").unwrap();
+        let actions = csv.lines().skip(1).map(|line| {
+            let (name, id) = line.split_once(',').unwrap();
+            (name.trim_matches('"').to_string(), id.trim().parse::<usize>().unwrap())
+        }).collect::<Vec<_>>();
+        assert_eq!(schema.actions, actions);
+
+        let players = vec![
+            Player::with_values(2, 3, 1, 2, 0),
+            Player::with_values(4, 5, 2, 1, 2),
+            Player::with_values(1, 0, 3, 3, 1),
+        ];
+        let previous_actions = vec![vec![Action::Wall; 3], vec![Action::Attack(1), Action::Skip, Action::Recruit]];
+        let data = TrainingData::new(previous_actions, players.clone(), vec![(Action::Recruit, 0.0, 0.0), (Action::None, 0.0, 0.0), (Action::Attack(1), 0.0, 0.0)]);
+
+        assert!(encoder.encode_training(&data, 1).is_none());
+        let (input, label, _) = encoder.encode_training(&data, 2).unwrap();
+        assert_eq!(input.len(), schema.input_size);
+
+        // Player 1 is the strongest opponent of player 2, so it comes right after it
+        let attack = schema.actions.iter().find(|(name, _)| name == "Attack").unwrap().1;
+        assert_eq!(label, attack);
+
+        // The features of every player follow the schema
+        for (slot, player) in [(0, &players[2]), (1, &players[1]), (2, &players[0])] {
+            let offset = schema.players_offset + slot * schema.player_features.len();
+            for feature in schema.player_features.iter() {
+                let x = match feature.offset {
+                    0 => player.walls as ModelPrec,
+                    1 => player.soldiers as ModelPrec,
+                    2 => player.barracks as ModelPrec,
+                    3 => player.obelisks as ModelPrec,
+                    _ => player.defense as ModelPrec,
+                };
+                let expected = match feature.transform.as_str() {
+                    "x / scale" => x / feature.scale,
+                    "1 - exp(-x / scale)" => 1.0 - (-x / feature.scale).exp(),
+                    _ => (x > feature.scale) as u8 as ModelPrec,
+                };
+                assert!((input[offset + feature.offset] - expected).abs() < 1e-6, "{} of slot {}", feature.name, slot);
             }
         }
 
-        best_action
+        // The most recent action comes first, and the missing actions are `None`
+        let history = &input[schema.history_offset..];
+        assert_eq!(history[2], 1.0);
+        assert_eq!(history[schema.max_actions + 1], 1.0);
+        assert_eq!(history[2 * schema.max_actions], 1.0);
+
+        assert!(encoder.check_version(Some(ENCODER_VERSION)).is_ok());
+        assert!(encoder.check_version(Some(ENCODER_VERSION + 1)).is_err());
+
+        // The models that don't record their version are refused
+        let unversioned = tract_onnx::pb::ModelProto::default();
+        assert_eq!(encoder_version(&unversioned).unwrap(), None);
+        let error = model_for_proto(&unversioned).err().unwrap();
+        assert!(error.to_string().contains("doesn't record its encoder version"), "{}", error);
+
+        let mut proto = tract_onnx::pb::ModelProto::default();
+        proto.metadata_props.push(tract_onnx::pb::StringStringEntryProto {
+            key: ENCODER_VERSION_KEY.to_string(),
+            value: (ENCODER_VERSION + 1).to_string(),
+        });
+        assert_eq!(encoder_version(&proto).unwrap(), Some(ENCODER_VERSION + 1));
+        assert!(model_for_proto(&proto).is_err());
     }
 }
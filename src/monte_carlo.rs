@@ -1,119 +1,446 @@
 use super::*;
-use rand::Rng;
+use super::outcome::*;
+use super::objective::Objective;
+use super::uncertain::*;
+use super::plan::{RoundConstraint, find_constraint};
+use rand::rngs::ThreadRng;
 
 // The monte_carlo function approximates the loss of an action by running a lot of random games and averaging their results, using monte carlo's approximation
-// It then returns the approximated loss and the loss variance (σ²)
+// It then returns, for each player of interest, the approximated loss, the loss variance (σ²) and the requested statistics about the outcome of the games
+// mc_best_action picks the action with the best outcome, either the one with the lowest mean loss or, to avoid risky actions,
+// according to a risk-sensitive criterion (see `Criterion`)
+// The simulated games are played by a `Rollout`: the actions of the first simulated round are chosen by its `first_ai`,
+// which is given the history of each player, while the remaining rounds are chosen by its `ai`; this allows using a slower,
+// more accurate policy for the current turn only.
+// Both can also be `RolloutAi`s, whose behaviour is drawn at the start of each simulated game; to play the first round
+// with the same AI and the same draw as the rest of the game, pass `SameAi` as `first_ai`.
 
 pub trait AiFn<'x, R: 'x> = Fn(&'x [Player], usize, usize, &'x [Action], &'x mut R) -> Action;
 
-pub fn mc_best_action<Ai, Loss>(
+/// An AI whose behaviour may change from one simulated game to another: at the start of every game, `new_game` draws
+/// the state of the game (for instance, which policy each player follows), which is then given to every call to `action`.
+/// Every `AiFn` is a `RolloutAi` that behaves the same way in every game.
+pub trait RolloutAi {
+    type Game;
+
+    fn new_game(&self, players: &[Player], rng: &mut ThreadRng) -> Self::Game;
+
+    fn action(
+        &self,
+        game: &Self::Game,
+        players: &[Player],
+        index: usize,
+        round: usize,
+        previous_actions: &[Action],
+        rng: &mut ThreadRng,
+    ) -> Action;
+
+    /// Draws the actions of every player during `round`, `previous_actions` being the history of every player.
+    /// By default, `action` is called for each player; AIs backed by the model override it to predict every player at once.
+    fn actions(
+        &self,
+        game: &Self::Game,
+        players: &[Player],
+        round: usize,
+        previous_actions: &[Vec<Action>],
+        rng: &mut ThreadRng,
+    ) -> Vec<Action> {
+        (0..players.len())
+            .map(|n| self.action(game, players, n, round, &previous_actions[n], rng))
+            .collect()
+    }
+}
+
+impl<F> RolloutAi for F
+where
+    F: for<'c> AiFn<'c, ThreadRng>,
+{
+    type Game = ();
+
+    #[inline]
+    fn new_game(&self, _players: &[Player], _rng: &mut ThreadRng) {}
+
+    #[inline]
+    fn action(
+        &self,
+        _game: &(),
+        players: &[Player],
+        index: usize,
+        round: usize,
+        previous_actions: &[Action],
+        rng: &mut ThreadRng,
+    ) -> Action {
+        self(players, index, round, previous_actions, rng)
+    }
+}
+
+/// The AI that plays the first simulated round (see `monte_carlo`). Any `RolloutAi` can play it, with a game of its own
+/// drawn for that round; `SameAi` plays it with the rollout AI instead, within the game that the rollout AI drew, so that
+/// an AI whose behaviour is drawn per game (like `PosteriorAi`) behaves the same way during the whole simulated game.
+pub trait FirstAi<Ai: RolloutAi> {
+    /// Draws the actions of every player during the first round; `game` is the game that `ai` drew for the rollout
+    fn first_actions(
+        &self,
+        ai: &Ai,
+        game: &Ai::Game,
+        players: &[Player],
+        round: usize,
+        previous_actions: &[Vec<Action>],
+        rng: &mut ThreadRng,
+    ) -> Vec<Action>;
+
+    /// Returns true if the first round is played by the rollout AI, within the game it drew (see `SameAi`)
+    fn is_rollout_ai(&self) -> bool {
+        false
+    }
+}
+
+impl<F: RolloutAi, Ai: RolloutAi> FirstAi<Ai> for F {
+    #[inline]
+    fn first_actions(
+        &self,
+        _ai: &Ai,
+        _game: &Ai::Game,
+        players: &[Player],
+        round: usize,
+        previous_actions: &[Vec<Action>],
+        rng: &mut ThreadRng,
+    ) -> Vec<Action> {
+        let game = self.new_game(players, rng);
+
+        self.actions(&game, players, round, previous_actions, rng)
+    }
+}
+
+/// Plays the first simulated round with the rollout AI, and the game it drew
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SameAi;
+
+impl<Ai: RolloutAi> FirstAi<Ai> for SameAi {
+    #[inline]
+    fn first_actions(
+        &self,
+        ai: &Ai,
+        game: &Ai::Game,
+        players: &[Player],
+        round: usize,
+        previous_actions: &[Vec<Action>],
+        rng: &mut ThreadRng,
+    ) -> Vec<Action> {
+        ai.actions(game, players, round, previous_actions, rng)
+    }
+
+    #[inline]
+    fn is_rollout_ai(&self) -> bool {
+        true
+    }
+}
+
+/// How far the games are simulated, and how the loss of a simulated game is measured
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Horizon {
+    /// Simulates until a player wins or until `max_rounds` rounds have been simulated, and measures the loss at the end
+    #[default]
+    GameEnd,
+    /// Simulates `n` rounds (including the current turn), and measures the loss at the end
+    Rounds(usize),
+    /// Simulates `rounds` rounds and measures the loss after every round; the loss of the game is the average of these losses,
+    /// the loss after round `t` being weighted by `gamma^t`. If the game ends earlier, its final loss is used for the remaining rounds.
+    Discounted { rounds: usize, gamma: f64 },
+}
+
+impl Horizon {
+    /// Returns the number of rounds to simulate, including the current turn; the games never go past `max_rounds`
+    pub fn rounds(&self, max_rounds: usize) -> usize {
+        match self {
+            Horizon::GameEnd => max_rounds,
+            Horizon::Rounds(rounds) | Horizon::Discounted { rounds, .. } => (*rounds).clamp(1, max_rounds.max(1)),
+        }
+    }
+}
+
+impl std::str::FromStr for Horizon {
+    type Err = String;
+
+    /// Parses `end`, `rounds:<n>` or `discounted:<rounds>:<gamma>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split(':').collect::<Vec<_>>();
+        let error = || format!("Invalid horizon: {}", s);
+
+        match parts[..] {
+            ["end"] => Ok(Horizon::GameEnd),
+            ["rounds", rounds] => Ok(Horizon::Rounds(rounds.parse().map_err(|_| error())?)),
+            ["discounted", rounds, gamma] => {
+                let rounds = rounds.parse().map_err(|_| error())?;
+                let gamma = gamma.parse::<f64>().map_err(|_| error())?;
+                if gamma > 0.0 && gamma <= 1.0 {
+                    Ok(Horizon::Discounted { rounds, gamma })
+                } else {
+                    Err(error())
+                }
+            }
+            _ => Err(error()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonteCarloSettings {
+    pub samples: usize,
+    /// Maximum number of simulated rounds, including the current turn; see `horizon`
+    pub max_rounds: usize,
+    pub round_offset: usize,
+    pub horizon: Horizon,
+    /// Statistics to collect on top of the mean loss and its variance
+    pub statistics: Vec<Statistic>,
+    /// How mc_best_action picks the best action
+    pub criterion: Criterion,
+    /// If not empty, a concrete state of the game is drawn from these players at the start of every sample,
+    /// instead of using the given `players`
+    pub uncertain_players: Vec<UncertainPlayer>,
+    /// Actions that players are forced to take during later rounds (see `plan`); during the first round,
+    /// the constraints given to monte_carlo take precedence over these
+    pub round_constraints: Vec<RoundConstraint>,
+}
+
+impl Default for MonteCarloSettings {
+    fn default() -> Self {
+        Self {
+            samples: 1000,
+            max_rounds: 50,
+            round_offset: 0,
+            horizon: Horizon::GameEnd,
+            statistics: Vec::new(),
+            criterion: Criterion::Mean,
+            uncertain_players: Vec::new(),
+            round_constraints: Vec::new(),
+        }
+    }
+}
+
+impl MonteCarloSettings {
+    /// The requested statistics, along with the ones needed by the criterion
+    pub fn collected_statistics(&self) -> Vec<Statistic> {
+        let mut res = self.statistics.clone();
+
+        for statistic in self.criterion.statistics() {
+            if !res.contains(&statistic) {
+                res.push(statistic);
+            }
+        }
+
+        res
+    }
+}
+
+/// The AIs that play the simulated games of `monte_carlo`, and the objective that measures them
+#[derive(Debug, Clone, Copy)]
+pub struct Rollout<First, Ai, Loss> {
+    /// Plays the first round, given the history of every player (see `FirstAi`)
+    pub first_ai: First,
+    /// Plays the remaining rounds
+    pub ai: Ai,
+    pub compute_loss: Loss,
+}
+
+pub fn mc_best_action<First, Ai, Loss>(
     players: &[Player],
     index: usize,
     previous_actions: &[Vec<Action>],
     mut constraints: Vec<(usize, Action)>,
-    samples: usize,
-    max_rounds: usize,
-    round_offset: usize,
-    ai: Ai,
-    compute_loss: Loss
-) -> (Action, Vec<(Action, f64, f64)>)
+    settings: &MonteCarloSettings,
+    rollout: Rollout<First, Ai, Loss>,
+) -> (Action, Vec<(Action, Outcome)>)
 where
-    Ai: for<'c> AiFn<'c, rand::rngs::ThreadRng> + Copy,
-    Loss: for<'c> Fn(&'c [Player], usize) -> f64 + Copy,
+    First: FirstAi<Ai> + Copy,
+    Ai: RolloutAi + Copy,
+    Loss: Objective + Copy,
 {
     assert!(previous_actions.len() == players.len());
 
     let iter = players.iter().enumerate().filter(|(n, _p)| *n != index);
 
-    let mut best = (f64::INFINITY, Action::None);
-    let mut actions = Vec::new();
-
-    let compute_loss = move |players: &[Player]| compute_loss(players, index);
+    let mut best: Option<(Action, usize)> = None;
+    let mut actions: Vec<(Action, Outcome)> = Vec::new();
 
     constraints.push((index, Action::None));
     for action in players[index].possible_actions(iter) {
         if action == Action::None {
-            actions.push((Action::None, f64::INFINITY, 0.0));
+            actions.push((Action::None, Outcome::new(&settings.collected_statistics())));
             continue;
         }
 
         *constraints.last_mut().unwrap() = (index, action);
 
-        let (loss, variance) = monte_carlo(players, previous_actions, &constraints, samples, max_rounds, round_offset, ai, compute_loss);
-
-        actions.push((action, loss, variance));
+        let outcome = monte_carlo(players, previous_actions, &constraints, &[index], settings, rollout)
+            .pop()
+            .unwrap();
 
-        if loss < best.0 {
-            best = (loss, action);
+        let is_better = match best {
+            Some((_, n)) => outcome.better_than(&actions[n].1, settings.criterion),
+            None => true,
+        };
+        if is_better {
+            best = Some((action, actions.len()));
         }
+
+        actions.push((action, outcome));
     }
 
-    (best.1, actions)
+    (best.map(|(action, _)| action).unwrap_or(Action::None), actions)
 }
 
-pub fn monte_carlo<Ai, Loss>(
+/// Simulates `settings.samples` games and measures the outcome of these games for each player in `indices`.
+/// The returned outcomes are in the same order as `indices`.
+pub fn monte_carlo<First, Ai, Loss>(
     players: &[Player],
     previous_actions: &[Vec<Action>],
     constraints: &[(usize, Action)],
-    samples: usize,
-    max_rounds: usize,
-    round_offset: usize,
-    ai: Ai,
-    compute_loss: Loss,
-) -> (f64, f64)
+    indices: &[usize],
+    settings: &MonteCarloSettings,
+    rollout: Rollout<First, Ai, Loss>,
+) -> Vec<Outcome>
 where
-    Ai: for<'c> AiFn<'c, rand::rngs::ThreadRng> + Copy,
-    Loss: for<'c> Fn(&'c [Player]) -> f64,
+    First: FirstAi<Ai> + Copy,
+    Ai: RolloutAi + Copy,
+    Loss: Objective,
 {
+    monte_carlo_observed(players, previous_actions, constraints, indices, settings, rollout, |_, _, _, _| {})
+}
+
+/// Like `monte_carlo`, but `observe` is also called after every simulated game, with the initial state of that game,
+/// the history of every player (including the simulated actions), the final state and the loss of each player in `indices`
+pub fn monte_carlo_observed<First, Ai, Loss>(
+    players: &[Player],
+    previous_actions: &[Vec<Action>],
+    constraints: &[(usize, Action)],
+    indices: &[usize],
+    settings: &MonteCarloSettings,
+    rollout: Rollout<First, Ai, Loss>,
+    mut observe: impl FnMut(&[Player], &[Vec<Action>], &[Player], &[f64]),
+) -> Vec<Outcome>
+where
+    First: FirstAi<Ai> + Copy,
+    Ai: RolloutAi + Copy,
+    Loss: Objective,
+{
+    let Rollout { first_ai, ai, compute_loss } = rollout;
     let mut rng = rand::thread_rng();
 
-    let mut sum = 0.0;
-    let mut sum_square = 0.0; // used to compute the variance with O(1) memory
+    let mut outcomes = vec![Outcome::new(&settings.collected_statistics()); indices.len()];
 
-    for _n in 0..samples {
-        let players = players.iter().cloned().collect::<Vec<_>>();
-        let mut actions = vec![Action::Skip; players.len()];
+    let rounds = settings.horizon.rounds(settings.max_rounds);
+    let total_weight = match settings.horizon {
+        Horizon::Discounted { gamma, .. } => (0..rounds).map(|round| gamma.powi(round as i32)).sum::<f64>(),
+        _ => 1.0,
+    };
+    let mut discounted = vec![0.0; indices.len()];
+    let mut losses = vec![0.0; indices.len()];
 
-        for n in 0..players.len() {
-            actions[n] = ai(&players, n, round_offset, &[], &mut rng);
+    for _n in 0..settings.samples {
+        let sampled;
+        let players = if settings.uncertain_players.is_empty() {
+            players
+        } else {
+            sampled = sample_players(&settings.uncertain_players, &mut rng);
+            &sampled
+        };
+
+        let game = ai.new_game(players, &mut rng);
+
+        let mut actions = first_ai.first_actions(&ai, &game, players, settings.round_offset, previous_actions, &mut rng);
+
+        for constraint in settings.round_constraints.iter().filter(|c| c.round == 0) {
+            actions[constraint.player] = constraint.action.resolve(players, constraint.player);
         }
 
         for (index, action) in constraints.iter().copied() {
             actions[index] = action;
         }
 
-        let final_state = simulate(players, actions, previous_actions, ai, &mut rng, max_rounds, round_offset);
-        let loss = compute_loss(&final_state);
+        // The constrained actions might not be possible in the drawn state (for instance, attacking without soldiers)
+        if !settings.uncertain_players.is_empty() {
+            for (index, action) in constraints.iter().copied() {
+                let possible = players[index].possible_actions(players.iter().enumerate().filter(|(n, _p)| *n != index));
+                if !possible.contains(&action) {
+                    actions[index] = Action::Skip;
+                }
+            }
+        }
+
+        let next = update(players.to_vec(), &actions);
+        let mut last_round = 0;
+        discounted.iter_mut().for_each(|loss| *loss = 0.0);
+
+        let drawn = DrawnGame { ai, game: &game };
+        let (final_state, history) = simulate(next.clone(), actions, previous_actions, drawn, &mut rng, settings, |round, state| {
+            last_round = round;
+            if let Horizon::Discounted { gamma, .. } = settings.horizon {
+                for (loss, index) in discounted.iter_mut().zip(indices.iter().copied()) {
+                    *loss += gamma.powi(round as i32) * compute_loss.loss(state, index);
+                }
+            }
+        });
 
-        sum += loss;
-        sum_square += loss * loss;
+        for (((outcome, index), discounted), res) in outcomes.iter_mut().zip(indices.iter().copied()).zip(discounted.iter()).zip(losses.iter_mut()) {
+            let loss = match settings.horizon {
+                Horizon::Discounted { gamma, .. } => {
+                    let remaining = (last_round + 1..rounds).map(|round| gamma.powi(round as i32)).sum::<f64>();
+                    (discounted + remaining * compute_loss.loss_with_history(&final_state, index, &history)) / total_weight
+                }
+                _ => compute_loss.loss_with_history(&final_state, index, &history),
+            };
+            outcome.record(players, &next, &final_state, index, loss);
+            outcome.sample_trajectory(&mut rng, || Trajectory {
+                actions: simulated_actions(&history, previous_actions),
+                final_state: final_state.clone(),
+                loss,
+            });
+            *res = loss;
+        }
+
+        observe(players, &history, &final_state, &losses);
     }
 
-    sum /= samples as f64;
-    sum_square /= samples as f64;
+    outcomes
+}
 
-    (sum, sum_square - sum * sum)
+// Returns the actions taken during each simulated round, as `actions[round][player]`, from the history of every player
+fn simulated_actions(history: &[Vec<Action>], previous_actions: &[Vec<Action>]) -> Vec<Vec<Action>> {
+    let rounds = history.first().map(|actions| actions.len() - previous_actions[0].len()).unwrap_or(0);
+
+    (0..rounds).map(|round| {
+        history.iter().zip(previous_actions.iter()).map(|(actions, previous)| actions[previous.len() + round]).collect()
+    }).collect()
+}
+
+// The rollout AI of a simulated game, along with the game that it drew at the start of that game
+struct DrawnGame<'g, Ai: RolloutAi> {
+    ai: Ai,
+    game: &'g Ai::Game,
 }
 
+// Simulates the rest of a game, `players` being the state after the first round and `actions` the actions taken during that round
+// `observe` is called with the state after every simulated round, starting with `players` for round 0
+// Returns the final state and the history of every player, including the simulated actions
 #[inline]
-fn simulate<Ai, R>(
+fn simulate<Ai>(
     mut players: Vec<Player>,
     mut actions: Vec<Action>,
     previous_actions: &[Vec<Action>],
-    ai: Ai,
-    rng: &mut R,
-    max_rounds: usize,
-    round_offset: usize,
-) -> Vec<Player>
+    drawn: DrawnGame<Ai>,
+    rng: &mut ThreadRng,
+    settings: &MonteCarloSettings,
+    mut observe: impl FnMut(usize, &[Player]),
+) -> (Vec<Player>, Vec<Vec<Action>>)
 where
-    R: Rng,
-    Ai: for<'c> AiFn<'c, R>,
+    Ai: RolloutAi,
 {
-    players = update(players, &actions);
+    let rounds = settings.horizon.rounds(settings.max_rounds);
 
     let mut prev = Vec::with_capacity(players.len());
     for (n, &a) in actions.iter().enumerate() {
-        let mut vec = Vec::with_capacity(max_rounds);
+        let mut vec = Vec::with_capacity(previous_actions[n].len() + rounds);
         for &action in previous_actions[n].iter() {
             vec.push(action);
         }
@@ -121,19 +448,99 @@ where
         prev.push(vec);
     }
 
-    for round in 1..max_rounds {
+    observe(0, &players);
+
+    for round in 1..rounds {
         if players.iter().any(|p| p.won()) {
             break;
         }
 
+        actions = drawn.ai.actions(drawn.game, &players, round + settings.round_offset, &prev, rng);
         for n in 0..players.len() {
-            let prev = &mut prev[n];
-            actions[n] = ai(&players, n, round + round_offset, &*prev, rng);
-            prev.push(actions[n]);
+            if let Some(action) = find_constraint(&settings.round_constraints, round, n) {
+                actions[n] = action.resolve(&players, n);
+            }
+            prev[n].push(actions[n]);
         }
 
         players = update(players, &actions);
+        observe(round, &players);
+    }
+
+    (players, prev)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::objective::ValueFn;
+
+    #[test]
+    fn test_horizon() {
+        assert_eq!("rounds:3".parse::<Horizon>(), Ok(Horizon::Rounds(3)));
+        assert_eq!("discounted:10:0.9".parse::<Horizon>(), Ok(Horizon::Discounted { rounds: 10, gamma: 0.9 }));
+        assert!("discounted:10".parse::<Horizon>().is_err());
+
+        let players = vec![Player::new(); 2];
+        let previous_actions = vec![vec![]; 2];
+        let ai = |_p: &[Player], _index: usize, _round: usize, _previous: &[Action], _rng: &mut ThreadRng| Action::Skip;
+        let soldiers = ValueFn(|players: &[Player], index: usize| players[index].soldiers as f64);
+
+        let loss = |horizon| {
+            let settings = MonteCarloSettings {
+                samples: 1,
+                horizon,
+                ..Default::default()
+            };
+            monte_carlo(&players, &previous_actions, &[], &[0], &settings, Rollout { first_ai: ai, ai, compute_loss: soldiers }).pop().unwrap().mean()
+        };
+
+        assert_eq!(loss(Horizon::Rounds(1)), 2.0);
+        assert_eq!(loss(Horizon::Rounds(3)), 4.0);
+        assert!((loss(Horizon::Discounted { rounds: 3, gamma: 0.5 }) - 4.5 / 1.75).abs() < 1e-9);
+
+        // The horizon doesn't go past the end of the game
+        assert_eq!(Horizon::Rounds(10).rounds(4), 4);
+        assert_eq!(Horizon::Discounted { rounds: 10, gamma: 0.9 }.rounds(4), 4);
+        assert_eq!(Horizon::Rounds(0).rounds(4), 1);
+    }
+
+    // Builds walls or recruits for the whole game, as drawn at its start
+    #[derive(Clone, Copy)]
+    struct Coin;
+
+    impl RolloutAi for Coin {
+        type Game = bool;
+
+        fn new_game(&self, _players: &[Player], rng: &mut ThreadRng) -> bool {
+            rand::Rng::gen(rng)
+        }
+
+        fn action(&self, game: &bool, _players: &[Player], _index: usize, _round: usize, _previous: &[Action], _rng: &mut ThreadRng) -> Action {
+            if *game { Action::Wall } else { Action::Recruit }
+        }
     }
 
-    players
+    #[test]
+    fn test_same_ai() {
+        let players = vec![Player::new(); 2];
+        let previous_actions = vec![vec![]; 2];
+        let settings = MonteCarloSettings {
+            samples: 64,
+            horizon: Horizon::Rounds(3),
+            ..Default::default()
+        };
+        let soldiers = ValueFn(|players: &[Player], index: usize| players[index].soldiers as f64);
+
+        // With `SameAi`, the first round is played within the game drawn for the rollout
+        let mut mixed = 0;
+        monte_carlo_observed(&players, &previous_actions, &[], &[0], &settings, Rollout { first_ai: SameAi, ai: Coin, compute_loss: soldiers }, |_, history, _, _| {
+            assert!(history[0].iter().all(|action| *action == history[0][0]));
+        });
+        // Otherwise, the first round draws a game of its own
+        monte_carlo_observed(&players, &previous_actions, &[], &[0], &settings, Rollout { first_ai: Coin, ai: Coin, compute_loss: soldiers }, |_, history, _, _| {
+            mixed += (history[0][0] != history[0][1]) as usize;
+        });
+        assert!(mixed > 0);
+    }
 }
@@ -0,0 +1,265 @@
+// Objectives measure how good the end of a (simulated) game is for a player, as a loss to minimize.
+// They are used by monte carlo, by the training data generation and by `evaluate`.
+use super::*;
+use std::path::Path;
+
+pub trait Objective {
+    /// Returns the loss of the player `index` in the state `players`; the lower, the better
+    fn loss(&self, players: &[Player], index: usize) -> f64;
+
+    /// Returns the loss of the player `index` at the end of a simulated game, `history` being the actions of every player
+    /// since the start of the game; only the objectives that need the history (like `ModelValue`) override it
+    #[inline]
+    fn loss_with_history(&self, players: &[Player], index: usize, _history: &[Vec<Action>]) -> f64 {
+        self.loss(players, index)
+    }
+}
+
+impl<O: Objective + ?Sized> Objective for &O {
+    #[inline]
+    fn loss(&self, players: &[Player], index: usize) -> f64 {
+        (**self).loss(players, index)
+    }
+
+    #[inline]
+    fn loss_with_history(&self, players: &[Player], index: usize, history: &[Vec<Action>]) -> f64 {
+        (**self).loss_with_history(players, index, history)
+    }
+}
+
+/// The hand-tuned mix of obelisk, barrack, soldier and wall differentials, plus siege bonuses and penalties
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Heuristic;
+
+impl Objective for Heuristic {
+    #[inline]
+    fn loss(&self, players: &[Player], index: usize) -> f64 {
+        heuristic_loss(players, index)
+    }
+}
+
+pub fn heuristic_loss(players: &[Player], index: usize) -> f64 {
+    let iter = players.iter().enumerate().filter(|(n, _p)| *n != index);
+
+    let (max_obelisks, max_barracks, max_soldiers, max_walls) = iter
+        .map(|(_, x)| {
+            (
+                x.obelisks as f64,
+                x.barracks as f64,
+                x.soldiers as f64,
+                x.walls as f64,
+            )
+        })
+        .reduce(|acc, act| {
+            (
+                acc.0.max(act.0),
+                acc.1.max(act.1),
+                acc.2.max(act.2),
+                acc.3.max(act.3),
+            )
+        })
+        .unwrap_or((0.0, 0.0, 0.0, 0.0));
+
+    let player = &players[index];
+
+    let mut res = (10.0 + max_obelisks) / 2.0 - player.obelisks as f64
+        + (max_barracks - player.barracks as f64) / 5.0
+        + (max_soldiers - player.soldiers as f64) / 10.0
+        + (max_walls - player.walls as f64) / 5.0;
+
+    if player.obelisks == 0 {
+        res += 4.0;
+    }
+
+    if max_obelisks == 10.0 {
+        res += 2.0; // penalty for not winning the game first
+    }
+
+    res += player.defeats as f64 * 0.75; // penalty for losing sieges
+    res += (player.victories as f64 * -0.5).exp() * 3.0; // bonus for winning sieges
+
+    res
+}
+
+// Iterates over the other players that are competing in the game (targets aren't)
+#[inline]
+fn opponents(players: &[Player], index: usize) -> impl Iterator<Item = &Player> {
+    players.iter().enumerate().filter(move |(n, p)| *n != index && !p.is_target).map(|(_, p)| p)
+}
+
+/// 0 if the player has won, 1 otherwise: the mean loss is the probability of not winning
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Win;
+
+impl Objective for Win {
+    #[inline]
+    fn loss(&self, players: &[Player], index: usize) -> f64 {
+        if players[index].won() { 0.0 } else { 1.0 }
+    }
+}
+
+/// The placement of the player, ranked by their number of obelisks: 1 for the first place, 2 for the second, etc.
+/// Ties are split evenly between the tied players.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Placement;
+
+impl Objective for Placement {
+    #[inline]
+    fn loss(&self, players: &[Player], index: usize) -> f64 {
+        let obelisks = players[index].obelisks;
+
+        let better = opponents(players, index).filter(|p| p.obelisks > obelisks).count();
+        let ties = opponents(players, index).filter(|p| p.obelisks == obelisks).count();
+
+        1.0 + better as f64 + ties as f64 / 2.0
+    }
+}
+
+/// 1 minus the share of the obelisks in the game that the player owns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ObeliskShare;
+
+impl Objective for ObeliskShare {
+    #[inline]
+    fn loss(&self, players: &[Player], index: usize) -> f64 {
+        let obelisks = players[index].obelisks as f64;
+        let total = obelisks + opponents(players, index).map(|p| p.obelisks as f64).sum::<f64>();
+
+        if total == 0.0 {
+            1.0
+        } else {
+            1.0 - obelisks / total
+        }
+    }
+}
+
+/// 1 if the player is last (or tied for the last place) in number of obelisks, 0 otherwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NotLast;
+
+impl Objective for NotLast {
+    #[inline]
+    fn loss(&self, players: &[Player], index: usize) -> f64 {
+        let obelisks = players[index].obelisks;
+
+        if opponents(players, index).any(|p| p.obelisks < obelisks) { 0.0 } else { 1.0 }
+    }
+}
+
+/// Uses `objective` for the games that ended, and the estimate `value` for the games that were cut short by the horizon
+/// of the simulation (see `Horizon`); for instance, `Win` can't tell apart two unfinished games, while `Heuristic` can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Bootstrapped<O, V> {
+    pub objective: O,
+    pub value: V,
+}
+
+impl<O: Objective, V: Objective> Objective for Bootstrapped<O, V> {
+    #[inline]
+    fn loss(&self, players: &[Player], index: usize) -> f64 {
+        if players.iter().any(|p| p.won()) {
+            self.objective.loss(players, index)
+        } else {
+            self.value.loss(players, index)
+        }
+    }
+
+    #[inline]
+    fn loss_with_history(&self, players: &[Player], index: usize, history: &[Vec<Action>]) -> f64 {
+        if players.iter().any(|p| p.won()) {
+            self.objective.loss_with_history(players, index, history)
+        } else {
+            self.value.loss_with_history(players, index, history)
+        }
+    }
+}
+
+/// Turns a function (like a value estimate) into an objective
+#[derive(Debug, Clone, Copy)]
+pub struct ValueFn<F>(pub F);
+
+impl<F: Fn(&[Player], usize) -> f64> Objective for ValueFn<F> {
+    #[inline]
+    fn loss(&self, players: &[Player], index: usize) -> f64 {
+        (self.0)(players, index)
+    }
+}
+
+/// An objective that can be loaded from a configuration file, like `objective.json`
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub enum ObjectiveConfig {
+    #[default]
+    Heuristic,
+    Win,
+    Placement,
+    ObeliskShare,
+    NotLast,
+    /// A weighted sum of objectives
+    Weighted(Vec<(f64, ObjectiveConfig)>),
+    /// See `Bootstrapped`
+    Bootstrapped { objective: Box<ObjectiveConfig>, value: Box<ObjectiveConfig> },
+}
+
+impl ObjectiveConfig {
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        let raw = std::fs::read_to_string(path).unwrap_or_else(|_| panic!("Couldn't open {}", path.display()));
+
+        serde_json::from_str(&raw).unwrap_or_else(|_| panic!("Couldn't parse {}", path.display()))
+    }
+}
+
+impl Objective for ObjectiveConfig {
+    fn loss(&self, players: &[Player], index: usize) -> f64 {
+        match self {
+            ObjectiveConfig::Heuristic => Heuristic.loss(players, index),
+            ObjectiveConfig::Win => Win.loss(players, index),
+            ObjectiveConfig::Placement => Placement.loss(players, index),
+            ObjectiveConfig::ObeliskShare => ObeliskShare.loss(players, index),
+            ObjectiveConfig::NotLast => NotLast.loss(players, index),
+            ObjectiveConfig::Weighted(objectives) => objectives
+                .iter()
+                .map(|(weight, objective)| weight * objective.loss(players, index))
+                .sum(),
+            ObjectiveConfig::Bootstrapped { objective, value } => Bootstrapped { objective: &**objective, value: &**value }.loss(players, index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_objectives() {
+        let players = vec![
+            Player::with_values(1, 1, 1, 3, 0),
+            Player::with_values(1, 1, 1, 2, 0),
+            Player::with_values(1, 1, 1, 2, 0),
+            Player::with_values(1, 1, 1, 0, 0),
+            Player::with_values(1, 0, 1, 5, 0).make_target(),
+        ];
+
+        assert_eq!(Placement.loss(&players, 0), 1.0);
+        assert_eq!(Placement.loss(&players, 1), 2.5);
+        assert_eq!(Placement.loss(&players, 3), 4.0);
+
+        assert_eq!(NotLast.loss(&players, 1), 0.0);
+        assert_eq!(NotLast.loss(&players, 3), 1.0);
+
+        assert_eq!(ObeliskShare.loss(&players, 0), 1.0 - 3.0 / 7.0);
+        assert_eq!(Win.loss(&players, 0), 1.0);
+
+        let config: ObjectiveConfig = serde_json::from_str(r#"{"Weighted": [[1.0, "Win"], [0.5, "NotLast"]]}"#).unwrap();
+        assert_eq!(config.loss(&players, 3), 1.5);
+        assert_eq!(config.loss(&players, 0), 1.0);
+
+        let config: ObjectiveConfig = serde_json::from_str(r#"{"Bootstrapped": {"objective": "Win", "value": "NotLast"}}"#).unwrap();
+        assert_eq!(config.loss(&players, 0), 0.0);
+
+        let mut players = players;
+        players[0].obelisks = 10;
+        assert_eq!(config.loss(&players, 0), 0.0);
+        assert_eq!(config.loss(&players, 1), 1.0);
+    }
+}
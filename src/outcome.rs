@@ -0,0 +1,442 @@
+// Statistics about the outcomes of the games simulated by monte carlo
+// The mean loss and its variance are always collected; the other statistics are only collected when requested,
+// since some of them (like the quantiles) need to keep every sample in memory.
+use super::*;
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Statistic {
+    /// Probability of having won the game at the end of the simulation
+    Win,
+    /// Probability of having no obelisks left at the end of the simulation
+    Elimination,
+    /// Probability of being sieged during the first simulated round (the current turn)
+    SiegedNextTurn,
+    /// Probability of being sieged (and thus losing an obelisk) at least once during the simulation
+    ObeliskLoss,
+    /// Distribution of the number of obelisks at the end of the simulation
+    Obelisks,
+    /// Quantiles of the loss, in `[0, 1]`
+    LossQuantiles(Vec<f64>),
+    /// Keeps the loss of every sample
+    Losses,
+    /// Keeps a uniform sample of `n` of the simulated games, with every action taken and their final state
+    Trajectories(usize),
+}
+
+impl Statistic {
+    /// The statistics printed by `evaluate`
+    pub fn all() -> Vec<Statistic> {
+        vec![
+            Statistic::Win,
+            Statistic::Elimination,
+            Statistic::SiegedNextTurn,
+            Statistic::ObeliskLoss,
+            Statistic::Obelisks,
+            Statistic::LossQuantiles(vec![0.05, 0.25, 0.5, 0.75, 0.95]),
+        ]
+    }
+}
+
+/// How to pick the best action among the outcomes of monte carlo
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Criterion {
+    /// Minimize the mean loss
+    #[default]
+    Mean,
+    /// Minimize the mean of the worst `alpha` fraction of the losses (conditional value at risk)
+    CVaR(f64),
+    /// Minimize `mean + λ·σ`
+    MeanStd(f64),
+    /// Maximize the probability of not losing an obelisk
+    Safety,
+}
+
+impl Criterion {
+    /// The statistics that need to be collected to compute this criterion
+    pub fn statistics(&self) -> Vec<Statistic> {
+        match self {
+            Criterion::Mean | Criterion::MeanStd(_) => vec![],
+            Criterion::CVaR(_) => vec![Statistic::Losses],
+            Criterion::Safety => vec![Statistic::ObeliskLoss],
+        }
+    }
+
+    /// Returns true if the criterion only depends on statistics that can be summed across runs (see `CachedOutcome`),
+    /// so that it can rank outcomes topped up with the samples of previous runs
+    pub fn is_summable(&self) -> bool {
+        !matches!(self, Criterion::CVaR(_))
+    }
+}
+
+impl std::str::FromStr for Criterion {
+    type Err = String;
+
+    /// Parses `mean`, `cvar:<alpha>`, `meanstd:<lambda>` or `safety`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, parameter) = match s.split_once(':') {
+            Some((name, parameter)) => {
+                let parameter = parameter.parse::<f64>().map_err(|_| format!("Invalid parameter: {}", parameter))?;
+                (name, Some(parameter))
+            }
+            None => (s, None),
+        };
+
+        match (name, parameter) {
+            ("mean", None) => Ok(Criterion::Mean),
+            ("cvar", Some(alpha)) if alpha > 0.0 && alpha <= 1.0 => Ok(Criterion::CVaR(alpha)),
+            ("meanstd", Some(lambda)) => Ok(Criterion::MeanStd(lambda)),
+            ("safety", None) => Ok(Criterion::Safety),
+            _ => Err(format!("Invalid criterion: {}", s)),
+        }
+    }
+}
+
+/// A simulated game
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trajectory {
+    /// The actions of every player during each simulated round, as `actions[round][player]`
+    pub actions: Vec<Vec<Action>>,
+    pub final_state: Vec<Player>,
+    pub loss: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub samples: usize,
+    pub sum: f64,
+    pub sum_square: f64, // used to compute the variance with O(1) memory
+
+    pub statistics: Vec<Statistic>,
+    pub wins: usize,
+    pub eliminations: usize,
+    pub sieged_next_turn: usize,
+    pub obelisk_losses: usize,
+    /// Number of samples ending with `n` obelisks, indexed by `n`
+    pub obelisks: Vec<usize>,
+    /// The loss of every sample, only kept if it is needed by the requested statistics
+    pub losses: Vec<f64>,
+    /// A reservoir sample of the simulated games
+    pub trajectories: Vec<Trajectory>,
+}
+
+impl Outcome {
+    pub fn new(statistics: &[Statistic]) -> Self {
+        Self {
+            samples: 0,
+            sum: 0.0,
+            sum_square: 0.0,
+
+            statistics: statistics.to_vec(),
+            wins: 0,
+            eliminations: 0,
+            sieged_next_turn: 0,
+            obelisk_losses: 0,
+            obelisks: Vec::new(),
+            losses: Vec::new(),
+            trajectories: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn collects(&self, statistic: &Statistic) -> bool {
+        self.statistics.iter().any(|s| std::mem::discriminant(s) == std::mem::discriminant(statistic))
+    }
+
+    #[inline]
+    fn keeps_losses(&self) -> bool {
+        self.collects(&Statistic::Losses) || self.collects(&Statistic::LossQuantiles(Vec::new()))
+    }
+
+    /// Records the outcome of a simulated game for the player `index`:
+    /// - `initial` is the state before the simulation
+    /// - `next` is the state after the first simulated round
+    /// - `final_state` is the state at the end of the simulation
+    pub fn record(&mut self, initial: &[Player], next: &[Player], final_state: &[Player], index: usize, loss: f64) {
+        self.samples += 1;
+        self.sum += loss;
+        self.sum_square += loss * loss;
+
+        if self.collects(&Statistic::Win) && final_state[index].won() {
+            self.wins += 1;
+        }
+
+        if self.collects(&Statistic::Elimination) && final_state[index].lost() {
+            self.eliminations += 1;
+        }
+
+        if self.collects(&Statistic::SiegedNextTurn) && next[index].defeats > initial[index].defeats {
+            self.sieged_next_turn += 1;
+        }
+
+        if self.collects(&Statistic::ObeliskLoss) && final_state[index].defeats > initial[index].defeats {
+            self.obelisk_losses += 1;
+        }
+
+        if self.collects(&Statistic::Obelisks) {
+            let obelisks = final_state[index].obelisks as usize;
+            if self.obelisks.len() <= obelisks {
+                self.obelisks.resize(obelisks + 1, 0);
+            }
+            self.obelisks[obelisks] += 1;
+        }
+
+        if self.keeps_losses() {
+            self.losses.push(loss);
+        }
+    }
+
+    #[inline]
+    fn max_trajectories(&self) -> usize {
+        self.statistics.iter().find_map(|statistic| match statistic {
+            Statistic::Trajectories(n) => Some(*n),
+            _ => None,
+        }).unwrap_or(0)
+    }
+
+    /// Keeps the game that was just recorded with `record` with reservoir sampling, so that every game has the same
+    /// chance of being kept; `trajectory` is only called if the game is kept
+    pub fn sample_trajectory(&mut self, rng: &mut impl rand::Rng, trajectory: impl FnOnce() -> Trajectory) {
+        let max = self.max_trajectories();
+
+        if self.trajectories.len() < max {
+            self.trajectories.push(trajectory());
+        } else if max > 0 {
+            let n = rng.gen_range(0..self.samples.max(1));
+            if n < max {
+                self.trajectories[n] = trajectory();
+            }
+        }
+    }
+
+    /// Merges the samples of `other` into `self`; both outcomes must collect the same statistics
+    pub fn merge(&mut self, other: &Outcome) {
+        debug_assert!(self.statistics == other.statistics);
+
+        self.merge_trajectories(other);
+
+        self.samples += other.samples;
+        self.sum += other.sum;
+        self.sum_square += other.sum_square;
+        self.wins += other.wins;
+        self.eliminations += other.eliminations;
+        self.sieged_next_turn += other.sieged_next_turn;
+        self.obelisk_losses += other.obelisk_losses;
+
+        if self.obelisks.len() < other.obelisks.len() {
+            self.obelisks.resize(other.obelisks.len(), 0);
+        }
+        for (n, count) in other.obelisks.iter().enumerate() {
+            self.obelisks[n] += count;
+        }
+
+        self.losses.extend(other.losses.iter().copied());
+    }
+
+    // Draws the trajectories of the merged outcome from both reservoirs, in proportion to their number of samples
+    fn merge_trajectories(&mut self, other: &Outcome) {
+        use rand::prelude::*;
+
+        let max = self.max_trajectories();
+        if other.trajectories.is_empty() {
+            return;
+        }
+
+        let mut rng = rand::thread_rng();
+        let mut ours = std::mem::take(&mut self.trajectories);
+        let mut theirs = other.trajectories.clone();
+        ours.shuffle(&mut rng);
+        theirs.shuffle(&mut rng);
+
+        let weight = self.samples as f64 / (self.samples + other.samples).max(1) as f64;
+        while self.trajectories.len() < max && !(ours.is_empty() && theirs.is_empty()) {
+            let trajectory = if theirs.is_empty() || !ours.is_empty() && rng.gen::<f64>() < weight {
+                ours.pop()
+            } else {
+                theirs.pop()
+            };
+            self.trajectories.extend(trajectory);
+        }
+    }
+
+    #[inline]
+    pub fn mean(&self) -> f64 {
+        if self.samples == 0 {
+            return f64::INFINITY;
+        }
+
+        self.sum / self.samples as f64
+    }
+
+    /// The variance (σ²) of the loss
+    #[inline]
+    pub fn variance(&self) -> f64 {
+        if self.samples == 0 {
+            return 0.0;
+        }
+
+        let mean = self.mean();
+        self.sum_square / self.samples as f64 - mean * mean
+    }
+
+    /// Half-width of the 95% confidence interval of the mean loss
+    #[inline]
+    pub fn confidence(&self) -> f64 {
+        if self.samples == 0 {
+            return 0.0;
+        }
+
+        1.96 * (self.variance() / self.samples as f64).sqrt()
+    }
+
+    #[inline]
+    fn probability(&self, count: usize) -> f64 {
+        if self.samples == 0 {
+            return 0.0;
+        }
+
+        count as f64 / self.samples as f64
+    }
+
+    pub fn win_probability(&self) -> f64 {
+        self.probability(self.wins)
+    }
+
+    pub fn elimination_probability(&self) -> f64 {
+        self.probability(self.eliminations)
+    }
+
+    pub fn sieged_next_turn_probability(&self) -> f64 {
+        self.probability(self.sieged_next_turn)
+    }
+
+    pub fn obelisk_loss_probability(&self) -> f64 {
+        self.probability(self.obelisk_losses)
+    }
+
+    /// Conditional value at risk: the mean of the worst `alpha` fraction of the losses
+    pub fn cvar(&self, alpha: f64) -> f64 {
+        if self.losses.is_empty() {
+            return self.mean();
+        }
+
+        let mut losses = self.losses.clone();
+        losses.sort_by(|a, b| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+
+        let tail = ((losses.len() as f64 * alpha).ceil() as usize).clamp(1, losses.len());
+
+        losses[0..tail].iter().sum::<f64>() / tail as f64
+    }
+
+    /// The score of this outcome according to `criterion`; the lower, the better
+    pub fn score(&self, criterion: Criterion) -> f64 {
+        match criterion {
+            Criterion::Mean => self.mean(),
+            Criterion::CVaR(alpha) => self.cvar(alpha),
+            Criterion::MeanStd(lambda) => self.mean() + lambda * self.variance().sqrt(),
+            Criterion::Safety => {
+                if self.samples == 0 {
+                    f64::INFINITY
+                } else {
+                    self.obelisk_loss_probability()
+                }
+            }
+        }
+    }
+
+    /// Returns true if this outcome is better than `other` according to `criterion`; ties are broken by the mean loss
+    pub fn better_than(&self, other: &Outcome, criterion: Criterion) -> bool {
+        let (a, b) = (self.score(criterion), other.score(criterion));
+
+        a < b || a == b && self.mean() < other.mean()
+    }
+
+    /// The kept game whose loss is the closest to the mean loss
+    pub fn typical_trajectory(&self) -> Option<&Trajectory> {
+        let mean = self.mean();
+
+        self.trajectories.iter().min_by(|a, b| {
+            (a.loss - mean).abs().partial_cmp(&(b.loss - mean).abs()).unwrap_or(Ordering::Equal)
+        })
+    }
+
+    /// The kept game with the highest loss
+    pub fn worst_trajectory(&self) -> Option<&Trajectory> {
+        self.trajectories.iter().max_by(|a, b| a.loss.partial_cmp(&b.loss).unwrap_or(Ordering::Equal))
+    }
+
+    /// Returns the probability of ending with `n` obelisks, for every `n`
+    pub fn obelisk_distribution(&self) -> Vec<f64> {
+        self.obelisks.iter().map(|count| self.probability(*count)).collect()
+    }
+
+    /// Returns the requested quantiles of the loss, as `(q, loss)` pairs
+    pub fn quantiles(&self) -> Vec<(f64, f64)> {
+        let requested = self.statistics.iter().find_map(|statistic| match statistic {
+            Statistic::LossQuantiles(quantiles) => Some(quantiles.clone()),
+            _ => None,
+        }).unwrap_or_default();
+
+        if self.losses.is_empty() {
+            return Vec::new();
+        }
+
+        let mut losses = self.losses.clone();
+        losses.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+        requested
+            .into_iter()
+            .map(|q| {
+                let index = ((losses.len() - 1) as f64 * q.clamp(0.0, 1.0)).round() as usize;
+                (q, losses[index])
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_criteria() {
+        let players = [Player::new()];
+        let mut outcome = Outcome::new(&[Statistic::Losses]);
+        for loss in [1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 11.0] {
+            outcome.record(&players, &players, &players, 0, loss);
+        }
+
+        assert_eq!(outcome.score(Criterion::Mean), 2.0);
+        assert_eq!(outcome.score(Criterion::CVaR(0.1)), 11.0);
+        assert_eq!(outcome.score(Criterion::CVaR(0.2)), 6.0);
+        assert_eq!(outcome.score(Criterion::MeanStd(1.0)), 5.0);
+
+        assert_eq!("cvar:0.05".parse::<Criterion>(), Ok(Criterion::CVaR(0.05)));
+        assert_eq!("safety".parse::<Criterion>(), Ok(Criterion::Safety));
+        assert!("cvar".parse::<Criterion>().is_err());
+    }
+
+    #[test]
+    fn test_trajectories() {
+        let players = [Player::new()];
+        let mut rng = rand::thread_rng();
+        let mut outcome = Outcome::new(&[Statistic::Trajectories(5)]);
+
+        for n in 0..100 {
+            outcome.record(&players, &players, &players, 0, n as f64);
+            outcome.sample_trajectory(&mut rng, || Trajectory {
+                actions: vec![vec![Action::Wall]],
+                final_state: players.to_vec(),
+                loss: n as f64,
+            });
+        }
+        assert_eq!(outcome.trajectories.len(), 5);
+
+        let mut other = outcome.clone();
+        other.merge(&outcome);
+        assert_eq!(other.trajectories.len(), 5);
+
+        let worst = outcome.worst_trajectory().unwrap().loss;
+        assert!(outcome.trajectories.iter().all(|trajectory| trajectory.loss <= worst));
+    }
+}
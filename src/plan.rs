@@ -0,0 +1,240 @@
+// Multi-turn, open-loop plans: sequences of actions that a player commits to for the next few rounds, whatever the other players do.
+// Plans are given to monte carlo as constraints keyed by (round, player), and `best_plans` searches over short plans
+// with a beam search: every plan is extended by one step at a time, and only the most promising plans are kept.
+use super::*;
+use super::monte_carlo::*;
+use super::outcome::*;
+use super::objective::Objective;
+use std::cmp::Ordering;
+
+/// An action to take during a planned round; unlike `Action`, the target of an attack can be picked when the round is played
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PlannedAction {
+    Fixed(Action),
+    Dynamic(DynamicAction),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DynamicAction {
+    /// Attacks the player with the most obelisks (ties are broken by the number of soldiers)
+    AttackStrongest,
+    /// Attacks the player that is the easiest to siege
+    AttackWeakest,
+}
+
+impl PlannedAction {
+    /// Returns the action that player `index` takes in the state `players`; if the planned action isn't possible, `Skip` is taken instead
+    pub fn resolve(&self, players: &[Player], index: usize) -> Action {
+        let possible = players[index].possible_actions(players.iter().enumerate().filter(|(n, _p)| *n != index));
+        if possible == [Action::None] {
+            return Action::None;
+        }
+
+        let defense = |p: &Player| p.walls as u32 * if p.defense > 0 { 2 } else { 1 } + p.soldiers;
+        let targets = possible.iter().filter_map(|action| match action {
+            Action::Attack(n) => Some(*n),
+            _ => None,
+        });
+
+        let action = match self {
+            PlannedAction::Fixed(action) => Some(*action).filter(|action| possible.contains(action)),
+            PlannedAction::Dynamic(DynamicAction::AttackStrongest) => targets
+                .max_by_key(|&n| (players[n].obelisks, players[n].soldiers))
+                .map(Action::Attack),
+            PlannedAction::Dynamic(DynamicAction::AttackWeakest) => targets
+                .min_by_key(|&n| defense(&players[n]))
+                .map(Action::Attack),
+        };
+
+        action.unwrap_or(Action::Skip)
+    }
+}
+
+impl From<Action> for PlannedAction {
+    fn from(action: Action) -> Self {
+        PlannedAction::Fixed(action)
+    }
+}
+
+/// Forces `player` to take `action` during `round`, 0 being the first simulated round
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoundConstraint {
+    pub round: usize,
+    pub player: usize,
+    pub action: PlannedAction,
+}
+
+/// Returns the action that `player` is constrained to take during `round`, if any
+#[inline]
+pub fn find_constraint(constraints: &[RoundConstraint], round: usize, player: usize) -> Option<PlannedAction> {
+    constraints.iter().find(|c| c.round == round && c.player == player).map(|c| c.action)
+}
+
+/// Turns a sequence of actions of `player` into constraints, starting from the first simulated round
+pub fn plan_constraints(player: usize, steps: &[PlannedAction]) -> Vec<RoundConstraint> {
+    steps.iter().enumerate().map(|(round, action)| RoundConstraint {
+        round,
+        player,
+        action: *action,
+    }).collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlannerSettings {
+    /// Settings used to evaluate the best plans
+    pub monte_carlo: MonteCarloSettings,
+    /// Number of planned rounds
+    pub depth: usize,
+    /// Number of samples used to evaluate the plans while searching
+    pub prune_samples: usize,
+    /// Number of plans kept after each round of the search
+    pub beam_width: usize,
+    /// The actions that can be planned, on top of the ones possible during the first round
+    pub steps: Vec<PlannedAction>,
+}
+
+impl Default for PlannerSettings {
+    fn default() -> Self {
+        Self {
+            monte_carlo: MonteCarloSettings::default(),
+            depth: 3,
+            prune_samples: 200,
+            beam_width: 6,
+            steps: vec![
+                PlannedAction::Fixed(Action::Wall),
+                PlannedAction::Fixed(Action::Recruit),
+                PlannedAction::Fixed(Action::Barracks),
+                PlannedAction::Fixed(Action::Obelisk),
+                PlannedAction::Fixed(Action::Defend),
+                PlannedAction::Fixed(Action::Skip),
+                PlannedAction::Dynamic(DynamicAction::AttackStrongest),
+                PlannedAction::Dynamic(DynamicAction::AttackWeakest),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    pub steps: Vec<PlannedAction>,
+    pub outcome: Outcome,
+}
+
+/// Searches for the best plans of player `index`, `settings.depth` rounds deep.
+/// The plans are extended one round at a time; after each round, only the `settings.beam_width` best plans
+/// (evaluated with `settings.prune_samples` samples) are kept. The remaining plans are then evaluated with
+/// `settings.monte_carlo` and returned from the best to the worst, according to `settings.monte_carlo.criterion`.
+///
+/// The other players follow `constraints` during the first round and `settings.monte_carlo.round_constraints` afterwards.
+pub fn best_plans<First, Ai, Loss>(
+    players: &[Player],
+    index: usize,
+    previous_actions: &[Vec<Action>],
+    constraints: &[(usize, Action)],
+    settings: &PlannerSettings,
+    rollout: Rollout<First, Ai, Loss>,
+) -> Vec<Plan>
+where
+    First: FirstAi<Ai> + Copy,
+    Ai: RolloutAi + Copy,
+    Loss: Objective + Copy,
+{
+    let constraints = constraints.iter().copied().filter(|(n, _)| *n != index).collect::<Vec<_>>();
+    let round_constraints = settings.monte_carlo.round_constraints.iter().copied().filter(|c| c.player != index).collect::<Vec<_>>();
+    let criterion = settings.monte_carlo.criterion;
+
+    let evaluate = |steps: &[PlannedAction], samples: usize| {
+        let mut round_constraints = round_constraints.clone();
+        round_constraints.extend(plan_constraints(index, steps));
+
+        let settings = MonteCarloSettings {
+            samples,
+            round_constraints,
+            ..settings.monte_carlo.clone()
+        };
+
+        monte_carlo(players, previous_actions, &constraints, &[index], &settings, rollout).pop().unwrap()
+    };
+
+    // The first step can also be an attack on a specific player
+    let first_steps = players[index]
+        .possible_actions(players.iter().enumerate().filter(|(n, _p)| *n != index))
+        .into_iter()
+        .map(PlannedAction::Fixed)
+        .chain(settings.steps.iter().copied().filter(|step| matches!(step, PlannedAction::Dynamic(_))))
+        .collect::<Vec<_>>();
+
+    let mut beam: Vec<Vec<PlannedAction>> = vec![Vec::new()];
+
+    for depth in 0..settings.depth {
+        let steps = if depth == 0 { &first_steps } else { &settings.steps };
+
+        let mut candidates = beam.iter().flat_map(|plan| {
+            steps.iter().map(move |step| {
+                let mut plan = plan.clone();
+                plan.push(*step);
+                plan
+            })
+        }).map(|steps| {
+            let outcome = evaluate(&steps, settings.prune_samples);
+            Plan { steps, outcome }
+        }).collect::<Vec<_>>();
+
+        sort_plans(&mut candidates, criterion);
+        candidates.truncate(settings.beam_width);
+
+        beam = candidates.into_iter().map(|plan| plan.steps).collect();
+    }
+
+    let mut res = beam.into_iter().map(|steps| {
+        let outcome = evaluate(&steps, settings.monte_carlo.samples);
+        Plan { steps, outcome }
+    }).collect::<Vec<_>>();
+
+    sort_plans(&mut res, criterion);
+
+    res
+}
+
+fn sort_plans(plans: &mut [Plan], criterion: Criterion) {
+    plans.sort_by(|a, b| {
+        if a.outcome.better_than(&b.outcome, criterion) {
+            Ordering::Less
+        } else if b.outcome.better_than(&a.outcome, criterion) {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let players = vec![
+            Player::with_values(1, 4, 1, 1, 0),
+            Player::with_values(3, 3, 1, 4, 0),
+            Player::with_values(0, 1, 1, 2, 0),
+            Player::with_values(1, 1, 1, 0, 0),
+        ];
+
+        let strongest = PlannedAction::Dynamic(DynamicAction::AttackStrongest);
+        let weakest = PlannedAction::Dynamic(DynamicAction::AttackWeakest);
+
+        assert_eq!(strongest.resolve(&players, 0), Action::Attack(1));
+        assert_eq!(weakest.resolve(&players, 0), Action::Attack(2));
+        assert_eq!(PlannedAction::Fixed(Action::Wall).resolve(&players, 0), Action::Wall);
+        assert_eq!(strongest.resolve(&players, 3), Action::None);
+
+        let constraints: Vec<RoundConstraint> = serde_json::from_str(
+            r#"[{"round": 1, "player": 0, "action": "Obelisk"}, {"round": 2, "player": 0, "action": "AttackStrongest"}]"#
+        ).unwrap();
+        assert_eq!(find_constraint(&constraints, 1, 0), Some(PlannedAction::Fixed(Action::Obelisk)));
+        assert_eq!(find_constraint(&constraints, 2, 0), Some(strongest));
+        assert_eq!(find_constraint(&constraints, 0, 0), None);
+    }
+}
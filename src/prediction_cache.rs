@@ -0,0 +1,227 @@
+// A bounded cache of the predictions of the model, shared by the threads of a monte carlo run.
+// The first simulated rounds of every game start from the same few states, which are encoded to the same inputs
+// over and over; the outputs of the model are stored, keyed by a hash of the encoded input, so that these inputs
+// only go through the model once. The least recently used entries are evicted once the cache is full.
+//
+// The cache is split into shards, each behind its own lock, so that the threads rarely wait on each other.
+// Only the 64-bit hash of the input is stored: two different inputs with the same hash would share a prediction,
+// which is unlikely enough to be ignored.
+use super::cache::fnv1a;
+use super::model::ModelPrec;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const NIL: usize = usize::MAX;
+const SHARDS: usize = 16;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    key: u64,
+    value: Vec<ModelPrec>,
+    // Towards the most recently used entry
+    previous: usize,
+    // Towards the least recently used entry
+    next: usize,
+}
+
+/// A least recently used cache, as a doubly linked list of entries stored in a `Vec`
+#[derive(Debug, Clone)]
+struct Lru {
+    capacity: usize,
+    indices: HashMap<u64, usize>,
+    entries: Vec<Entry>,
+    // The most recently used entry
+    head: usize,
+    // The least recently used entry
+    tail: usize,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            indices: HashMap::with_capacity(capacity),
+            entries: Vec::with_capacity(capacity),
+            head: NIL,
+            tail: NIL,
+        }
+    }
+
+    fn detach(&mut self, index: usize) {
+        let (previous, next) = (self.entries[index].previous, self.entries[index].next);
+
+        match previous {
+            NIL => self.head = next,
+            previous => self.entries[previous].next = next,
+        }
+        match next {
+            NIL => self.tail = previous,
+            next => self.entries[next].previous = previous,
+        }
+    }
+
+    fn attach_front(&mut self, index: usize) {
+        self.entries[index].previous = NIL;
+        self.entries[index].next = self.head;
+
+        match self.head {
+            NIL => self.tail = index,
+            head => self.entries[head].previous = index,
+        }
+        self.head = index;
+    }
+
+    fn get(&mut self, key: u64) -> Option<&[ModelPrec]> {
+        let index = *self.indices.get(&key)?;
+        self.detach(index);
+        self.attach_front(index);
+
+        Some(&self.entries[index].value)
+    }
+
+    fn insert(&mut self, key: u64, value: Vec<ModelPrec>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let index = if let Some(index) = self.indices.get(&key).copied() {
+            self.detach(index);
+            self.entries[index].value = value;
+            index
+        } else if self.entries.len() < self.capacity {
+            self.entries.push(Entry { key, value, previous: NIL, next: NIL });
+            self.entries.len() - 1
+        } else {
+            // Reuses the least recently used entry
+            let index = self.tail;
+            self.detach(index);
+            self.indices.remove(&self.entries[index].key);
+            self.entries[index].key = key;
+            self.entries[index].value = value;
+            index
+        };
+
+        self.indices.insert(key, index);
+        self.attach_front(index);
+    }
+}
+
+/// The hit-rate statistics of a `PredictionCache`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStatistics {
+    pub hits: usize,
+    pub misses: usize,
+    pub entries: usize,
+}
+
+impl CacheStatistics {
+    pub fn hit_rate(&self) -> f64 {
+        if self.hits + self.misses == 0 {
+            0.0
+        } else {
+            self.hits as f64 / (self.hits + self.misses) as f64
+        }
+    }
+}
+
+/// A bounded, thread-safe cache of the outputs of the model, keyed by the encoded inputs (see `run_model_batch_cached`)
+#[derive(Debug)]
+pub struct PredictionCache {
+    shards: Vec<Mutex<Lru>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl PredictionCache {
+    /// Creates a cache that holds at most about `capacity` predictions
+    pub fn new(capacity: usize) -> Self {
+        let per_shard = capacity.div_ceil(SHARDS);
+
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(Lru::new(per_shard))).collect(),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the key of an encoded input
+    pub fn key(input: &[ModelPrec]) -> u64 {
+        let bytes = input.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>();
+
+        fnv1a(&bytes)
+    }
+
+    #[inline]
+    fn shard(&self, key: u64) -> &Mutex<Lru> {
+        &self.shards[(key % SHARDS as u64) as usize]
+    }
+
+    /// Returns the stored output for the input of hash `key`, if there is one, and counts the hit or the miss
+    pub fn get(&self, key: u64) -> Option<Vec<ModelPrec>> {
+        let res = self.shard(key).lock().unwrap().get(key).map(|value| value.to_vec());
+
+        match res {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        res
+    }
+
+    pub fn insert(&self, key: u64, output: Vec<ModelPrec>) {
+        self.shard(key).lock().unwrap().insert(key, output);
+    }
+
+    pub fn statistics(&self) -> CacheStatistics {
+        CacheStatistics {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.shards.iter().map(|shard| shard.lock().unwrap().indices.len()).sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::*;
+    use super::super::model::*;
+    use super::super::train::{initialize, TrainSettings};
+
+    #[test]
+    fn test_prediction_cache() {
+        let mut lru = Lru::new(2);
+        lru.insert(1, vec![1.0]);
+        lru.insert(2, vec![2.0]);
+        assert!(lru.get(1).is_some());
+        // 2 is now the least recently used entry
+        lru.insert(3, vec![3.0]);
+        assert!(lru.get(2).is_none());
+        assert_eq!(lru.get(1), Some(&[1.0][..]));
+        assert_eq!(lru.get(3), Some(&[3.0][..]));
+        lru.insert(1, vec![4.0]);
+        lru.insert(5, vec![5.0]);
+        assert_eq!(lru.get(1), Some(&[4.0][..]));
+        assert!(lru.get(3).is_none());
+
+        let settings = TrainSettings {
+            hidden: vec![8],
+            ..Default::default()
+        };
+        let network = Network::Native(initialize(&settings, &mut rand::thread_rng()));
+        let cache = PredictionCache::new(64);
+
+        let players = vec![Player::with_values(2, 1, 4, 2, 0), Player::with_values(4, 3, 2, 2, 1), Player::new()];
+        let previous_actions = vec![vec![Action::Wall], vec![Action::Recruit], vec![Action::Skip]];
+        let uncached = ModelPolicy::new(&network).predict_all(&players, &previous_actions);
+        let policy = ModelPolicy::new(&network).with_cache(&cache);
+
+        assert_eq!(policy.predict_all(&players, &previous_actions), uncached);
+        assert_eq!(cache.statistics(), CacheStatistics { hits: 0, misses: 3, entries: 3 });
+        assert_eq!(policy.predict_all(&players, &previous_actions), uncached);
+        assert_eq!(policy.predict(&players, 1, &previous_actions[1]), uncached[1]);
+        assert_eq!(cache.statistics(), CacheStatistics { hits: 4, misses: 3, entries: 3 });
+        assert!((cache.statistics().hit_rate() - 4.0 / 7.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,213 @@
+// Measures how well the model predicts the best actions of held-out training data, with the inputs encoded by the
+// same `Encoder` as the one that runs the model in monte carlo, instead of the encoding of train.py.
+// Besides the cross-entropy and the accuracy that keras reports, the report breaks the predictions down by kind of
+// action (all the attacks are one kind, whatever their target) and measures how well the confidence of the model
+// matches its accuracy. Like `run_model`, the predictions are restricted to the possible actions of each example and
+// renormalized before they are scored.
+use super::model::*;
+use super::train::Dataset;
+use tract_onnx::prelude::TractResult;
+
+/// The number of bins of the calibration curve
+pub const CALIBRATION_BINS: usize = 10;
+
+// The kind of the attacks, which come last in the output of the model
+const ATTACK_KIND: usize = 7;
+
+/// The name of each kind of action, as in actions.csv; all the attacks are `Attack`
+pub fn action_kinds() -> Vec<String> {
+    Encoder::default().schema().actions.into_iter().map(|(name, _)| name).collect()
+}
+
+/// Returns the kind of the action of the output `index` of the model
+#[inline]
+pub fn action_kind(index: usize) -> usize {
+    index.min(ATTACK_KIND)
+}
+
+/// The predictions whose highest probability falls in a bin of the calibration curve
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CalibrationBin {
+    pub count: usize,
+    pub confidence: f64,
+    pub correct: usize,
+}
+
+impl CalibrationBin {
+    pub fn mean_confidence(&self) -> f64 {
+        if self.count == 0 { 0.0 } else { self.confidence / self.count as f64 }
+    }
+
+    pub fn accuracy(&self) -> f64 {
+        if self.count == 0 { 0.0 } else { self.correct as f64 / self.count as f64 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub examples: usize,
+    pub cross_entropy: f64,
+    pub top1: f64,
+    pub top3: f64,
+    /// `confusion[label][predicted]`, by kind of action (see `action_kind`)
+    pub confusion: Vec<Vec<usize>>,
+    pub calibration: [CalibrationBin; CALIBRATION_BINS],
+}
+
+impl Report {
+    /// Computes the report from the `[labels.len(), max actions]` output of the model, `legal` being the indices of the
+    /// possible actions of each example (see `Dataset`)
+    pub fn new(prediction: &[ModelPrec], labels: &[usize], legal: &[Vec<usize>]) -> Self {
+        assert_eq!(labels.len(), legal.len());
+        let kinds = ATTACK_KIND + 1;
+        let mut res = Self {
+            examples: labels.len(),
+            cross_entropy: 0.0,
+            top1: 0.0,
+            top3: 0.0,
+            confusion: vec![vec![0; kinds]; kinds],
+            calibration: [CalibrationBin::default(); CALIBRATION_BINS],
+        };
+        if labels.is_empty() {
+            return res;
+        }
+
+        for ((row, label), legal) in prediction.chunks_exact(prediction.len() / labels.len()).zip(labels.iter().copied()).zip(legal.iter()) {
+            let sum = legal.iter().map(|&n| row[n]).sum::<ModelPrec>();
+            let mut masked = vec![0.0; row.len()];
+            for &n in legal.iter() {
+                masked[n] = if sum > 0.0 { row[n] / sum } else { 1.0 / legal.len() as ModelPrec };
+            }
+
+            res.cross_entropy -= (masked[label].max(1e-7) as f64).ln();
+
+            let mut order = legal.clone();
+            order.sort_unstable_by(|a, b| masked[*b].partial_cmp(&masked[*a]).unwrap_or(std::cmp::Ordering::Equal));
+            let best = order.first().copied().unwrap_or(label);
+
+            res.top1 += (best == label) as usize as f64;
+            res.top3 += order.iter().take(3).any(|&n| n == label) as usize as f64;
+            res.confusion[action_kind(label)][action_kind(best)] += 1;
+
+            let confidence = masked[best] as f64;
+            let bin = &mut res.calibration[((confidence * CALIBRATION_BINS as f64) as usize).min(CALIBRATION_BINS - 1)];
+            bin.count += 1;
+            bin.confidence += confidence;
+            bin.correct += (best == label) as usize;
+        }
+
+        let examples = labels.len() as f64;
+        res.cross_entropy /= examples;
+        res.top1 /= examples;
+        res.top3 /= examples;
+
+        res
+    }
+
+    /// Returns the precision and the recall of the attacks: the fraction of the predicted attacks that are attacks, and the
+    /// fraction of the attacks that are predicted as attacks (whatever their target)
+    pub fn attack_precision_recall(&self) -> (f64, f64) {
+        let true_positives = self.confusion[ATTACK_KIND][ATTACK_KIND] as f64;
+        let predicted = self.confusion.iter().map(|row| row[ATTACK_KIND]).sum::<usize>() as f64;
+        let actual = self.confusion[ATTACK_KIND].iter().sum::<usize>() as f64;
+
+        (
+            if predicted > 0.0 { true_positives / predicted } else { 0.0 },
+            if actual > 0.0 { true_positives / actual } else { 0.0 },
+        )
+    }
+
+    /// Returns the mean gap between the confidence and the accuracy of the bins of the calibration curve, weighted by their size
+    pub fn expected_calibration_error(&self) -> f64 {
+        if self.examples == 0 {
+            return 0.0;
+        }
+
+        self.calibration.iter()
+            .map(|bin| bin.count as f64 * (bin.mean_confidence() - bin.accuracy()).abs())
+            .sum::<f64>() / self.examples as f64
+    }
+}
+
+/// Runs `model` on every example of `dataset`, `batch_size` examples at a time; the examples must be encoded with the
+/// encoder of the model
+pub fn predict_dataset(model: &Network, dataset: &Dataset, batch_size: usize) -> TractResult<Vec<ModelPrec>> {
+    if model.encoder() != dataset.encoder {
+        tract_onnx::prelude::tract_data::anyhow::bail!("The examples weren't encoded for this model");
+    }
+
+    let input_size = dataset.encoder.input_size();
+    let mut res = Vec::with_capacity(dataset.len() * dataset.encoder.max_actions());
+
+    for start in (0..dataset.len()).step_by(batch_size.max(1)) {
+        let rows = batch_size.min(dataset.len() - start);
+        res.extend(run_policy(model, &dataset.inputs[start * input_size..(start + rows) * input_size], rows)?);
+    }
+
+    Ok(res)
+}
+
+/// Returns the fraction of the `rows` examples on which two models predict the same most likely action; the models can
+/// see different numbers of players
+pub fn agreement(a: &[ModelPrec], b: &[ModelPrec], rows: usize) -> f64 {
+    let best = |row: &[ModelPrec]| row.iter().enumerate().fold(0, |best, (n, x)| if *x > row[best] { n } else { best });
+    if rows == 0 {
+        return 0.0;
+    }
+
+    a.chunks_exact(a.len() / rows).zip(b.chunks_exact(b.len() / rows))
+        .filter(|(a, b)| best(a) == best(b))
+        .count() as f64 / rows as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let row = |probabilities: &[(usize, ModelPrec)]| {
+            let mut res = vec![0.0; MAX_ACTIONS];
+            for (index, prob) in probabilities.iter().copied() {
+                res[index] = prob;
+            }
+            res
+        };
+        // Wall (1) predicted as a wall, an attack (9) predicted as another attack (8), and a skip (6) predicted as a
+        // recruit (2), with skip second and attack third
+        let prediction = [
+            row(&[(1, 0.95), (2, 0.05)]),
+            row(&[(8, 0.6), (9, 0.4)]),
+            row(&[(2, 0.5), (6, 0.3), (9, 0.2)]),
+        ].concat();
+        let labels = [1, 9, 6];
+        let legal = [vec![0, 1, 2, 6], vec![1, 8, 9], vec![1, 2, 6, 9]];
+
+        let report = Report::new(&prediction, &labels, &legal);
+        assert_eq!(report.examples, 3);
+        assert!((report.cross_entropy - -(0.95f64.ln() + 0.4f64.ln() + 0.3f64.ln()) / 3.0).abs() < 1e-5);
+        assert!((report.top1 - 1.0 / 3.0).abs() < 1e-9);
+        assert!((report.top3 - 1.0).abs() < 1e-9);
+        assert_eq!(report.confusion[1][1], 1);
+        assert_eq!(report.confusion[ATTACK_KIND][ATTACK_KIND], 1);
+        assert_eq!(report.confusion[6][2], 1);
+        assert_eq!(report.attack_precision_recall(), (1.0, 1.0));
+        assert_eq!(action_kinds()[ATTACK_KIND], "Attack");
+
+        assert_eq!(report.calibration[9], CalibrationBin { count: 1, confidence: 0.95f32 as f64, correct: 1 });
+        assert_eq!(report.calibration[6].count, 1);
+        assert_eq!(report.calibration[5].count, 1);
+        let error = ((0.95f32 as f64 - 1.0).abs() + 0.6f32 as f64 + 0.5) / 3.0;
+        assert!((report.expected_calibration_error() - error).abs() < 1e-9);
+
+        assert!((agreement(&prediction, &prediction, 3) - 1.0).abs() < 1e-9);
+
+        // The probability of the actions that can't be played is left out, and the rest renormalized
+        let prediction = row(&[(3, 0.5), (1, 0.3), (6, 0.2)]);
+        let report = Report::new(&prediction, &[1], &[vec![1, 6]]);
+        assert!((report.cross_entropy - -(0.6f64.ln())).abs() < 1e-5);
+        assert!((report.top1 - 1.0).abs() < 1e-9);
+        assert_eq!(report.confusion[1][1], 1);
+        assert_eq!(report.calibration[6].count, 1);
+    }
+}
@@ -0,0 +1,298 @@
+// A search session that persists across the turns of an ongoing game.
+// The session holds the monte carlo statistics of the current position (the root) and of the positions that the
+// simulated games went through after the first round (the children, keyed by the joint actions of that round).
+// Every simulated game of the root is also a simulated game of the child it went through, in which the player took
+// the action that the rollout policy picked during the second round. When the real joint actions of the turn are
+// entered, the session advances to the matching child, and the next turn starts with these samples instead of zero.
+//
+// The samples of a child are only valid if it would be searched with the same settings, which is why they are only
+// collected when the games are simulated until the end, from known states, without constraints after the first round,
+// and with the same policy for the first round and the rollouts.
+// The session file keeps at most `MAX_SESSION_CHILDREN` children, the ones with the fewest samples being dropped when it
+// is saved.
+use super::*;
+use super::monte_carlo::*;
+use super::outcome::*;
+use super::cache::CachedOutcome;
+use super::objective::Objective;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// The largest number of children kept in a session file
+pub const MAX_SESSION_CHILDREN: usize = 1000;
+
+/// The samples of the children of a position, keyed by the joint actions of the turn (see `child_key`)
+pub type Children = HashMap<String, Vec<ActionStatistics>>;
+
+/// The accumulated outcome of `action`, for `player`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActionStatistics {
+    pub player: usize,
+    pub action: Action,
+    pub outcome: CachedOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    /// Identifies the policy, the objective and the other settings of the search; the samples of a session
+    /// can't be reused if it changes
+    pub identity: String,
+    pub players: Vec<Player>,
+    pub previous_actions: Vec<Vec<Action>>,
+    /// The statistics of the current position
+    pub statistics: Vec<ActionStatistics>,
+    /// The statistics of the positions after the current turn
+    pub children: Children,
+}
+
+#[inline]
+fn child_key(actions: &[Action]) -> String {
+    serde_json::to_string(actions).unwrap()
+}
+
+fn add_sample(statistics: &mut Vec<ActionStatistics>, player: usize, action: Action, outcome: &CachedOutcome) {
+    match statistics.iter_mut().find(|s| s.player == player && s.action == action) {
+        Some(s) => s.outcome.add(outcome),
+        None => statistics.push(ActionStatistics { player, action, outcome: outcome.clone() }),
+    }
+}
+
+impl Session {
+    pub fn new(identity: impl Into<String>, players: &[Player], previous_actions: &[Vec<Action>]) -> Self {
+        Self {
+            identity: identity.into(),
+            players: players.to_vec(),
+            previous_actions: previous_actions.to_vec(),
+            statistics: Vec::new(),
+            children: HashMap::new(),
+        }
+    }
+
+    /// Loads the session stored at `path`, if it exists and can be parsed
+    pub fn load(path: impl AsRef<Path>) -> Option<Self> {
+        let raw = std::fs::read_to_string(path).ok()?;
+
+        serde_json::from_str(&raw).ok()
+    }
+
+    /// Drops the children with the fewest samples, then writes the session
+    pub fn save(&mut self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        self.prune_children(MAX_SESSION_CHILDREN);
+        std::fs::write(path, serde_json::to_string(&self)?)
+    }
+
+    /// Drops the children with the fewest samples, until there are at most `max_children` left
+    pub fn prune_children(&mut self, max_children: usize) {
+        if self.children.len() <= max_children {
+            return;
+        }
+
+        let samples = |statistics: &[ActionStatistics]| statistics.iter().map(|s| s.outcome.samples).sum::<usize>();
+        let mut keys = self.children.iter().map(|(key, statistics)| (samples(statistics), key.clone())).collect::<Vec<_>>();
+        keys.sort_unstable_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+        for (_, key) in keys.into_iter().skip(max_children) {
+            self.children.remove(&key);
+        }
+    }
+
+    /// Returns true if the samples of the children can be collected with these settings; `same_policy` tells whether
+    /// the first round is played by the rollout policy, within the same draw (see `SameAi`)
+    pub fn collects_children(settings: &MonteCarloSettings, same_policy: bool) -> bool {
+        same_policy
+            && settings.horizon == Horizon::GameEnd
+            && settings.uncertain_players.is_empty()
+            && settings.round_constraints.iter().all(|c| c.round == 0)
+    }
+
+    /// Evaluates every action of player `index` in the current position, like `mc_best_action`, then tops up the
+    /// outcomes with the samples of the previous turns.
+    /// Also returns the samples of the children that the simulated games went through, to be given to `record`; they are
+    /// only collected when the first round is played with `SameAi` (see `collects_children`).
+    pub fn search<First, Ai, Loss>(
+        &self,
+        index: usize,
+        constraints: &[(usize, Action)],
+        settings: &MonteCarloSettings,
+        rollout: Rollout<First, Ai, Loss>,
+    ) -> (Action, Vec<(Action, Outcome)>, Children)
+    where
+        First: FirstAi<Ai> + Copy,
+        Ai: RolloutAi + Copy,
+        Loss: Objective + Copy,
+    {
+        let players = &self.players;
+        let rounds = self.previous_actions.first().map(|actions| actions.len()).unwrap_or(0);
+        let statistics = settings.collected_statistics();
+        let collects_children = Self::collects_children(settings, rollout.first_ai.is_rollout_ai());
+
+        let mut children = Children::new();
+        let mut actions: Vec<(Action, Outcome)> = Vec::new();
+
+        let mut constraints = constraints.to_vec();
+        constraints.push((index, Action::None));
+        for action in players[index].possible_actions(players.iter().enumerate().filter(|(n, _p)| *n != index)) {
+            if action == Action::None {
+                actions.push((Action::None, Outcome::new(&statistics)));
+                continue;
+            }
+
+            *constraints.last_mut().unwrap() = (index, action);
+
+            let observe = |players: &[Player], history: &[Vec<Action>], final_state: &[Player], losses: &[f64]| {
+                if !collects_children || history[index].len() < rounds + 2 || history[index][rounds + 1] == Action::None {
+                    return;
+                }
+
+                let first = history.iter().map(|actions| actions[rounds]).collect::<Vec<_>>();
+                let second = history.iter().map(|actions| actions[rounds + 1]).collect::<Vec<_>>();
+                let child = update(players.to_vec(), &first);
+                let next = update(child.clone(), &second);
+
+                let mut sample = Outcome::new(&statistics);
+                sample.record(&child, &next, final_state, index, losses[0]);
+                add_sample(children.entry(child_key(&first)).or_default(), index, second[index], &CachedOutcome::from(&sample));
+            };
+
+            let mut outcome = monte_carlo_observed(
+                players, &self.previous_actions, &constraints, &[index], settings, rollout, observe
+            ).pop().unwrap();
+
+            if let Some(previous) = self.statistics.iter().find(|s| s.player == index && s.action == action) {
+                outcome.merge(&previous.outcome.to_outcome(&statistics));
+            }
+
+            actions.push((action, outcome));
+        }
+
+        let best = actions.iter()
+            .filter(|(action, _)| *action != Action::None)
+            .reduce(|best, other| if other.1.better_than(&best.1, settings.criterion) { other } else { best })
+            .map(|(action, _)| *action)
+            .unwrap_or(Action::None);
+
+        (best, actions, children)
+    }
+
+    /// Stores the outcomes of the actions of player `index` and the samples of the children, as returned by `search`
+    pub fn record(&mut self, index: usize, actions: &[(Action, Outcome)], children: Children) {
+        for (action, outcome) in actions.iter() {
+            if *action == Action::None {
+                continue;
+            }
+
+            self.statistics.retain(|s| !(s.player == index && s.action == *action));
+            self.statistics.push(ActionStatistics {
+                player: index,
+                action: *action,
+                outcome: CachedOutcome::from(outcome),
+            });
+        }
+
+        for (key, statistics) in children {
+            let entry = self.children.entry(key).or_default();
+            for s in statistics.iter() {
+                add_sample(entry, s.player, s.action, &s.outcome);
+            }
+        }
+    }
+
+    /// Advances to the position after the turn in which every player took `actions`; the samples of that child are kept
+    pub fn advance(&mut self, actions: &[Action]) {
+        assert!(actions.len() == self.players.len());
+
+        self.players = update(std::mem::take(&mut self.players), actions);
+        for (previous_actions, action) in self.previous_actions.iter_mut().zip(actions.iter()) {
+            previous_actions.push(*action);
+        }
+
+        self.statistics = self.children.remove(&child_key(actions)).unwrap_or_default();
+        self.children.clear();
+    }
+
+    /// Moves the session to the position `players`, reached after `previous_actions`: if it is the position after
+    /// the current turn, the session advances to it; otherwise, the session starts over from that position.
+    /// Returns the number of samples that were kept.
+    pub fn advance_to(&mut self, identity: &str, players: &[Player], previous_actions: &[Vec<Action>]) -> usize {
+        let rounds = self.previous_actions.first().map(|actions| actions.len()).unwrap_or(0);
+        let is_next = self.identity == identity
+            && previous_actions.len() == self.previous_actions.len()
+            && previous_actions.iter().zip(self.previous_actions.iter()).all(|(actions, previous)| {
+                actions.len() == rounds + 1 && actions[0..rounds] == previous[..]
+            });
+        let is_same = self.identity == identity && self.previous_actions == previous_actions && self.players == players;
+
+        if is_next {
+            let actions = previous_actions.iter().map(|actions| actions[rounds]).collect::<Vec<_>>();
+            self.advance(&actions);
+        }
+
+        if !is_same && !(is_next && self.players == players) {
+            *self = Session::new(identity, players, previous_actions);
+        }
+
+        self.statistics.iter().map(|s| s.outcome.samples).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::inference::{Bot, Policy};
+    use super::super::objective::ValueFn;
+
+    #[test]
+    fn test_session() {
+        let players = vec![Player::new(); 3];
+        let previous_actions = vec![vec![]; 3];
+        let settings = MonteCarloSettings {
+            samples: 300,
+            max_rounds: 6,
+            ..Default::default()
+        };
+        let ai = |p: &[Player], index, round, previous: &[Action], rng: &mut rand::rngs::ThreadRng| {
+            Bot::Uniform.sample(p, index, round, previous, rng)
+        };
+        let loss = ValueFn(|p: &[Player], index: usize| p[index].obelisks as f64);
+        let rollout = Rollout { first_ai: SameAi, ai, compute_loss: loss };
+
+        let mut session = Session::new("test", &players, &previous_actions);
+        let (_, actions, children) = session.search(0, &[], &settings, rollout);
+        session.record(0, &actions, children);
+
+        let possible = actions.iter().filter(|(action, _)| *action != Action::None).count();
+        assert_eq!(session.statistics.iter().map(|s| s.outcome.samples).sum::<usize>(), 300 * possible);
+
+        // Every simulated game that lasted at least two rounds went through one of the children
+        let samples: usize = session.children.values().flatten().map(|s| s.outcome.samples).sum();
+        assert!(samples > 0 && samples <= 300 * possible);
+
+        // Searching again tops up the outcomes
+        let (_, actions, _) = session.search(0, &[], &settings, rollout);
+        assert!(actions.iter().all(|(action, outcome)| *action == Action::None || outcome.samples == 600));
+
+        // Pruning keeps the children with the most samples
+        let mut pruned = session.clone();
+        pruned.prune_children(1);
+        let most = session.children.values().map(|s| s.iter().map(|s| s.outcome.samples).sum::<usize>()).max().unwrap();
+        assert_eq!(pruned.children.len(), 1);
+        assert_eq!(pruned.children.values().next().unwrap().iter().map(|s| s.outcome.samples).sum::<usize>(), most);
+
+        let (joint, child) = session.children.iter().max_by_key(|(_, s)| s.iter().map(|s| s.outcome.samples).sum::<usize>()).unwrap();
+        let (joint, child): (Vec<Action>, _) = (serde_json::from_str(joint).unwrap(), child.clone());
+        let next_actions = previous_actions.iter().zip(joint.iter()).map(|(_, a)| vec![*a]).collect::<Vec<_>>();
+        let next_players = update(players.clone(), &joint);
+
+        let kept = session.advance_to("test", &next_players, &next_actions);
+        assert_eq!(kept, child.iter().map(|s| s.outcome.samples).sum::<usize>());
+        assert_eq!(session.statistics, child);
+        assert!(session.children.is_empty());
+
+        // A different position starts over
+        assert_eq!(session.advance_to("test", &players, &previous_actions), 0);
+
+        // The children aren't collected when the first round is played by another policy
+        let (_, _, children) = session.search(0, &[], &settings, Rollout { first_ai: ai, ai, compute_loss: loss });
+        assert!(children.is_empty());
+    }
+}
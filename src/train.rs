@@ -0,0 +1,363 @@
+// Trains the policy network in Rust, from the data of `generate_training`.
+// The examples are encoded with the same `Encoder` as `run_model`, so the trained network can't disagree with the one
+// that runs it. The network is a stack of dense layers with `relu` activations, dropout and a `softmax` output,
+// trained with Adam on the categorical cross-entropy, like the network of `train.py`; it is saved as a weights file
+// that the native backend can load (see `mlp.rs`).
+use super::generate_training::TrainingData;
+use super::mlp::{Activation, Dense, Mlp};
+use super::model::*;
+use rand::prelude::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrainSettings {
+    /// The sizes of the hidden layers
+    pub hidden: Vec<usize>,
+    /// The probability of dropping the output of a hidden neuron during training
+    pub dropout: ModelPrec,
+    pub learning_rate: ModelPrec,
+    pub beta1: ModelPrec,
+    pub beta2: ModelPrec,
+    pub epsilon: ModelPrec,
+    pub batch_size: usize,
+    pub epochs: usize,
+    /// The fraction of the examples that is kept aside to measure the validation loss and accuracy
+    pub validation_split: f64,
+    /// The probability of skipping each example, which reduces the correlation between the players of a same state
+    pub skip_rate: f64,
+    /// The number of players that the network sees (see `Encoder`)
+    pub max_players: usize,
+}
+
+impl Default for TrainSettings {
+    fn default() -> Self {
+        Self {
+            hidden: vec![96, 64, 48, 24],
+            dropout: 0.25,
+            learning_rate: 0.001,
+            beta1: 0.9,
+            beta2: 0.999,
+            epsilon: 1e-7,
+            batch_size: 32,
+            epochs: 40,
+            validation_split: 0.1,
+            skip_rate: 0.5,
+            max_players: MAX_PLAYERS,
+        }
+    }
+}
+
+/// Encoded examples: `inputs` holds `labels.len()` rows of `encoder.input_size()` values, and `legal` the indices of the
+/// possible actions of each example
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Dataset {
+    pub encoder: Encoder,
+    pub inputs: Vec<ModelPrec>,
+    pub labels: Vec<usize>,
+    pub legal: Vec<Vec<usize>>,
+}
+
+impl Dataset {
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    #[inline]
+    pub fn input(&self, n: usize) -> &[ModelPrec] {
+        let input_size = self.encoder.input_size();
+        &self.inputs[n * input_size..(n + 1) * input_size]
+    }
+
+    fn push(&mut self, input: &[ModelPrec], label: usize, legal: Vec<usize>) {
+        self.inputs.extend_from_slice(input);
+        self.labels.push(label);
+        self.legal.push(legal);
+    }
+
+    /// Splits the dataset in a random order into a training and a validation set
+    pub fn split(&self, validation_split: f64, rng: &mut impl Rng) -> (Dataset, Dataset) {
+        let mut order = (0..self.len()).collect::<Vec<_>>();
+        order.shuffle(rng);
+
+        let validation = (self.len() as f64 * validation_split).round() as usize;
+        let empty = Dataset { encoder: self.encoder, ..Default::default() };
+        let mut res = (empty.clone(), empty);
+        for (n, index) in order.into_iter().enumerate() {
+            let set = if n < self.len() - validation { &mut res.0 } else { &mut res.1 };
+            set.push(self.input(index), self.labels[index], self.legal[index].clone());
+        }
+
+        res
+    }
+}
+
+/// Encodes the best action of every player that could play in `data` with `encoder`, skipping each of them with
+/// probability `skip_rate`
+pub fn encode_training(encoder: Encoder, data: &[TrainingData], skip_rate: f64, rng: &mut impl Rng) -> Dataset {
+    let mut res = Dataset { encoder, ..Default::default() };
+
+    for row in data {
+        for index in 0..row.players.len() {
+            if rng.gen_bool(skip_rate) {
+                continue;
+            }
+
+            if let Some((input, label, legal)) = encoder.encode_training(row, index) {
+                res.push(&input, label, legal);
+            }
+        }
+    }
+
+    res
+}
+
+/// Returns a network with the layers of `settings`, initialized like keras does (Glorot uniform weights, zero bias)
+pub fn initialize(settings: &TrainSettings, rng: &mut impl Rng) -> Mlp {
+    let encoder = Encoder::new(settings.max_players);
+    let sizes = std::iter::once(encoder.input_size())
+        .chain(settings.hidden.iter().copied())
+        .chain(std::iter::once(encoder.max_actions()))
+        .collect::<Vec<_>>();
+
+    let layers = sizes.windows(2).enumerate().map(|(n, window)| {
+        let (inputs, outputs) = (window[0], window[1]);
+        let limit = (6.0 / (inputs + outputs) as ModelPrec).sqrt();
+
+        Dense {
+            inputs,
+            outputs,
+            weights: (0..inputs * outputs).map(|_| rng.gen_range(-limit..limit)).collect(),
+            bias: vec![0.0; outputs],
+            activation: if n + 2 == sizes.len() { Activation::Softmax } else { Activation::Relu },
+        }
+    }).collect();
+
+    Mlp {
+        layers,
+        value: None,
+        encoder_version: Some(encoder.version()),
+    }
+}
+
+/// Returns the mean cross-entropy loss and the accuracy of `mlp` on `dataset`
+pub fn evaluate(mlp: &Mlp, dataset: &Dataset) -> (f64, f64) {
+    if dataset.is_empty() {
+        return (0.0, 0.0);
+    }
+
+    let prediction = mlp.forward(&dataset.inputs, dataset.len());
+    let mut loss = 0.0;
+    let mut correct = 0;
+
+    for (row, label) in prediction.chunks_exact(mlp.outputs()).zip(dataset.labels.iter().copied()) {
+        loss -= (row[label].max(1e-7) as f64).ln();
+
+        let best = row.iter().enumerate().fold(0, |best, (n, x)| if *x > row[best] { n } else { best });
+        correct += (best == label) as usize;
+    }
+
+    (loss / dataset.len() as f64, correct as f64 / dataset.len() as f64)
+}
+
+// First and second moments of the gradients of a layer
+#[derive(Debug, Clone)]
+struct Moments {
+    weights: (Vec<ModelPrec>, Vec<ModelPrec>),
+    bias: (Vec<ModelPrec>, Vec<ModelPrec>),
+}
+
+/// Trains a network with Adam on the categorical cross-entropy, keeping the state of the optimizer across `epoch` calls.
+/// Only the policy is trained, so the network must not have a value head (see `ValueHead`).
+pub struct Trainer {
+    pub settings: TrainSettings,
+    moments: Vec<Moments>,
+    steps: i32,
+}
+
+impl Trainer {
+    pub fn new(mlp: &Mlp, settings: TrainSettings) -> Self {
+        assert!(mlp.layers.last().map(|layer| layer.activation) == Some(Activation::Softmax));
+        assert!(mlp.value.is_none(), "The trainer doesn't train the value head, drop it before training");
+        assert!(Encoder::from_input_size(mlp.inputs()).map(|encoder| encoder.max_actions()) == Some(mlp.outputs()));
+
+        let moments = mlp.layers.iter().map(|layer| Moments {
+            weights: (vec![0.0; layer.weights.len()], vec![0.0; layer.weights.len()]),
+            bias: (vec![0.0; layer.outputs], vec![0.0; layer.outputs]),
+        }).collect();
+
+        Self {
+            settings,
+            moments,
+            steps: 0,
+        }
+    }
+
+    /// Runs one pass over `dataset`, in a random order; returns the mean training loss, measured with dropout
+    pub fn epoch(&mut self, mlp: &mut Mlp, dataset: &Dataset, rng: &mut impl Rng) -> f64 {
+        let mut order = (0..dataset.len()).collect::<Vec<_>>();
+        order.shuffle(rng);
+
+        let mut loss = 0.0;
+        for batch in order.chunks(self.settings.batch_size) {
+            loss += self.step(mlp, dataset, batch, rng) * batch.len() as f64;
+        }
+
+        loss / dataset.len().max(1) as f64
+    }
+
+    // Computes the gradients of the mean loss of `batch` by backpropagation, then applies them; returns the mean loss
+    fn step(&mut self, mlp: &mut Mlp, dataset: &Dataset, batch: &[usize], rng: &mut impl Rng) -> f64 {
+        let layers = mlp.layers.len();
+        let keep = 1.0 - self.settings.dropout;
+
+        // activations[l] holds the inputs of layer l, for every example of the batch, and activations[layers] the outputs;
+        // scales[l] holds the derivative of activations[l + 1] with respect to the outputs of the dense part of layer l
+        let mut activations = Vec::with_capacity(layers + 1);
+        let mut scales = Vec::with_capacity(layers);
+        activations.push(batch.iter().flat_map(|&n| dataset.input(n).iter().copied()).collect::<Vec<_>>());
+
+        for (l, layer) in mlp.layers.iter().enumerate() {
+            let mut output = vec![0.0; batch.len() * layer.outputs];
+            for (input, output) in activations[l].chunks_exact(layer.inputs).zip(output.chunks_exact_mut(layer.outputs)) {
+                layer.forward(input, output);
+            }
+
+            let mut scale = vec![1.0; output.len()];
+            if l + 1 < layers {
+                for (x, scale) in output.iter_mut().zip(scale.iter_mut()) {
+                    let kept = self.settings.dropout <= 0.0 || rng.gen::<ModelPrec>() < keep;
+                    let derivative = match layer.activation {
+                        Activation::Relu => (*x > 0.0) as u8 as ModelPrec,
+                        _ => 1.0,
+                    };
+                    *scale = if kept { derivative / keep } else { 0.0 };
+                    *x = if kept { *x / keep } else { 0.0 };
+                }
+            }
+
+            activations.push(output);
+            scales.push(scale);
+        }
+
+        // The gradient of the cross-entropy through the softmax is the prediction minus the one-hot label
+        let mut loss = 0.0;
+        let mut delta = activations[layers].clone();
+        for (row, n) in delta.chunks_exact_mut(mlp.outputs()).zip(batch.iter().copied()) {
+            let label = dataset.labels[n];
+            loss -= (row[label].max(1e-7) as f64).ln();
+            row[label] -= 1.0;
+            row.iter_mut().for_each(|x| *x /= batch.len() as ModelPrec);
+        }
+
+        for l in (0..layers).rev() {
+            let layer = &mlp.layers[l];
+            let mut weight_gradient = vec![0.0; layer.weights.len()];
+            let mut bias_gradient = vec![0.0; layer.outputs];
+            let mut previous = vec![0.0; batch.len() * layer.inputs];
+
+            for ((delta, input), previous) in delta.chunks_exact(layer.outputs)
+                .zip(activations[l].chunks_exact(layer.inputs))
+                .zip(previous.chunks_exact_mut(layer.inputs))
+            {
+                for (((d, gradient), row), bias) in delta.iter().zip(weight_gradient.chunks_exact_mut(layer.inputs))
+                    .zip(layer.weights.chunks_exact(layer.inputs))
+                    .zip(bias_gradient.iter_mut())
+                {
+                    if *d == 0.0 {
+                        continue;
+                    }
+                    *bias += d;
+                    gradient.iter_mut().zip(input.iter()).for_each(|(g, x)| *g += d * x);
+                    previous.iter_mut().zip(row.iter()).for_each(|(p, w)| *p += d * w);
+                }
+            }
+
+            if l > 0 {
+                previous.iter_mut().zip(scales[l - 1].iter()).for_each(|(p, scale)| *p *= scale);
+            }
+            delta = previous;
+
+            apply(&self.settings, self.steps + 1, &mut self.moments[l], &mut mlp.layers[l], &weight_gradient, &bias_gradient);
+        }
+        self.steps += 1;
+
+        loss / batch.len() as f64
+    }
+}
+
+// Applies the update of Adam, `step` being the number of updates so far, including this one
+fn apply(
+    settings: &TrainSettings,
+    step: i32,
+    moments: &mut Moments,
+    layer: &mut Dense,
+    weight_gradient: &[ModelPrec],
+    bias_gradient: &[ModelPrec],
+) {
+    let rate = settings.learning_rate * (1.0 - settings.beta2.powi(step)).sqrt() / (1.0 - settings.beta1.powi(step));
+
+    let update = |params: &mut [ModelPrec], gradient: &[ModelPrec], (m, v): &mut (Vec<ModelPrec>, Vec<ModelPrec>)| {
+        for (((param, g), m), v) in params.iter_mut().zip(gradient.iter()).zip(m.iter_mut()).zip(v.iter_mut()) {
+            *m = settings.beta1 * *m + (1.0 - settings.beta1) * g;
+            *v = settings.beta2 * *v + (1.0 - settings.beta2) * g * g;
+            *param -= rate * *m / (v.sqrt() + settings.epsilon);
+        }
+    };
+
+    update(&mut layer.weights, weight_gradient, &mut moments.weights);
+    update(&mut layer.bias, bias_gradient, &mut moments.bias);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::*;
+
+    #[test]
+    fn test_train() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+        // The best action only depends on the walls of the player
+        let data = (0..40).map(|_| {
+            let players = (0..4).map(|_| Player::with_values(rng.gen_range(0..2) * 8, rng.gen_range(0..5), 1, 2, 0)).collect::<Vec<_>>();
+            let best_actions = players.iter().map(|p| (if p.walls == 0 { Action::Wall } else { Action::Recruit }, 0.0, 0.0)).collect();
+            let previous_actions = vec![vec![Action::Skip; 4], vec![Action::Barracks; 4]];
+
+            TrainingData::new(previous_actions, players, best_actions)
+        }).collect::<Vec<_>>();
+
+        let settings = TrainSettings {
+            hidden: vec![16],
+            dropout: 0.1,
+            learning_rate: 0.01,
+            skip_rate: 0.0,
+            ..Default::default()
+        };
+
+        let dataset = encode_training(Encoder::default(), &data, settings.skip_rate, &mut rng);
+        assert_eq!(dataset.len(), 160);
+        let (train, validation) = dataset.split(0.25, &mut rng);
+        assert_eq!((train.len(), validation.len()), (120, 40));
+
+        let mut mlp = initialize(&settings, &mut rng);
+        let (initial_loss, _) = evaluate(&mlp, &validation);
+
+        let mut trainer = Trainer::new(&mlp, settings);
+        for _ in 0..60 {
+            trainer.epoch(&mut mlp, &train, &mut rng);
+        }
+
+        let (loss, accuracy) = evaluate(&mlp, &validation);
+        assert!(loss < initial_loss / 3.0, "{} {}", loss, initial_loss);
+        assert!(accuracy > 0.9, "{}", accuracy);
+
+        // The trained network runs through the native backend
+        let network = Network::Native(mlp);
+        let players = vec![Player::with_values(0, 2, 1, 2, 0), Player::with_values(3, 2, 1, 2, 0)];
+        let prediction = run_model(&network, &[Action::Skip, Action::Barracks], &players, 0, &[Action::Wall, Action::Recruit]).unwrap();
+        assert_eq!(prediction[0].0, Action::Wall);
+    }
+}
@@ -0,0 +1,201 @@
+// Players whose stats aren't exactly known, like the soldiers of an opponent whose recruits and losses we can only estimate.
+// Each stat can be an exact value, a range or a distribution; monte carlo draws a concrete `Player` from them for every sample.
+use super::*;
+use super::monte_carlo::*;
+use super::objective::Objective;
+use rand::prelude::*;
+
+/// A stat of a player, as found in `players.json`: either an exact value (`3`), a range of equally likely values
+/// (`{"min": 3, "max": 7}`, inclusive) or a list of `[value, weight]` pairs (`[[3, 1.0], [5, 2.0]]`)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Stat {
+    Exact(u32),
+    Range { min: u32, max: u32 },
+    Distribution(Vec<(u32, f64)>),
+}
+
+impl Stat {
+    pub fn is_exact(&self) -> bool {
+        match self {
+            Stat::Exact(_) => true,
+            Stat::Range { min, max } => min == max,
+            Stat::Distribution(values) => values.len() <= 1,
+        }
+    }
+
+    pub fn sample(&self, rng: &mut impl Rng) -> u32 {
+        match self {
+            Stat::Exact(value) => *value,
+            Stat::Range { min, max } => rng.gen_range(*min.min(max)..=*max.max(min)),
+            Stat::Distribution(values) => values.choose_weighted(rng, |(_, weight)| *weight).map(|(value, _)| *value).unwrap_or(0),
+        }
+    }
+
+    /// The expected value, rounded to the nearest integer
+    pub fn expected(&self) -> u32 {
+        match self {
+            Stat::Exact(value) => *value,
+            Stat::Range { min, max } => ((*min as f64 + *max as f64) / 2.0).round() as u32,
+            Stat::Distribution(values) => {
+                let sum: f64 = values.iter().map(|(_, weight)| weight).sum();
+                if sum == 0.0 {
+                    return 0;
+                }
+                (values.iter().map(|(value, weight)| *value as f64 * weight).sum::<f64>() / sum).round() as u32
+            }
+        }
+    }
+}
+
+impl From<u32> for Stat {
+    fn from(value: u32) -> Self {
+        Stat::Exact(value)
+    }
+}
+
+/// A player whose stats may be uncertain
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UncertainPlayer {
+    pub walls: Stat,
+    pub soldiers: Stat,
+    pub barracks: Stat,
+    pub obelisks: Stat,
+    pub defense: Stat,
+    pub is_target: bool,
+}
+
+impl UncertainPlayer {
+    pub fn is_exact(&self) -> bool {
+        self.walls.is_exact()
+            && self.soldiers.is_exact()
+            && self.barracks.is_exact()
+            && self.obelisks.is_exact()
+            && self.defense.is_exact()
+    }
+
+    #[inline]
+    fn make_player(&self, walls: u32, soldiers: u32, barracks: u32, obelisks: u32, defense: u32) -> Player {
+        let player = Player::with_values(
+            walls.min(MAX_WALLS as u32) as u8,
+            soldiers,
+            barracks.min(MAX_BARRACKS as u32) as u8,
+            obelisks.min(MAX_OBELISKS as u32) as u8,
+            defense.min(2) as u8,
+        );
+
+        if self.is_target { player.make_target() } else { player }
+    }
+
+    /// Draws a concrete player
+    pub fn sample(&self, rng: &mut impl Rng) -> Player {
+        self.make_player(
+            self.walls.sample(rng),
+            self.soldiers.sample(rng),
+            self.barracks.sample(rng),
+            self.obelisks.sample(rng),
+            self.defense.sample(rng),
+        )
+    }
+
+    /// The player with the expected value of each stat
+    pub fn expected(&self) -> Player {
+        self.make_player(
+            self.walls.expected(),
+            self.soldiers.expected(),
+            self.barracks.expected(),
+            self.obelisks.expected(),
+            self.defense.expected(),
+        )
+    }
+}
+
+impl From<&Player> for UncertainPlayer {
+    fn from(player: &Player) -> Self {
+        Self {
+            walls: Stat::Exact(player.walls as u32),
+            soldiers: Stat::Exact(player.soldiers),
+            barracks: Stat::Exact(player.barracks as u32),
+            obelisks: Stat::Exact(player.obelisks as u32),
+            defense: Stat::Exact(player.defense as u32),
+            is_target: player.is_target,
+        }
+    }
+}
+
+/// Draws a concrete state of the game
+pub fn sample_players(players: &[UncertainPlayer], rng: &mut impl Rng) -> Vec<Player> {
+    players.iter().map(|player| player.sample(rng)).collect()
+}
+
+/// Measures how sensitive the best action of player `index` is to the uncertainty on the stats of the players
+/// (`settings.uncertain_players`): `scenarios` concrete states are drawn, the best action is computed in each of them (with `settings.samples` samples),
+/// and the fraction of the scenarios in which each action was the best one is returned, from the most to the least frequent.
+///
+/// Note that the best action can also change between scenarios because of the noise of monte carlo,
+/// so `settings.samples` shouldn't be too low.
+pub fn best_action_sensitivity<First, Ai, Loss>(
+    index: usize,
+    previous_actions: &[Vec<Action>],
+    constraints: &[(usize, Action)],
+    settings: &MonteCarloSettings,
+    scenarios: usize,
+    rollout: Rollout<First, Ai, Loss>,
+) -> Vec<(Action, f64)>
+where
+    First: FirstAi<Ai> + Copy,
+    Ai: RolloutAi + Copy,
+    Loss: Objective + Copy,
+{
+    let mut rng = rand::thread_rng();
+    let players = &settings.uncertain_players;
+    let settings = MonteCarloSettings {
+        uncertain_players: Vec::new(),
+        ..settings.clone()
+    };
+
+    let mut res: Vec<(Action, f64)> = Vec::new();
+
+    for _n in 0..scenarios {
+        let scenario = sample_players(players, &mut rng);
+        let (best, _) = mc_best_action(&scenario, index, previous_actions, constraints.to_vec(), &settings, rollout);
+
+        match res.iter_mut().find(|(action, _)| *action == best) {
+            Some((_, count)) => *count += 1.0,
+            None => res.push((best, 1.0)),
+        }
+    }
+
+    for (_, count) in res.iter_mut() {
+        *count /= scenarios as f64;
+    }
+    res.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let stats: Vec<Stat> = serde_json::from_str(r#"[2, {"min": 3, "max": 7}, [[1, 1.0], [4, 3.0]]]"#).unwrap();
+        assert_eq!(stats, vec![
+            Stat::Exact(2),
+            Stat::Range { min: 3, max: 7 },
+            Stat::Distribution(vec![(1, 1.0), (4, 3.0)]),
+        ]);
+
+        assert_eq!(stats[1].expected(), 5);
+        assert_eq!(stats[2].expected(), 3);
+        assert!(stats[0].is_exact() && !stats[1].is_exact());
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let soldiers = stats[1].sample(&mut rng);
+            assert!((3..=7).contains(&soldiers));
+            assert!([1, 4].contains(&stats[2].sample(&mut rng)));
+        }
+    }
+}
@@ -1,4 +1,5 @@
 import math
+import os
 import csv
 import json
 import time
@@ -72,12 +73,17 @@ def read_training(path):
         for best_action in row["best_actions"]:
             best_actions.append(parse_action(best_action[0]))
 
-        res.append([previous_actions, players, best_actions])
+        # The value targets, which older training data doesn't have
+        values = row.get("values", [])
+
+        res.append([previous_actions, players, best_actions, values])
     return res
 
 print(actions_map)
 
-MAX_PLAYERS = 16
+# The number of players that the model sees; it is recorded in the converted model, and in larger games, the weakest
+# opponents are left out of the input
+MAX_PLAYERS = int(os.environ.get("OBELISK_MAX_PLAYERS", 16))
 ACTION_ATTACK = parse_action("Attack")
 MAX_ACTIONS = ACTION_ATTACK + MAX_PLAYERS - 1
 N_ACTIONS = 8
@@ -89,6 +95,23 @@ PERMUTATIONS = 1
 SKIP_RATE = 0.5
 # previous moves + players
 INPUT_SIZE = MAX_ACTIONS * N_ACTIONS + 6 * MAX_PLAYERS
+# Must match ENCODER_VERSION in src/model.rs; it is recorded in the converted model, which is refused on mismatch
+ENCODER_VERSION = 3
+
+def check_schema(path="./target/encoder.json"):
+    """Checks the constants above against the schema written by `cargo run --bin train -- --schema`, if it exists"""
+    try:
+        schema = json.load(open(path))
+    except IOError:
+        return
+    assert schema["version"] == ENCODER_VERSION, "The encoder version changed, train.py needs to be updated"
+    assert schema["input_size"] == INPUT_SIZE
+    assert schema["history_length"] == N_ACTIONS
+    assert schema["max_actions"] == MAX_ACTIONS
+    assert schema["max_players"] == MAX_PLAYERS
+    assert dict(schema["actions"]) == dict(actions_map)
+    scales = {feature["name"]: feature["scale"] for feature in schema["player_features"][0:4]}
+    assert scales == {"walls": MAX_WALLS, "soldiers": SOLDIERS_SCALE, "barracks": MAX_BARRACKS, "obelisks": MAX_OBELISKS}
 
 def categorize(value, max):
     res = []
@@ -106,94 +129,75 @@ def flatten(l):
 def refine_training(training):
     res_output = []
     res_input = []
-    for [previous_actions, raw_players, best_actions] in training:
+    res_value = []
+    for [previous_actions, raw_players, best_actions, values] in training:
         for n, playable in filter(lambda x: best_actions[x[0]] != 0, enumerate(raw_players)):
             if random.random() < SKIP_RATE:
                 continue
+            # The other players, from the strongest to the weakest (walls, doubled if they defend, plus soldiers); the model
+            # only sees the MAX_PLAYERS - 1 strongest ones (see compute_permutation in src/model.rs)
+            def strength(player):
+                return player[0] * (2 if player[4] > 0 else 1) + player[1]
+            others = sorted((o for o in range(len(raw_players)) if o != n), key=lambda o: -strength(raw_players[o]))
+            rank = {o: r for r, o in enumerate(others)}
+
+            # Attacks are ordered like the opponents, and the attacks on the opponents that are left out share the last output
+            def map_action(action):
+                if action < ACTION_ATTACK:
+                    return action
+                return ACTION_ATTACK + min(rank[action - ACTION_ATTACK], MAX_PLAYERS - 2)
+
+            # Attacking an opponent that the model doesn't see isn't an example
+            if best_actions[n] >= ACTION_ATTACK and rank[best_actions[n] - ACTION_ATTACK] >= MAX_PLAYERS - 1:
+                continue
+
             prev = []
             for o in range(N_ACTIONS):
                 index = len(previous_actions[n]) - 1 - o
                 if index < 0:
                     prev.append(0)
                 else:
-                    action = previous_actions[n][o]
-                    # The player can't attack itself, so might as well remove it
-                    if action >= ACTION_ATTACK + n:
-                        prev.append(action - 1)
-                    else:
-                        prev.append(action)
-
-            players = [playable]
-            for o, other in filter(lambda x: x[0] != n, enumerate(raw_players)):
-                players.append(other)
-
-            if best_actions[n] >= ACTION_ATTACK + n:
-                best_action = best_actions[n] - 1
-            else:
-                best_action = best_actions[n]
-
-            # for perm_n in range(PERMUTATIONS):
-            perm = [x for x in range(MAX_PLAYERS - 1)]
-            # random.shuffle(perm)
-
-            # Let's order them by strength instead
-            def sort_strength(index):
-                if index + 1 < len(players):
-                    player = players[index + 1]
-                    return -(player[0] * (2 if player[4] > 0 else 1) + player[1])
-                else:
-                    return 1000
-
-            perm.sort(key = sort_strength)
-
-            action_map = bidict()
-            for a in range(ACTION_ATTACK):
-                action_map.put(a, a)
-            for p in range(MAX_PLAYERS - 1):
-                action_map.put(ACTION_ATTACK + p, ACTION_ATTACK + perm[p])
+                    prev.append(map_action(previous_actions[n][index]))
 
             transformed_players = []
-            for p in range(MAX_PLAYERS):
-                if p == 0:
-                    index = 0
-                else:
-                    index = perm[p - 1] + 1
-
-                if index < len(players):
-                    player = players[index]
-                    transformed_players.append([
-                        player[0] / MAX_WALLS,
-                        1 - math.exp(-player[1] / SOLDIERS_SCALE),
-                        player[2] / MAX_BARRACKS,
-                        player[3] / MAX_OBELISKS,
-                        player[4],
-                        player[5]
-                    ])
-                else:
-                    transformed_players.append([0, 0, 0, 0, 0, 0])
-
-            # best_action = categorize(best_action, MAX_ACTIONS)
+            for player in [playable] + [raw_players[o] for o in others[0:MAX_PLAYERS - 1]]:
+                transformed_players.append([
+                    player[0] / MAX_WALLS,
+                    1 - math.exp(-player[1] / SOLDIERS_SCALE),
+                    player[2] / MAX_BARRACKS,
+                    player[3] / MAX_OBELISKS,
+                    player[4],
+                    player[5]
+                ])
+            while len(transformed_players) < MAX_PLAYERS:
+                transformed_players.append([0, 0, 0, 0, 0, 0])
 
             transformed_prev = []
             for action in prev:
-                transformed_prev.append(categorize(action_map[action], MAX_ACTIONS))
+                transformed_prev.append(categorize(action, MAX_ACTIONS))
 
             row = flatten(transformed_prev) + flatten(transformed_players)
             res_input.append(row)
-            res_output.append(action_map[best_action])
+            res_output.append(map_action(best_actions[n]))
+            res_value.append(values[n] if n < len(values) else math.nan)
             assert len(row) == INPUT_SIZE
-    return res_input,res_output
+    return res_input,res_output,res_value
 
 if __name__ == "__main__":
+    check_schema()
+
     # Read the training data as a bunch of numbers
     training = read_training("./target/train-last.json")
     print(len(training), "training games")
 
     # Turn the training data into a list of tensors for the AI
     random.shuffle(training)
-    refined_x,refined_y = refine_training(training)
+    refined_x,refined_y,refined_value = refine_training(training)
     train_x = numpy.array(refined_x)
     train_y = numpy.array(refined_y)
+    train_value = numpy.array(refined_value)
+    # The value head is only trained if every example has a value target
+    value_head = not numpy.isnan(train_value).any()
 
     print(train_x.shape, train_y.shape)
 
@@ -201,31 +205,47 @@ if __name__ == "__main__":
         model = tf.keras.models.load_model("target/model.h5")
     except IOError:
         print("Creating a new model!")
-        model = tf.keras.Sequential([
-            layers.Input(shape=(INPUT_SIZE,)),
-            # layers.Dense(units = 180, activation="relu"),
-            # layers.Dropout(0.2),
-            layers.Dense(units = 96, activation="relu"),
-            layers.Dropout(0.25),
-            layers.Dense(units = 64, activation="relu"),
-            layers.Dropout(0.25),
-            layers.Dense(units = 48, activation="relu"),
-            layers.Dropout(0.25),
-            layers.Dense(units = 24, activation="relu"),
-            layers.Dropout(0.25),
-            layers.Dense(units = MAX_ACTIONS, activation="softmax")
-        ])
-
-        # loss: categorical cross-entropy
-        model.compile(
-            optimizer="adam",
-            loss="sparse_categorical_crossentropy",
-            metrics=["accuracy"]
-        )
+        inputs = layers.Input(shape=(INPUT_SIZE,))
+        x = inputs
+        # x = layers.Dense(units = 180, activation="relu")(x)
+        # x = layers.Dropout(0.2)(x)
+        for units in [96, 64, 48, 24]:
+            x = layers.Dense(units = units, activation="relu")(x)
+            x = layers.Dropout(0.25)(x)
+        policy = layers.Dense(units = MAX_ACTIONS, activation="softmax", name="policy")(x)
+
+        if value_head:
+            # The value head predicts the loss of the player; it branches from the last hidden layer, and its layers
+            # are named "value..." so that convert.py can tell them apart
+            value = layers.Dense(units = 16, activation="relu", name="value_hidden")(x)
+            value = layers.Dense(units = 1, activation="linear", name="value")(value)
+            model = tf.keras.Model(inputs=inputs, outputs=[policy, value])
+
+            # loss: categorical cross-entropy for the policy, mean squared error for the value
+            model.compile(
+                optimizer="adam",
+                loss={"policy": "sparse_categorical_crossentropy", "value": "mse"},
+                metrics={"policy": "accuracy"}
+            )
+        else:
+            model = tf.keras.Model(inputs=inputs, outputs=policy)
+
+            # loss: categorical cross-entropy
+            model.compile(
+                optimizer="adam",
+                loss="sparse_categorical_crossentropy",
+                metrics=["accuracy"]
+            )
+
+    if len(model.outputs) > 1:
+        assert value_head, "The model has a value head, but the training data has no value targets"
+        targets = {"policy": train_y, "value": train_value}
+    else:
+        targets = train_y
 
     history = model.fit(
         x=train_x,
-        y=train_y,
+        y=targets,
         validation_split=0.1,
         epochs=40,
         verbose=1